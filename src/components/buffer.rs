@@ -7,22 +7,34 @@ use smallvec::smallvec;
 use std::{
     borrow::Cow,
     cmp,
-    fs::File,
-    io::{self, BufReader, BufWriter},
-    iter,
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
     path::{Path, PathBuf},
-    time::Instant,
+    process::{Command, Stdio},
+    ptr,
+    sync::Arc,
+    thread,
+    time::{Instant, SystemTime},
 };
 use zee_highlight::SelectorNodeId;
 
 use super::{
-    cursor::{CharIndex, Cursor},
+    cursor::{next_grapheme_boundary, CharIndex, Cursor},
     theme::Theme as EditorTheme,
-    BindingMatch, Bindings, Component, Context, HashBindings,
+    BindingMatch, Bindings, Component, Context, HashBindings, PrefixArg,
 };
 use crate::{
-    error::Result,
-    mode::{self, Mode},
+    editorconfig,
+    encoding::{self, FileFormat},
+    error::{Error, Result},
+    files,
+    indentation::{self, DetectedIndentation, IndentStyle, Indentation},
+    keymap::{ActionInfo, NamedAction},
+    mode::{self, Mode, PLAIN_TEXT_MODE},
+    places::{self, Place},
+    recovery,
+    settings::{ModeSettings, Settings, TrimTrailingWhitespace},
     syntax::{
         highlight::{text_style_at_char, Theme as SyntaxTheme},
         parse::{NodeTrace, OpaqueDiff, ParserStatus, SyntaxCursor, SyntaxTree},
@@ -30,7 +42,8 @@ use crate::{
     task::Scheduler,
     terminal::{Key, Position, Rect, Screen, Size, Style},
     undo::UndoTree,
-    utils::{self, strip_trailing_whitespace, RopeGraphemes, TAB_WIDTH},
+    utils::{self, strip_trailing_whitespace, RopeGraphemes},
+    vi::{Motion, Operator},
 };
 
 #[derive(Clone, Debug)]
@@ -55,25 +68,133 @@ enum ModifiedStatus {
     Saving(Instant),
 }
 
+/// What the buffer knows about the file on disk, used to notice when it is
+/// modified by another program.
+#[derive(Clone, Debug, Default)]
+struct DiskState {
+    /// Modification time of the file when it was last read or written by us,
+    /// `None` if it doesn't exist
+    modified: Option<SystemTime>,
+
+    /// Incremented every time we read or write the file, so that the results
+    /// of checks started before then can be ignored
+    generation: usize,
+
+    /// Set when the file changed on disk while the buffer had unsaved changes
+    changed_externally: bool,
+
+    /// Hashes of the lines as they were last read or written, to tell which
+    /// lines were edited since
+    saved_lines: Arc<HashSet<u64>>,
+
+    /// Whether the file ended with a newline when last read or written. It's
+    /// kept when saving, even if final newlines aren't inserted, as the
+    /// buffer itself always ends with one.
+    final_newline: bool,
+
+    /// The edit count of the text when it was last saved, and of the text in
+    /// the last recovery file written since. Auto-saves and saves run
+    /// concurrently, a recovery file which is no newer than a save is stale.
+    saved_edit_count: Option<usize>,
+    autosaved_edit_count: Option<usize>,
+}
+
+/// How the text is changed as it's written, see `Buffer::save_options`
+#[derive(Clone, Debug, PartialEq)]
+struct SaveOptions {
+    trim_trailing_whitespace: TrimTrailingWhitespace,
+    final_newline: bool,
+    format_on_save: Option<String>,
+}
+
+impl DiskState {
+    fn read_or_written(&mut self, modified: Option<SystemTime>) {
+        self.modified = modified;
+        self.generation += 1;
+        self.changed_externally = false;
+    }
+}
+
 pub struct Buffer {
     mode: &'static Mode,
     text: UndoTree,
     clipboard: Option<Rope>,
     has_unsaved_changes: ModifiedStatus,
+    needs_autosave: bool,
     file_path: Option<PathBuf>,
+    format: FileFormat,
+    disk_state: DiskState,
+    editorconfig: editorconfig::Properties,
+    detected_indentation: Option<DetectedIndentation>,
     cursor: Cursor,
     first_line: usize,
+    // Set when moving to a position before the buffer was laid out, the
+    // cursor's line is centred once the frame is known
+    center_when_drawn: bool,
+    read_only: bool,
+    // Counts the changes to the text, to tell others it was edited
+    edit_count: usize,
     syntax: Option<SyntaxTree>,
     //repo: Option<Repository>,
-    bindings: BufferBindings,
 }
 
 impl Buffer {
-    pub fn from_file(file_path: PathBuf) -> Result<Self> {
-        let mode = mode::find_by_filename(&file_path);
+    fn with_text(text: Rope, file_path: Option<PathBuf>, mode: &'static Mode) -> Self {
+        let disk_state = DiskState {
+            saved_lines: Arc::new(utils::line_hashes(&text)),
+            final_newline: utils::has_final_newline(&text),
+            ..Default::default()
+        };
+        let editorconfig = file_path
+            .as_ref()
+            .map(editorconfig::properties)
+            .unwrap_or_default();
+        let detected_indentation = indentation::detect(&text);
+        Buffer {
+            text: UndoTree::new(text),
+            clipboard: None,
+            has_unsaved_changes: ModifiedStatus::Unchanged,
+            needs_autosave: false,
+            file_path,
+            format: FileFormat::default(),
+            disk_state,
+            editorconfig,
+            detected_indentation,
+            cursor: Cursor::new(),
+            first_line: 0,
+            center_when_drawn: false,
+            read_only: false,
+            edit_count: 0,
+            syntax: mode.language().map(|language| SyntaxTree::new(*language)),
+            mode,
+            //repo,
+        }
+    }
+
+    /// An empty buffer which isn't backed by a file until it's saved
+    pub fn new_scratch() -> Self {
+        Self::with_text(Rope::new(), None, &PLAIN_TEXT_MODE)
+    }
+
+    /// A scratch buffer with generated text, e.g. a list of key bindings
+    pub fn new_scratch_with_text(text: Rope) -> Self {
+        Self::with_text(text, None, &PLAIN_TEXT_MODE)
+    }
+
+    /// A scratch buffer with text read from elsewhere, e.g. piped to stdin.
+    /// Without a `mode`, it's detected from the text.
+    pub fn new_scratch_from_bytes(bytes: &[u8], mode: Option<&'static Mode>) -> Self {
+        let (text, format) = encoding::decode(bytes);
+        let mode = mode.unwrap_or_else(|| mode::find_by_content(&text));
+        let mut buffer = Self::with_text(text, None, mode);
+        buffer.format = format;
+        buffer
+    }
+
+    pub fn from_file(file_path: PathBuf, settings: &Settings) -> Result<Self> {
         //let repo = Repository::discover(&file_path).ok();
-        let text = if file_path.exists() {
-            Rope::from_reader(BufReader::new(File::open(&file_path)?))?
+        let (text, format) = if file_path.exists() {
+            encoding::decode(&fs::read(&file_path)?)
         } else {
             // Optimistically check if we can create it
             File::open(&file_path).map(|_| ()).or_else(|error| {
@@ -83,19 +204,138 @@ impl Buffer {
                     Err(error)
                 }
             })?;
-            Rope::new()
+            (Rope::new(), FileFormat::default())
         };
-        Ok(Buffer {
-            text: UndoTree::new(text),
-            clipboard: None,
-            has_unsaved_changes: ModifiedStatus::Unchanged,
-            file_path: Some(file_path),
-            cursor: Cursor::new(),
-            first_line: 0,
-            syntax: mode.language().map(|language| SyntaxTree::new(*language)),
-            mode,
-            //repo,
-            bindings: BufferBindings,
+        let disk_modified = file_modified_time(&file_path)?;
+        let place = places::find_place(&file_path);
+        let is_empty = text.len_chars() == 0;
+        let mode = mode::find(&file_path, settings);
+        let mut buffer = Self::with_text(text, Some(file_path), mode);
+        buffer.format = format;
+        if is_empty {
+            buffer.apply_editorconfig_format();
+        }
+        buffer.disk_state.modified = disk_modified;
+        if let Some(Place {
+            cursor, first_line, ..
+        }) = place
+        {
+            buffer.set_position(CharIndex(cursor), first_line);
+        }
+        Ok(buffer)
+    }
+
+    pub fn cursor_position(&self) -> CharIndex {
+        self.cursor.range().start
+    }
+
+    pub fn first_line(&self) -> usize {
+        self.first_line
+    }
+
+    /// The line and column of the cursor, counted from 1
+    pub fn cursor_location(&self) -> files::Position {
+        let char_index = self.cursor.range().start.0;
+        let line = self.text.char_to_line(char_index);
+        files::Position {
+            line: line + 1,
+            column: char_index - self.text.line_to_char(line) + 1,
+        }
+    }
+
+    pub fn text(&self) -> &Rope {
+        &self.text
+    }
+
+    pub fn mode_name(&self) -> &str {
+        &self.mode.name
+    }
+
+    /// Changes whenever the text does
+    pub fn edit_count(&self) -> usize {
+        self.edit_count
+    }
+
+    /// A read only buffer refuses any change to its text or file
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether saving writes the file, rather than being refused
+    pub fn can_save(&self) -> bool {
+        self.file_path.is_some() && !self.read_only
+    }
+
+    /// Moves the cursor to `char_index` and scrolls to `first_line`, both
+    /// clamped to the current text.
+    pub fn set_position(&mut self, char_index: CharIndex, first_line: usize) {
+        self.cursor.move_to_char(&self.text, char_index);
+        self.first_line = cmp::min(first_line, self.text.len_lines().saturating_sub(1));
+    }
+
+    /// A copy of the current text, to be saved at `file_path`
+    pub fn duplicate(&self, file_path: PathBuf, settings: &Settings) -> Self {
+        let mode = mode::find(&file_path, settings);
+        let mut buffer = Self::with_text(self.text.head().clone(), Some(file_path), mode);
+        buffer.format = self.format;
+        buffer.has_unsaved_changes = ModifiedStatus::Changed;
+        buffer.needs_autosave = true;
+        buffer.set_position(self.cursor_position(), self.first_line);
+        buffer
+    }
+
+    /// Points the buffer at a different file, e.g. after saving it under a
+    /// new name. The mode is detected again from the new file name.
+    pub fn set_file_path(&mut self, file_path: PathBuf, settings: &Settings) -> Result<()> {
+        self.set_mode(mode::find(&file_path, settings));
+        self.disk_state
+            .read_or_written(file_modified_time(&file_path)?);
+        self.editorconfig = editorconfig::properties(&file_path);
+        if self.file_path.is_none() {
+            self.apply_editorconfig_format();
+        }
+        self.file_path = Some(file_path);
+        self.needs_autosave = self.is_modified();
+        Ok(())
+    }
+
+    /// Picks the mode again, e.g. after the `file_patterns` in the settings
+    /// changed
+    pub fn update_mode(&mut self, settings: &Settings) {
+        if let Some(ref file_path) = self.file_path {
+            self.set_mode(mode::find(file_path, settings));
+        }
+    }
+
+    fn set_mode(&mut self, mode: &'static Mode) {
+        if !ptr::eq(mode, self.mode) {
+            self.syntax = mode.language().map(|language| SyntaxTree::new(*language));
+            self.mode = mode;
+        }
+    }
+
+    /// The encoding and line ending from `.editorconfig` only apply to new
+    /// files, existing ones are saved in the format they were read in.
+    fn apply_editorconfig_format(&mut self) {
+        if let Some(encoding) = self.editorconfig.charset() {
+            self.format.encoding = encoding;
+        }
+        if let Some(line_ending) = self.editorconfig.end_of_line() {
+            self.format.line_ending = line_ending;
+        }
+    }
+
+    /// The current position, to be restored when the file is reopened
+    pub fn place(&self) -> Option<Place> {
+        let path = self.file_path.as_ref()?.canonicalize().ok()?;
+        Some(Place {
+            path,
+            cursor: self.cursor_position().0,
+            first_line: self.first_line,
         })
     }
 
@@ -104,20 +344,261 @@ impl Buffer {
         scheduler: &mut Scheduler<<Self as Component>::Action>,
         context: &Context,
     ) -> Result<()> {
-        self.has_unsaved_changes = ModifiedStatus::Saving(context.time);
         if let Some(ref file_path) = self.file_path {
-            let text = self.text.clone();
+            self.has_unsaved_changes = ModifiedStatus::Saving(context.time);
+            let text = self.text.head().clone();
+            let file_path = file_path.clone();
+            let backup = context.settings.backup_on_save;
+            let format = self.format;
+            let SaveOptions {
+                trim_trailing_whitespace,
+                final_newline,
+                format_on_save,
+            } = self.save_options(context.settings, context.prefix_arg);
+            let saved_lines = self.disk_state.saved_lines.clone();
+            let edit_count = self.edit_count;
+            scheduler.spawn(move |_| {
+                let text = match format_on_save {
+                    Some(ref command) => match format_text(command, &text, &file_path) {
+                        Ok(text) => text,
+                        Err(error) => return Action::Async(Err(error)),
+                    },
+                    None => text,
+                };
+                let text = match trim_trailing_whitespace {
+                    TrimTrailingWhitespace::Always => strip_trailing_whitespace(text),
+                    TrimTrailingWhitespace::EditedLines => {
+                        utils::strip_trailing_whitespace_from_lines(text, |line| {
+                            !saved_lines.contains(&utils::line_hash(line))
+                        })
+                    }
+                    TrimTrailingWhitespace::Never => text,
+                };
+                // The buffer itself always ends with a newline
+                let mut written_text = text.clone();
+                if !final_newline {
+                    utils::remove_final_newline(&mut written_text);
+                }
+                Action::Async(
+                    files::write_atomically(&file_path, backup, |writer| {
+                        encoding::encode(&written_text, format, writer)
+                    })
+                    .and_then(|_| recovery::remove_recovery_file(&file_path))
+                    .and_then(|_| file_modified_time(&file_path))
+                    .map(|modified| AsyncAction::SaveFile {
+                        edit_count,
+                        saved_lines: utils::line_hashes(&text),
+                        final_newline: utils::has_final_newline(&written_text),
+                        text,
+                        modified,
+                    })
+                    .map_err(|error| error.into()),
+                )
+            })?;
+            Ok(())
+        } else {
+            Err(Error::UnnamedBuffer)
+        }
+    }
+
+    /// How the text is written. With `C-u` it's written as is, without
+    /// formatting it or trimming whitespace.
+    fn save_options(&self, settings: &Settings, prefix_arg: PrefixArg) -> SaveOptions {
+        let as_is = prefix_arg.is_universal();
+        SaveOptions {
+            trim_trailing_whitespace: if as_is {
+                TrimTrailingWhitespace::Never
+            } else {
+                self.trim_trailing_whitespace(settings)
+            },
+            final_newline: self.insert_final_newline(settings) || self.disk_state.final_newline,
+            format_on_save: self
+                .mode_settings(settings)
+                .and_then(|mode| mode.format_on_save.clone())
+                .filter(|_| !as_is),
+        }
+    }
+
+    /// The policy for trailing whitespace, `.editorconfig` takes precedence
+    /// over the mode's settings, which take precedence over the global ones.
+    fn trim_trailing_whitespace(&self, settings: &Settings) -> TrimTrailingWhitespace {
+        self.editorconfig
+            .trim_trailing_whitespace()
+            .map(|trim| {
+                if trim {
+                    TrimTrailingWhitespace::Always
+                } else {
+                    TrimTrailingWhitespace::Never
+                }
+            })
+            .or_else(|| {
+                self.mode_settings(settings)
+                    .and_then(|mode| mode.trim_trailing_whitespace)
+            })
+            .unwrap_or(settings.trim_trailing_whitespace)
+    }
+
+    fn insert_final_newline(&self, settings: &Settings) -> bool {
+        self.editorconfig
+            .insert_final_newline()
+            .or_else(|| {
+                self.mode_settings(settings)
+                    .and_then(|mode| mode.insert_final_newline)
+            })
+            .unwrap_or(settings.insert_final_newline)
+    }
+
+    fn mode_settings<'a>(&self, settings: &'a Settings) -> Option<&'a ModeSettings> {
+        settings.mode(&self.mode.name)
+    }
+
+    /// `.editorconfig` takes precedence over the indentation detected from
+    /// the file's content, which takes precedence over the mode's settings,
+    /// the mode's own default and finally the global settings.
+    fn indentation(&self, settings: &Settings) -> Indentation {
+        let mode_settings = self.mode_settings(settings);
+        let tab_width = mode_settings
+            .and_then(|mode| mode.tab_width)
+            .unwrap_or(settings.tab_width)
+            .max(1);
+        let mut indentation = Indentation {
+            style: mode_settings
+                .and_then(|mode| mode.indent_style)
+                .or(self.mode.indent_style)
+                .unwrap_or(settings.indent_style),
+            size: mode_settings
+                .and_then(|mode| mode.indent_size)
+                .or(settings.indent_size)
+                .unwrap_or(tab_width),
+            tab_width,
+        };
+        if let Some(detected) = self.detected_indentation {
+            detected.apply(&mut indentation);
+        }
+        self.editorconfig.apply_indentation(&mut indentation);
+        indentation
+    }
+
+    fn line_layout(&self, settings: &Settings) -> LineLayout {
+        LineLayout {
+            tab_width: self.indentation(settings).tab_width,
+            wrap_lines: self
+                .mode_settings(settings)
+                .and_then(|mode| mode.wrap_lines)
+                .unwrap_or(settings.wrap_lines),
+        }
+    }
+
+    fn line_numbers(&self, settings: &Settings) -> bool {
+        self.mode_settings(settings)
+            .and_then(|mode| mode.line_numbers)
+            .unwrap_or(settings.line_numbers)
+    }
+
+    /// Writes the current text to a recovery file if it changed since the last
+    /// time it was auto-saved.
+    pub fn spawn_autosave(
+        &mut self,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        match self.file_path {
+            Some(ref file_path) if self.needs_autosave => {
+                let text = self.text.head().clone();
+                let file_path = file_path.clone();
+                let edit_count = self.edit_count;
+                scheduler.spawn(move |_| {
+                    Action::Async(
+                        recovery::write_recovery_file(&file_path, &text)
+                            .map(|_| AsyncAction::SaveRecoveryFile { edit_count })
+                            .map_err(|error| error.into()),
+                    )
+                })?;
+                self.needs_autosave = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn remove_recovery_file(&self) -> Result<()> {
+        match self.file_path {
+            Some(ref file_path) => Ok(recovery::remove_recovery_file(file_path)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the buffer has changes which haven't been written to disk yet
+    pub fn is_modified(&self) -> bool {
+        match self.has_unsaved_changes {
+            ModifiedStatus::Unchanged => false,
+            ModifiedStatus::Changed | ModifiedStatus::Saving(..) => true,
+        }
+    }
+
+    /// Whether the file was modified by another program since it was last
+    /// read or written. Saving in this case would overwrite those changes.
+    pub fn changed_on_disk(&self) -> bool {
+        self.disk_state.changed_externally
+    }
+
+    /// Merges the edits made until `end_undo_group` into a single change,
+    /// so they're undone together
+    pub fn begin_undo_group(&mut self) {
+        self.text.begin_group();
+    }
+
+    pub fn end_undo_group(&mut self) {
+        self.text.end_group();
+    }
+
+    /// Checks in the background whether the file was modified or deleted by
+    /// another program.
+    pub fn spawn_check_file(
+        &mut self,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        match (&self.file_path, &self.has_unsaved_changes) {
+            (_, ModifiedStatus::Saving(..)) | (None, _) => {}
+            (Some(file_path), _) => {
+                let file_path = file_path.clone();
+                let generation = self.disk_state.generation;
+                scheduler.spawn(move |_| {
+                    Action::Async(
+                        file_modified_time(&file_path)
+                            .map(|modified| AsyncAction::CheckFile {
+                                modified,
+                                generation,
+                            })
+                            .map_err(|error| error.into()),
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_reload_file(
+        &mut self,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        if let Some(ref file_path) = self.file_path {
             let file_path = file_path.clone();
+            let generation = self.disk_state.generation;
             scheduler.spawn(move |_| {
                 Action::Async(
-                    File::create(&file_path)
-                        .map(BufWriter::new)
-                        .and_then(|writer| {
-                            let text = strip_trailing_whitespace(text);
-                            text.write_to(writer)?;
-                            Ok(text)
+                    File::open(&file_path)
+                        .and_then(|mut file| {
+                            let modified = file.metadata()?.modified()?;
+                            let mut bytes = Vec::new();
+                            file.read_to_end(&mut bytes)?;
+                            let (text, format) = encoding::decode(&bytes);
+                            Ok(AsyncAction::ReloadFile {
+                                text,
+                                format,
+                                modified,
+                                generation,
+                            })
                         })
-                        .map(|text| AsyncAction::SaveFile { text })
                         .map_err(|error| error.into()),
                 )
             })?;
@@ -125,34 +606,125 @@ impl Buffer {
         Ok(())
     }
 
+    fn handle_file_checked(
+        &mut self,
+        modified: Option<SystemTime>,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        let saving = match self.has_unsaved_changes {
+            ModifiedStatus::Saving(..) => true,
+            _ => false,
+        };
+        // While saving, the file is expected to change under us
+        if saving || modified == self.disk_state.modified || self.disk_state.changed_externally {
+            return Ok(());
+        }
+
+        let file_path = self.file_path.clone().unwrap_or_default();
+        match (modified, &self.has_unsaved_changes) {
+            (None, _) => {
+                // Keep the text around, saving will recreate the file
+                self.disk_state.read_or_written(None);
+                self.has_unsaved_changes = ModifiedStatus::Changed;
+                Err(Error::FileDeletedOnDisk(file_path))
+            }
+            (Some(_), ModifiedStatus::Unchanged) => self.spawn_reload_file(scheduler),
+            (Some(modified), _) => {
+                self.disk_state.modified = Some(modified);
+                self.disk_state.changed_externally = true;
+                Err(Error::FileChangedOnDisk(file_path))
+            }
+        }
+    }
+
+    fn spawn_recover_file(
+        &mut self,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        if let Some(ref file_path) = self.file_path {
+            let file_path = file_path.clone();
+            scheduler.spawn(move |_| {
+                Action::Async(
+                    recovery::read_recovery_file(&file_path)
+                        .map(|text| AsyncAction::LoadRecoveryFile { text })
+                        .map_err(|error| error.into()),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole text as a single edit which can be undone
+    fn replace_text(
+        &mut self,
+        mut new_text: Rope,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
+        utils::ensure_trailing_newline_with_content(&mut new_text);
+        let diff = OpaqueDiff::new(0, self.text.len_bytes(), new_text.len_bytes());
+        self.cursor.sync(&self.text, &new_text);
+        *self.text = new_text;
+        self.text.new_revision(diff.clone(), self.cursor.clone());
+        self.edit_count += 1;
+        if let Some(syntax) = self.syntax.as_mut() {
+            syntax.edit(&diff);
+            syntax.spawn_parse_task(scheduler, self.text.head().clone(), true)?;
+        }
+        Ok(())
+    }
+
     fn reduce_sync(
         &mut self,
         action: SyncAction,
         scheduler: &mut Scheduler<<Self as Component>::Action>,
         context: &Context,
     ) -> Result<()> {
+        if self.read_only && action.modifies_buffer() {
+            return Err(Error::ReadOnlyBuffer);
+        }
+        let indentation = self.indentation(context.settings);
+
         // Stateless
         match action {
-            SyncAction::Up => self.cursor.move_up(&self.text),
-            SyncAction::Down => self.cursor.move_down(&self.text),
+            SyncAction::Up => self.cursor.move_up(&self.text, indentation.tab_width),
+            SyncAction::Down => self.cursor.move_down(&self.text, indentation.tab_width),
             SyncAction::Left => self.cursor.move_left(&self.text),
             SyncAction::Right => self.cursor.move_right(&self.text),
-            SyncAction::PageDown => self
-                .cursor
-                .move_down_n(&self.text, context.frame.size.height - 1),
-            SyncAction::PageUp => self
-                .cursor
-                .move_up_n(&self.text, context.frame.size.height - 1),
+            SyncAction::PageDown => self.cursor.move_down_n(
+                &self.text,
+                indentation.tab_width,
+                context.frame.size.height - 1,
+            ),
+            SyncAction::PageUp => self.cursor.move_up_n(
+                &self.text,
+                indentation.tab_width,
+                context.frame.size.height - 1,
+            ),
             SyncAction::StartOfLine => self.cursor.move_to_start_of_line(&self.text),
             SyncAction::EndOfLine => self.cursor.move_to_end_of_line(&self.text),
             SyncAction::StartOfBuffer => self.cursor.move_to_start_of_buffer(&self.text),
             SyncAction::EndOfBuffer => self.cursor.move_to_end_of_buffer(&self.text),
             SyncAction::CenterCursorVisually => self.center_visual_cursor(&context.frame),
+            SyncAction::GotoPosition(position) => self.goto_position(position, &context.frame),
+            SyncAction::Move(motion) => {
+                self.apply_motion(motion, vi_count(context.prefix_arg), indentation.tab_width)
+            }
 
             SyncAction::BeginSelection => self.cursor.begin_selection(),
             SyncAction::ClearSelection => self.cursor.clear_selection(),
             SyncAction::SelectAll => self.cursor.select_all(&self.text),
-            SyncAction::SaveBuffer => self.spawn_save_file(scheduler, context)?,
+            SyncAction::CycleEncoding => {
+                self.format.encoding = self.format.encoding.next();
+                self.has_unsaved_changes = ModifiedStatus::Changed;
+            }
+            SyncAction::CycleLineEnding => {
+                self.format.line_ending = self.format.line_ending.next();
+                self.has_unsaved_changes = ModifiedStatus::Changed;
+            }
+            SyncAction::SaveBuffer | SyncAction::OverwriteFile => {
+                self.spawn_save_file(scheduler, context)?
+            }
+            SyncAction::RecoverFile => self.spawn_recover_file(scheduler)?,
             _ => {}
         };
 
@@ -168,17 +740,49 @@ impl Buffer {
             SyncAction::Yank => self.yank_line(),
             SyncAction::CopySelection => self.copy_selection(),
             SyncAction::CutSelection => self.cut_selection(),
-            SyncAction::InsertTab if DISABLE_TABS => {
-                let diff = self
-                    .cursor
-                    .insert_chars(&mut self.text, iter::repeat(' ').take(TAB_WIDTH));
-                self.cursor.move_right_n(&self.text, TAB_WIDTH);
+            SyncAction::Operate(operator, motion) => self.operate(
+                operator,
+                motion,
+                vi_count(context.prefix_arg),
+                indentation.tab_width,
+            ),
+            SyncAction::OperateOnSelection(operator) => {
+                // The selection includes the character under the cursor
+                if self.cursor.selection().end == self.cursor.range().start {
+                    self.cursor.move_right(&self.text);
+                }
+                match operator {
+                    Operator::Delete | Operator::Change => self.cut_selection(),
+                    Operator::Yank => self.copy_selection(),
+                }
+            }
+            SyncAction::PasteAfter => self.paste_after(),
+            SyncAction::OpenLineBelow => {
+                self.cursor.move_to_end_of_line(&self.text);
+                let diff = self.cursor.insert_char(&mut self.text, '\n');
+                self.cursor.move_right(&self.text);
+                diff
+            }
+            SyncAction::OpenLineAbove => {
+                self.cursor.move_to_start_of_line(&self.text);
+                self.cursor.insert_char(&mut self.text, '\n')
+            }
+            SyncAction::Tabify => {
+                self.convert_indentation(IndentStyle::Tabs, indentation.tab_width)
+            }
+            SyncAction::Untabify => {
+                self.convert_indentation(IndentStyle::Spaces, indentation.tab_width)
+            }
+            SyncAction::InsertTab => {
+                let unit = indentation.unit();
+                let diff = self.cursor.insert_chars(&mut self.text, unit.chars());
+                self.cursor.move_right_n(&self.text, unit.chars().count());
                 diff
             }
             SyncAction::InsertNewLine => {
                 let diff = self.cursor.insert_char(&mut self.text, '\n');
                 // self.ensure_trailing_newline_with_content();
-                self.cursor.move_down(&self.text);
+                self.cursor.move_down(&self.text, indentation.tab_width);
                 self.cursor.move_to_start_of_line(&self.text);
                 diff
             }
@@ -201,11 +805,21 @@ impl Buffer {
                 self.cursor.move_right(&self.text);
                 diff
             }
+            SyncAction::InsertText(ref text) => {
+                let end = self.cursor.range().start.0 + text.chars().count();
+                let diff = self.cursor.insert_chars(&mut self.text, text.chars());
+                self.cursor.move_to_char(&self.text, CharIndex(end));
+                diff
+            }
             _ => OpaqueDiff::empty(),
         };
+        if !diff.is_empty() {
+            self.edit_count += 1;
+        }
 
         if !diff.is_empty() && !undoing {
             self.has_unsaved_changes = ModifiedStatus::Changed;
+            self.needs_autosave = true;
             self.text.new_revision(diff.clone(), self.cursor.clone());
         }
 
@@ -230,31 +844,114 @@ impl Buffer {
         Ok(())
     }
 
-    fn reduce_async(&mut self, action: Result<AsyncAction>) -> Result<()> {
+    fn reduce_async(
+        &mut self,
+        action: Result<AsyncAction>,
+        scheduler: &mut Scheduler<<Self as Component>::Action>,
+    ) -> Result<()> {
         match action? {
-            AsyncAction::SaveFile { text: new_text } => {
+            AsyncAction::SaveFile {
+                edit_count,
+                text: new_text,
+                modified,
+                saved_lines,
+                final_newline,
+            } => {
+                if *self.text.head() != new_text {
+                    self.edit_count += 1;
+                }
                 self.cursor.sync(&self.text, &new_text);
                 self.text
                     .new_revision(OpaqueDiff::empty(), self.cursor.clone());
                 *self.text = new_text;
                 self.has_unsaved_changes = ModifiedStatus::Unchanged;
+                self.disk_state.read_or_written(modified);
+                self.disk_state.saved_lines = Arc::new(saved_lines);
+                self.disk_state.final_newline = final_newline;
+                self.disk_state.saved_edit_count = Some(edit_count);
+                // An auto-save may have written the recovery file after the
+                // save removed it
+                if self
+                    .disk_state
+                    .autosaved_edit_count
+                    .take()
+                    .map_or(false, |autosaved| autosaved <= edit_count)
+                {
+                    self.remove_recovery_file()?;
+                }
+            }
+            AsyncAction::CheckFile {
+                modified,
+                generation,
+            } if generation == self.disk_state.generation => {
+                self.handle_file_checked(modified, scheduler)?;
+            }
+            AsyncAction::ReloadFile {
+                text,
+                format,
+                modified,
+                generation,
+            } if generation == self.disk_state.generation => {
+                if let ModifiedStatus::Unchanged = self.has_unsaved_changes {
+                    self.disk_state.final_newline = utils::has_final_newline(&text);
+                    self.replace_text(text, scheduler)?;
+                    self.format = format;
+                    self.detected_indentation = indentation::detect(&self.text);
+                    self.disk_state.saved_lines = Arc::new(utils::line_hashes(&self.text));
+                    self.disk_state.read_or_written(Some(modified));
+                } else {
+                    // Edited while the file was being read
+                    self.handle_file_checked(Some(modified), scheduler)?;
+                }
             }
+            AsyncAction::CheckFile { .. } | AsyncAction::ReloadFile { .. } => {}
             AsyncAction::ParseSyntax(parsed) => {
                 if let Some(syntax) = self.syntax.as_mut() {
                     syntax.handle_parse_syntax_done(parsed);
                 }
             }
+            AsyncAction::SaveRecoveryFile { edit_count } => {
+                if self
+                    .disk_state
+                    .saved_edit_count
+                    .map_or(false, |saved| saved >= edit_count)
+                {
+                    self.remove_recovery_file()?;
+                } else {
+                    self.disk_state.autosaved_edit_count = Some(edit_count);
+                }
+            }
+            AsyncAction::LoadRecoveryFile { text } => {
+                self.replace_text(text, scheduler)?;
+                self.has_unsaved_changes = ModifiedStatus::Changed;
+                self.needs_autosave = true;
+            }
         }
         Ok(())
     }
 
     #[inline]
-    fn ensure_cursor_in_view(&mut self, frame: &Rect) {
+    fn ensure_cursor_in_view(&mut self, frame: &Rect, layout: LineLayout) {
         let new_line = self.text.char_to_line(self.cursor.range().start.0);
         if new_line < self.first_line {
             self.first_line = new_line;
-        } else if new_line - self.first_line > frame.size.height - 1 {
-            self.first_line = new_line - frame.size.height + 1;
+        } else if !layout.wrap_lines {
+            if new_line - self.first_line > frame.size.height - 1 {
+                self.first_line = new_line - frame.size.height + 1;
+            }
+        } else {
+            // Scroll as little as possible to fit the wrapped lines up to the
+            // cursor's
+            let mut first_line = new_line;
+            let mut height = layout.height(self.text.line(new_line), frame.size.width);
+            while first_line > self.first_line {
+                height += layout.height(self.text.line(first_line - 1), frame.size.width);
+                if height > frame.size.height {
+                    break;
+                }
+                first_line -= 1;
+            }
+            self.first_line = first_line;
         }
     }
 
@@ -267,7 +964,8 @@ impl Buffer {
         line: RopeSlice,
         mut syntax_cursor: Option<&mut SyntaxCursor>,
         mut trace: &mut NodeTrace<SelectorNodeId>,
-    ) -> usize {
+        layout: LineLayout,
+    ) -> (usize, usize) {
         // Get references to the relevant bits of context
         let Context {
             ref frame,
@@ -278,20 +976,13 @@ impl Buffer {
             ..
         } = *context;
 
-        // Highlight the currently selected line
         let line_under_cursor = self.text.char_to_line(self.cursor.range().start.0) == line_index;
-        if line_under_cursor && focused {
-            screen.clear_region(
-                Rect::new(
-                    Position::new(frame.origin.x, frame.origin.y),
-                    Size::new(frame.size.width, 1),
-                ),
-                theme.syntax.text_current_line,
-            );
-        }
+        self.draw_row_background(screen, context, frame.origin.y, line_under_cursor);
 
         let mut visual_cursor_x = 0;
         let mut visual_x = frame.origin.x;
+        let mut visual_y = frame.origin.y;
+        let mut column = 0;
         let mut char_index = CharIndex(self.text.line_to_char(line_index));
 
         let mut content: Cow<str> = self
@@ -340,7 +1031,7 @@ impl Buffer {
                 //     trace.nth_children,
                 //     content,
                 // );
-                visual_cursor_x = visual_x.saturating_sub(frame.origin.x);
+                visual_cursor_x = column;
             }
 
             let style = text_style_at_char(
@@ -352,24 +1043,31 @@ impl Buffer {
                 scope.unwrap_or(""),
                 trace.is_error,
             );
-            let grapheme_width = utils::grapheme_width(&grapheme);
+            let grapheme_width = utils::grapheme_width_at(&grapheme, column, layout.tab_width);
             let horizontal_bounds_inclusive = frame.min_x()..=frame.max_x();
             if !horizontal_bounds_inclusive.contains(&(visual_x + grapheme_width)) {
-                break;
+                if !layout.wrap_lines || visual_x == frame.origin.x || visual_y + 1 >= frame.max_y()
+                {
+                    break;
+                }
+                visual_x = frame.origin.x;
+                visual_y += 1;
+                self.draw_row_background(screen, context, visual_y, line_under_cursor);
             }
 
             if grapheme == "\t" {
                 for offset in 0..grapheme_width {
-                    screen.draw_str(visual_x + offset, frame.origin.y, style, " ");
+                    screen.draw_str(visual_x + offset, visual_y, style, " ");
                 }
             } else if grapheme_width == 0 {
-                screen.draw_str(visual_x, frame.origin.y, style, " ");
+                screen.draw_str(visual_x, visual_y, style, " ");
             } else {
-                screen.draw_rope_slice(visual_x, frame.origin.y, style, &grapheme);
+                screen.draw_rope_slice(visual_x, visual_y, style, &grapheme);
             }
 
             char_index.0 += grapheme.len_chars();
             visual_x += grapheme_width;
+            column += grapheme_width;
         }
 
         if line_index == self.text.len_lines() - 1
@@ -387,56 +1085,128 @@ impl Buffer {
             );
         }
 
-        visual_cursor_x
+        (visual_cursor_x, visual_y - frame.origin.y + 1)
+    }
+
+    /// Highlights the line under the cursor and marks the column past
+    /// `max_line_length`, which text drawn over it hides
+    #[inline]
+    fn draw_row_background(
+        &self,
+        screen: &mut Screen,
+        context: &Context,
+        visual_y: usize,
+        line_under_cursor: bool,
+    ) {
+        let Context {
+            ref frame,
+            focused,
+            theme: EditorTheme {
+                buffer: ref theme, ..
+            },
+            ..
+        } = *context;
+
+        if line_under_cursor && focused {
+            screen.clear_region(
+                Rect::new(
+                    Position::new(frame.origin.x, visual_y),
+                    Size::new(frame.size.width, 1),
+                ),
+                theme.syntax.text_current_line,
+            );
+        }
+
+        if let Some(max_line_length) = self.editorconfig.max_line_length() {
+            if max_line_length < frame.size.width {
+                screen.draw_str(
+                    frame.origin.x + max_line_length,
+                    visual_y,
+                    theme.syntax.text_current_line,
+                    " ",
+                );
+            }
+        }
     }
 
     #[inline]
     fn draw_text(&mut self, screen: &mut Screen, context: &Context) -> usize {
-        self.ensure_cursor_in_view(&context.frame);
+        let layout = self.line_layout(context.settings);
+        let line_numbers = self.line_numbers(context.settings);
+        let frame =
+            context
+                .frame
+                .inner_rect(SideOffsets2D::new(0, 0, 0, self.gutter_width(line_numbers)));
+        if self.center_when_drawn {
+            self.center_when_drawn = false;
+            self.center_line(self.text.char_to_line(self.cursor.range().start.0), &frame);
+        }
+        self.ensure_cursor_in_view(&frame, layout);
         let mut syntax_cursor = self.syntax.as_ref().and_then(|syntax| syntax.cursor());
         let mut trace: NodeTrace<SelectorNodeId> = NodeTrace::new();
 
         let mut visual_cursor_x = 0;
-        for (line_index, line) in self
-            .text
-            .lines_at(self.first_line)
-            .take(context.frame.size.height)
-            .enumerate()
-        {
-            visual_cursor_x = cmp::max(
-                visual_cursor_x,
-                self.draw_line(
-                    screen,
-                    &context.set_frame(
-                        context
-                            .frame
-                            .inner_rect(SideOffsets2D::new(line_index, 0, 0, 0)),
-                    ),
-                    self.first_line + line_index,
-                    line,
-                    syntax_cursor.as_mut(),
-                    &mut trace,
-                ),
+        let mut row = 0;
+        for (line_index, line) in (self.first_line..).zip(self.text.lines_at(self.first_line)) {
+            if row >= frame.size.height {
+                break;
+            }
+            let (line_cursor_x, height) = self.draw_line(
+                screen,
+                &context.set_frame(frame.inner_rect(SideOffsets2D::new(row, 0, 0, 0))),
+                line_index,
+                line,
+                syntax_cursor.as_mut(),
+                &mut trace,
+                layout,
             );
+            visual_cursor_x = cmp::max(visual_cursor_x, line_cursor_x);
+            self.draw_line_info(screen, context, row, Some(line_index), line_numbers);
+            row += height;
+        }
+        for row in row..frame.size.height {
+            self.draw_line_info(screen, context, row, None, line_numbers);
         }
 
         visual_cursor_x
     }
 
+    fn gutter_width(&self, line_numbers: bool) -> usize {
+        if line_numbers {
+            self.text.len_lines().to_string().len() + 1
+        } else {
+            1
+        }
+    }
+
+    /// Draws the gutter left of a line, with its number if enabled, or `~`
+    /// past the end of the text
     #[inline]
-    fn draw_line_info(&self, screen: &mut Screen, context: &Context) {
-        for screen_index in 0..context.frame.size.height - 1 {
-            screen.draw_str(
-                context.frame.origin.x,
-                context.frame.origin.y + screen_index as usize,
-                context.theme.buffer.border,
-                if self.first_line + screen_index < self.text.len_lines() - 1 {
-                    " "
+    fn draw_line_info(
+        &self,
+        screen: &mut Screen,
+        context: &Context,
+        row: usize,
+        line_index: Option<usize>,
+        line_numbers: bool,
+    ) {
+        let info = match line_index {
+            Some(line_index) if line_index < self.text.len_lines() - 1 => {
+                if line_numbers {
+                    format!("{} ", line_index + 1)
                 } else {
-                    "~"
-                },
-            );
-        }
+                    " ".into()
+                }
+            }
+            _ => "~".into(),
+        };
+        let gutter_width = self.gutter_width(line_numbers);
+        screen.draw_str(
+            context.frame.origin.x + gutter_width - cmp::min(info.len(), gutter_width),
+            context.frame.origin.y + row,
+            context.theme.buffer.border,
+            &info,
+        );
     }
 
     #[inline]
@@ -472,6 +1242,16 @@ impl Buffer {
             &format!(" {} ", frame_id),
         );
 
+        // Mode of the vi key bindings
+        if let (Some(vi_mode), true) = (context.vi_mode, focused) {
+            offset += screen.draw_str(
+                offset,
+                line_height,
+                theme.status_mode,
+                &format!(" {} ", vi_mode.label()),
+            );
+        }
+
         // Has unsaved changes
         offset += screen.draw_str(
             offset,
@@ -481,6 +1261,7 @@ impl Buffer {
                 _ => theme.status_is_modified,
             },
             match self.has_unsaved_changes {
+                ModifiedStatus::Unchanged if self.read_only => " % ",
                 ModifiedStatus::Unchanged => " - ",
                 ModifiedStatus::Changed | ModifiedStatus::Saving(..) => " ☲ ",
                 // ModifiedStatus::Saving(start_time) => [" | ", " / ", " - ", " \\ "]
@@ -499,7 +1280,7 @@ impl Buffer {
             ),
         );
 
-        // File name if buffer is backed by a file
+        // File name if buffer is backed by a file, otherwise a placeholder
         offset += screen.draw_str(
             offset,
             line_height,
@@ -513,17 +1294,29 @@ impl Buffer {
                         None => format!("{} ", path.display()),
                     },
                 )
-                .unwrap_or_else(String::new),
+                .unwrap_or_else(|| format!("{} ", SCRATCH_BUFFER_NAME)),
         );
 
         // Name of the current mode
-        screen.draw_str(
+        offset += screen.draw_str(
             offset,
             line_height,
             theme.status_mode,
             &format!(" {}", self.mode.name),
         );
 
+        // How the file is stored on disk
+        screen.draw_str(
+            offset,
+            line_height,
+            theme.status_mode,
+            &format!(
+                "  {} {}",
+                self.format.encoding.name(),
+                self.format.line_ending.name()
+            ),
+        );
+
         // Name of the current mode
         //let reference = self.repo.as_ref().map(|repo| repo.head().unwrap());
 
@@ -570,6 +1363,32 @@ impl Buffer {
         }
     }
 
+    /// Moves the cursor to a line and column, clamped to the text, and
+    /// scrolls so the line is in the middle of the frame
+    fn goto_position(&mut self, position: files::Position, frame: &Rect) {
+        // The text ends with a newline, the empty line after it doesn't count
+        let line = cmp::min(
+            position.line.saturating_sub(1),
+            self.text.len_lines().saturating_sub(2),
+        );
+        let line_start = self.text.line_to_char(line);
+        let line_length = self.text.line(line).len_chars().saturating_sub(1);
+        let column = cmp::min(position.column.saturating_sub(1), line_length);
+        self.cursor
+            .move_to_char(&self.text, CharIndex(line_start + column));
+        if frame.size.height == 0 {
+            self.center_when_drawn = true;
+        } else {
+            self.center_line(line, frame);
+        }
+    }
+
+    /// Centred like the first press of `center_visual_cursor`, but without
+    /// cycling to the top when the line is already in the middle
+    fn center_line(&mut self, line: usize, frame: &Rect) {
+        self.first_line = line.saturating_sub(frame.size.height / 2);
+    }
+
     fn delete_line(&mut self) -> OpaqueDiff {
         let operation = self.cursor.delete_line(&mut self.text);
         self.clipboard = Some(operation.deleted);
@@ -597,6 +1416,253 @@ impl Buffer {
         self.clipboard = Some(operation.deleted);
         operation.diff
     }
+
+    /// Moves the cursor like a vi motion. `count` repeats it, or is the line to
+    /// go to for `gg` and `G`.
+    fn apply_motion(&mut self, motion: Motion, count: Option<usize>, tab_width: usize) {
+        let times = count.unwrap_or(1);
+        match motion {
+            Motion::Left => (0..times).for_each(|_| self.cursor.move_left(&self.text)),
+            Motion::Right => self.cursor.move_right_n(&self.text, times),
+            Motion::Up => self.cursor.move_up_n(&self.text, tab_width, times),
+            Motion::Down => self.cursor.move_down_n(&self.text, tab_width, times),
+            Motion::NextWordStart => {
+                (0..times).for_each(|_| self.cursor.move_to_next_word_start(&self.text))
+            }
+            Motion::PreviousWordStart => {
+                (0..times).for_each(|_| self.cursor.move_to_previous_word_start(&self.text))
+            }
+            Motion::WordEnd => (0..times).for_each(|_| self.cursor.move_to_word_end(&self.text)),
+            Motion::StartOfLine => self.cursor.move_to_start_of_line(&self.text),
+            Motion::EndOfLine => self.cursor.move_to_end_of_line(&self.text),
+            Motion::FirstLine | Motion::LastLine => {
+                // The text ends with a newline, the empty line after it
+                // doesn't count
+                let last_line = self.text.len_lines().saturating_sub(2);
+                let line = match (motion, count) {
+                    (_, Some(line)) => cmp::min(line.saturating_sub(1), last_line),
+                    (Motion::FirstLine, None) => 0,
+                    _ => last_line,
+                };
+                self.cursor
+                    .move_to_char(&self.text, CharIndex(self.text.line_to_char(line)));
+            }
+            Motion::Lines => {
+                self.cursor
+                    .move_down_n(&self.text, tab_width, times.saturating_sub(1))
+            }
+        }
+    }
+
+    /// Deletes, changes or copies the text from the cursor to where `motion`
+    /// moves it, or the whole lines in between for linewise motions
+    fn operate(
+        &mut self,
+        operator: Operator,
+        motion: Motion,
+        count: Option<usize>,
+        tab_width: usize,
+    ) -> OpaqueDiff {
+        // Like in vi, `cw` changes to the end of the word
+        let motion = match (operator, motion) {
+            (Operator::Change, Motion::NextWordStart) => Motion::WordEnd,
+            _ => motion,
+        };
+        let start = self.cursor.range().start;
+        self.cursor.clear_selection();
+        self.apply_motion(motion, count, tab_width);
+        let end = self.cursor.range().start;
+
+        let range = if motion.is_linewise() {
+            let first_line = self.text.char_to_line(cmp::min(start, end).0);
+            let last_line = self.text.char_to_line(cmp::max(start, end).0);
+            let mut range =
+                self.text.line_to_char(first_line)..self.text.line_to_char(last_line + 1);
+            // A changed line is kept, empty
+            if operator == Operator::Change && range.end > range.start {
+                range.end -= 1;
+            }
+            range
+        } else if motion.is_inclusive() {
+            let end = next_grapheme_boundary(&self.text.slice(..), cmp::max(start, end));
+            cmp::min(start, end).0..end.0
+        } else {
+            cmp::min(start, end).0..cmp::max(start, end).0
+        };
+        if range.is_empty() {
+            self.cursor.move_to_char(&self.text, start);
+            return OpaqueDiff::empty();
+        }
+
+        self.cursor.move_to_char(&self.text, CharIndex(range.end));
+        self.cursor.begin_selection();
+        self.cursor.move_to_char(&self.text, CharIndex(range.start));
+        match operator {
+            Operator::Delete | Operator::Change => self.cut_selection(),
+            Operator::Yank => {
+                let diff = self.copy_selection();
+                self.cursor
+                    .move_to_char(&self.text, start.min(CharIndex(range.start)));
+                diff
+            }
+        }
+    }
+
+    /// Pastes after the cursor, or below the current line if whole lines were
+    /// copied
+    fn paste_after(&mut self) -> OpaqueDiff {
+        let clipboard = match self.clipboard {
+            Some(ref clipboard) if clipboard.len_chars() > 0 => clipboard.to_string(),
+            _ => return OpaqueDiff::empty(),
+        };
+        if !clipboard.ends_with('\n') {
+            self.cursor.move_right(&self.text);
+            return self.yank_line();
+        }
+
+        let line_index = self.text.char_to_line(self.cursor.range().start.0);
+        let char_index = self.text.line_to_char(line_index + 1);
+        let diff = OpaqueDiff::new(self.text.char_to_byte(char_index), 0, clipboard.len());
+        self.text.insert(char_index, &clipboard);
+        self.cursor.move_to_char(&self.text, CharIndex(char_index));
+        diff
+    }
+
+    /// Rewrites the indentation of the lines in the selection, or of the whole
+    /// buffer if nothing is selected
+    fn convert_indentation(&mut self, style: IndentStyle, tab_width: usize) -> OpaqueDiff {
+        let (start_line, end_line) = if self.cursor.has_selection() {
+            let selection = self.cursor.selection();
+            (
+                self.text.char_to_line(selection.start.0),
+                self.text.char_to_line(cmp::max(
+                    selection.start.0,
+                    selection.end.0.saturating_sub(1),
+                )),
+            )
+        } else {
+            (0, self.text.len_lines() - 1)
+        };
+        let start = self.text.line_to_char(start_line);
+        let end = self.text.line_to_char(end_line + 1);
+        let old_text: String = self.text.slice(start..end).into();
+        let new_text = indentation::convert(&old_text, style, tab_width);
+        self.cursor.clear_selection();
+        if new_text == old_text {
+            return OpaqueDiff::empty();
+        }
+
+        let cursor_line = self.text.char_to_line(self.cursor_position().0);
+        let diff = OpaqueDiff::new(
+            self.text.char_to_byte(start),
+            old_text.len(),
+            new_text.len(),
+        );
+        self.text.remove(start..end);
+        self.text.insert(start, &new_text);
+        self.detected_indentation = indentation::detect(&self.text);
+        let cursor_position = CharIndex(self.text.line_to_char(cursor_line));
+        self.cursor.move_to_char(&self.text, cursor_position);
+        diff
+    }
+}
+
+/// How lines are laid out on screen
+#[derive(Clone, Copy, Debug)]
+struct LineLayout {
+    tab_width: usize,
+    wrap_lines: bool,
+}
+
+impl LineLayout {
+    /// The number of rows a line takes up in a window `width` columns wide
+    fn height(&self, line: RopeSlice, width: usize) -> usize {
+        if !self.wrap_lines {
+            return 1;
+        }
+        let mut rows = 1;
+        let mut visual_x = 0;
+        let mut column = 0;
+        for grapheme in RopeGraphemes::new(&line) {
+            let grapheme_width = utils::grapheme_width_at(&grapheme, column, self.tab_width);
+            if visual_x > 0 && visual_x + grapheme_width > width {
+                rows += 1;
+                visual_x = 0;
+            }
+            visual_x += grapheme_width;
+            column += grapheme_width;
+        }
+        rows
+    }
+}
+
+/// Pipes the text through a shell command run in the file's directory and
+/// returns what it writes to stdout
+fn format_text(command: &str, text: &Rope, file_path: &Path) -> Result<Rope> {
+    let directory = file_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut child = shell_command(command)
+        .current_dir(directory)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Written from another thread, as the command may fill up stdout before
+    // it's done reading stdin
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = text.clone();
+    let writer = thread::spawn(move || input.write_to(&mut stdin));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    let error = |message: String| Error::FormatOnSave(command.into(), message);
+    if !output.status.success() {
+        return Err(error(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    let mut formatted = String::from_utf8(output.stdout)
+        .map(Rope::from)
+        .map_err(|_| error("the output isn't valid UTF-8".into()))?;
+    utils::ensure_trailing_newline_with_content(&mut formatted);
+    Ok(formatted)
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Shown instead of the file name for buffers which aren't backed by a file
+pub const SCRATCH_BUFFER_NAME: &str = "*scratch*";
+
+/// The count of a vi command, `None` if it wasn't given one
+fn vi_count(prefix_arg: PrefixArg) -> Option<usize> {
+    match prefix_arg {
+        PrefixArg::None => None,
+        prefix_arg => Some(prefix_arg.count()),
+    }
+}
+
+/// Modification time of a file, `None` if it doesn't exist
+fn file_modified_time(path: &Path) -> io::Result<Option<SystemTime>> {
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => Ok(Some(modified)),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
 }
 
 pub enum Action {
@@ -604,7 +1670,7 @@ pub enum Action {
     Async(Result<AsyncAction>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SyncAction {
     // Movement
     Up,
@@ -618,6 +1684,7 @@ pub enum SyncAction {
     StartOfBuffer,
     EndOfBuffer,
     CenterCursorVisually,
+    GotoPosition(files::Position),
 
     // Editing
     BeginSelection,
@@ -632,15 +1699,202 @@ pub enum SyncAction {
     InsertTab,
     InsertNewLine,
     InsertChar(char),
+    InsertText(String),
     Undo,
 
+    // Vi commands, the count is given as the prefix argument
+    Move(Motion),
+    Operate(Operator, Motion),
+    OperateOnSelection(Operator),
+    PasteAfter,
+    OpenLineBelow,
+    OpenLineAbove,
+
     // Buffer
+    CycleEncoding,
+    CycleLineEnding,
+    Tabify,
+    Untabify,
     SaveBuffer,
+    OverwriteFile,
+    RecoverFile,
+}
+
+impl NamedAction for SyncAction {
+    const ACTIONS: &'static [ActionInfo<Self>] = &[
+        ActionInfo::new("up", "Move the cursor up a line", Self::Up),
+        ActionInfo::new("down", "Move the cursor down a line", Self::Down),
+        ActionInfo::new("left", "Move the cursor back a character", Self::Left),
+        ActionInfo::new("right", "Move the cursor forward a character", Self::Right),
+        ActionInfo::new("page-down", "Move down one page", Self::PageDown),
+        ActionInfo::new("page-up", "Move up one page", Self::PageUp),
+        ActionInfo::new(
+            "start-of-line",
+            "Move to the start of the line",
+            Self::StartOfLine,
+        ),
+        ActionInfo::new(
+            "end-of-line",
+            "Move to the end of the line",
+            Self::EndOfLine,
+        ),
+        ActionInfo::new(
+            "start-of-buffer",
+            "Move to the beginning of the buffer",
+            Self::StartOfBuffer,
+        ),
+        ActionInfo::new(
+            "end-of-buffer",
+            "Move to the end of the buffer",
+            Self::EndOfBuffer,
+        ),
+        ActionInfo::new(
+            "center-cursor-visually",
+            "Scroll so the cursor is in the middle of the window",
+            Self::CenterCursorVisually,
+        ),
+        ActionInfo::new(
+            "begin-selection",
+            "Start selecting text at the cursor",
+            Self::BeginSelection,
+        ),
+        ActionInfo::new(
+            "clear-selection",
+            "Clear the selection",
+            Self::ClearSelection,
+        ),
+        ActionInfo::new("select-all", "Select the whole buffer", Self::SelectAll),
+        ActionInfo::new(
+            "delete-forward",
+            "Delete the character after the cursor",
+            Self::DeleteForward,
+        ),
+        ActionInfo::new(
+            "delete-backward",
+            "Delete the character before the cursor",
+            Self::DeleteBackward,
+        ),
+        ActionInfo::new(
+            "delete-line",
+            "Delete the rest of the line",
+            Self::DeleteLine,
+        ),
+        ActionInfo::new("yank", "Paste the last copied or cut text", Self::Yank),
+        ActionInfo::new("copy-selection", "Copy the selection", Self::CopySelection),
+        ActionInfo::new("cut-selection", "Cut the selection", Self::CutSelection),
+        ActionInfo::new("insert-tab", "Indent with a tab or spaces", Self::InsertTab),
+        ActionInfo::new("insert-new-line", "Insert a new line", Self::InsertNewLine),
+        ActionInfo::new("undo", "Undo the last change", Self::Undo),
+        ActionInfo::new(
+            "cycle-encoding",
+            "Change the encoding used when saving",
+            Self::CycleEncoding,
+        ),
+        ActionInfo::new(
+            "cycle-line-ending",
+            "Switch between LF and CRLF line endings",
+            Self::CycleLineEnding,
+        ),
+        ActionInfo::new(
+            "tabify",
+            "Indent the selection or the buffer with tabs",
+            Self::Tabify,
+        ),
+        ActionInfo::new(
+            "untabify",
+            "Indent the selection or the buffer with spaces",
+            Self::Untabify,
+        ),
+        ActionInfo::new(
+            "save-buffer",
+            "Save the buffer to its file",
+            Self::SaveBuffer,
+        ),
+    ];
+}
+
+impl SyncAction {
+    /// Whether the action is repeated when given a count with `C-u` or
+    /// `A-<digit>`
+    fn is_repeatable(&self) -> bool {
+        matches!(
+            self,
+            Self::Up
+                | Self::Down
+                | Self::Left
+                | Self::Right
+                | Self::PageDown
+                | Self::PageUp
+                | Self::DeleteForward
+                | Self::DeleteBackward
+                | Self::DeleteLine
+                | Self::Yank
+                | Self::PasteAfter
+                | Self::InsertTab
+                | Self::InsertNewLine
+                | Self::InsertChar(_)
+                | Self::Undo
+        )
+    }
+
+    /// Whether the action changes the text or the file, which read only
+    /// buffers refuse
+    fn modifies_buffer(&self) -> bool {
+        matches!(
+            self,
+            Self::DeleteForward
+                | Self::DeleteBackward
+                | Self::DeleteLine
+                | Self::Yank
+                | Self::CutSelection
+                | Self::InsertTab
+                | Self::InsertNewLine
+                | Self::InsertChar(_)
+                | Self::InsertText(_)
+                | Self::Undo
+                | Self::Operate(Operator::Delete, _)
+                | Self::Operate(Operator::Change, _)
+                | Self::OperateOnSelection(Operator::Delete)
+                | Self::OperateOnSelection(Operator::Change)
+                | Self::PasteAfter
+                | Self::OpenLineBelow
+                | Self::OpenLineAbove
+                | Self::CycleEncoding
+                | Self::CycleLineEnding
+                | Self::Tabify
+                | Self::Untabify
+                | Self::SaveBuffer
+                | Self::OverwriteFile
+                | Self::RecoverFile
+        )
+    }
 }
 
 pub enum AsyncAction {
-    SaveFile { text: Rope },
+    SaveFile {
+        edit_count: usize,
+        text: Rope,
+        modified: Option<SystemTime>,
+        saved_lines: HashSet<u64>,
+        final_newline: bool,
+    },
     ParseSyntax(ParserStatus),
+    CheckFile {
+        modified: Option<SystemTime>,
+        generation: usize,
+    },
+    ReloadFile {
+        text: Rope,
+        format: FileFormat,
+        modified: SystemTime,
+        generation: usize,
+    },
+    SaveRecoveryFile {
+        edit_count: usize,
+    },
+    LoadRecoveryFile {
+        text: Rope,
+    },
 }
 
 static HASH_BINDINGS: Lazy<HashBindings<SyncAction>> = Lazy::new(|| {
@@ -685,27 +1939,43 @@ static HASH_BINDINGS: Lazy<HashBindings<SyncAction>> = Lazy::new(|| {
         // Buffer
         smallvec![Key::Ctrl('x'), Key::Ctrl('s')] => SyncAction::SaveBuffer,
         smallvec![Key::Ctrl('x'), Key::Char('s')] => SyncAction::SaveBuffer,
+        smallvec![Key::Ctrl('x'), Key::Char('\n'), Key::Char('f')] => SyncAction::CycleEncoding,
+        smallvec![Key::Ctrl('x'), Key::Char('\n'), Key::Char('l')] => SyncAction::CycleLineEnding,
+        smallvec![Key::Ctrl('x'), Key::Char('t')] => SyncAction::Tabify,
+        smallvec![Key::Ctrl('x'), Key::Char('T')] => SyncAction::Untabify,
     })
 });
 
-pub struct BufferBindings;
+pub fn default_bindings() -> HashBindings<SyncAction> {
+    HASH_BINDINGS.clone()
+}
+
+/// The bindings of the focused buffer, any other character is inserted
+pub struct BufferBindings(HashBindings<SyncAction>);
+
+impl BufferBindings {
+    pub fn new(bindings: HashBindings<SyncAction>) -> Self {
+        Self(bindings)
+    }
+
+    pub fn hash_bindings(&self) -> &HashBindings<SyncAction> {
+        &self.0
+    }
+}
 
 impl Bindings<Action> for BufferBindings {
     fn matches(&self, pressed: &[Key]) -> BindingMatch<Action> {
         match pressed {
-            [Key::Char(character)]
-                if *character != '\n' && (!DISABLE_TABS || *character != '\t') =>
-            {
+            [Key::Char(character)] if *character != '\n' && *character != '\t' => {
                 BindingMatch::Full(Action::Sync(SyncAction::InsertChar(*character)))
             }
-            pressed => HASH_BINDINGS.matches(pressed).map_action(Action::Sync),
+            pressed => self.0.matches(pressed).map_action(Action::Sync),
         }
     }
 }
 
 impl Component for Buffer {
     type Action = Action;
-    type Bindings = BufferBindings;
 
     fn draw(
         &mut self,
@@ -727,10 +1997,9 @@ impl Component for Buffer {
         }
 
         screen.clear_region(context.frame, context.theme.buffer.syntax.text);
-        self.draw_line_info(screen, context);
         let visual_cursor_x = self.draw_text(
             screen,
-            &context.set_frame(context.frame.inner_rect(SideOffsets2D::new(0, 0, 1, 1))),
+            &context.set_frame(context.frame.inner_rect(SideOffsets2D::new(0, 0, 1, 0))),
         );
         self.draw_status_bar(screen, context, visual_cursor_x);
     }
@@ -742,18 +2011,96 @@ impl Component for Buffer {
         context: &Context,
     ) -> Result<()> {
         match action {
+            Action::Sync(action) if action.is_repeatable() => {
+                for _ in 0..context.prefix_arg.repeat_count() {
+                    self.reduce_sync(action.clone(), scheduler, context)?;
+                }
+                Ok(())
+            }
             Action::Sync(action) => self.reduce_sync(action, scheduler, context),
-            Action::Async(action) => self.reduce_async(action),
+            Action::Async(action) => self.reduce_async(action, scheduler),
         }
     }
 
-    fn bindings(&self) -> Option<&Self::Bindings> {
-        Some(&self.bindings)
-    }
-
     fn path(&self) -> Option<&Path> {
         self.file_path.as_ref().map(|path| path.as_path())
     }
 }
 
-const DISABLE_TABS: bool = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::TaskPool;
+    use std::env;
+
+    #[test]
+    fn stale_recovery_files_are_removed() {
+        let path = env::temp_dir().join(format!("zee-autosave-{}.txt", std::process::id()));
+        fs::write(&path, "saved\n").unwrap();
+        let recovery_path = recovery::recovery_path(&path).unwrap();
+        let task_pool = TaskPool::new().unwrap();
+        let mut scheduler = task_pool.scheduler();
+        let mut buffer = Buffer::from_file(path.clone(), &Settings::default()).unwrap();
+        let mut reduce = |buffer: &mut Buffer, action| {
+            fs::write(&recovery_path, "recovered\n").unwrap();
+            buffer.reduce_async(Ok(action), &mut scheduler).unwrap();
+            recovery_path.exists()
+        };
+        let saved = || AsyncAction::SaveFile {
+            edit_count: 0,
+            text: Rope::from_str("saved\n"),
+            modified: None,
+            saved_lines: HashSet::new(),
+            final_newline: true,
+        };
+
+        // The auto-save finished first, but wrote after the save removed it
+        assert!(reduce(
+            &mut buffer,
+            AsyncAction::SaveRecoveryFile { edit_count: 0 }
+        ));
+        assert!(!reduce(&mut buffer, saved()));
+        // The auto-save finished after the save
+        assert!(!reduce(
+            &mut buffer,
+            AsyncAction::SaveRecoveryFile { edit_count: 0 }
+        ));
+        // The auto-save has edits made since the save
+        assert!(reduce(
+            &mut buffer,
+            AsyncAction::SaveRecoveryFile { edit_count: 1 }
+        ));
+        fs::remove_file(&recovery_path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn universal_argument_saves_as_is() {
+        let settings: Settings = toml::de::from_str(
+            "theme_index = 0\n\
+             [mode.plain]\n\
+             trim_trailing_whitespace = \"always\"\n\
+             format_on_save = \"fmt\"\n",
+        )
+        .unwrap();
+        let buffer = Buffer::new_scratch();
+        let options = |prefix_arg| buffer.save_options(&settings, prefix_arg);
+        assert_eq!(
+            options(PrefixArg::None),
+            SaveOptions {
+                trim_trailing_whitespace: TrimTrailingWhitespace::Always,
+                final_newline: settings.insert_final_newline,
+                format_on_save: Some("fmt".into()),
+            }
+        );
+        assert_eq!(
+            options(PrefixArg::Universal(1)),
+            SaveOptions {
+                trim_trailing_whitespace: TrimTrailingWhitespace::Never,
+                final_newline: settings.insert_final_newline,
+                format_on_save: None,
+            }
+        );
+        assert_eq!(options(PrefixArg::Number(2)), options(PrefixArg::None));
+    }
+}
@@ -64,6 +64,10 @@ impl Cursor {
         }
     }
 
+    pub fn has_selection(&self) -> bool {
+        self.selection.is_some()
+    }
+
     pub fn begin_selection(&mut self) {
         self.selection = Some(self.range.start)
     }
@@ -77,31 +81,31 @@ impl Cursor {
         self.selection = Some(CharIndex(text.len_chars()));
     }
 
-    pub fn move_up(&mut self, text: &Rope) {
+    pub fn move_up(&mut self, text: &Rope, tab_width: usize) {
         let current_line_index = text.char_to_line(self.range.start.0);
         if current_line_index == 0 {
             return;
         }
-        self.move_vertically(text, current_line_index, current_line_index - 1);
+        self.move_vertically(text, tab_width, current_line_index, current_line_index - 1);
     }
 
-    pub fn move_up_n(&mut self, text: &Rope, n: usize) {
+    pub fn move_up_n(&mut self, text: &Rope, tab_width: usize, n: usize) {
         for _ in 0..n {
-            self.move_up(text);
+            self.move_up(text, tab_width);
         }
     }
 
-    pub fn move_down(&mut self, text: &Rope) {
+    pub fn move_down(&mut self, text: &Rope, tab_width: usize) {
         let current_line_index = text.char_to_line(self.range.start.0);
         if current_line_index >= text.len_lines() {
             return;
         }
-        self.move_vertically(text, current_line_index, current_line_index + 1);
+        self.move_vertically(text, tab_width, current_line_index, current_line_index + 1);
     }
 
-    pub fn move_down_n(&mut self, text: &Rope, n: usize) {
+    pub fn move_down_n(&mut self, text: &Rope, tab_width: usize, n: usize) {
         for _ in 0..n {
-            self.move_down(text);
+            self.move_down(text, tab_width);
         }
     }
 
@@ -151,6 +155,52 @@ impl Cursor {
         self.visual_horizontal_offset = None;
     }
 
+    /// Moves to the start of the next word, words being runs of either
+    /// alphanumeric characters or punctuation like in vi
+    pub fn move_to_next_word_start(&mut self, text: &Rope) {
+        let last = text.len_chars().saturating_sub(1);
+        let mut index = self.range.start.0;
+        let class = char_class(text, index);
+        if class != CharClass::Whitespace {
+            while index < last && char_class(text, index) == class {
+                index += 1;
+            }
+        }
+        while index < last && char_class(text, index) == CharClass::Whitespace {
+            index += 1;
+        }
+        self.move_to_char(text, CharIndex(index));
+    }
+
+    pub fn move_to_previous_word_start(&mut self, text: &Rope) {
+        let mut index = self.range.start.0;
+        while index > 0 && char_class(text, index - 1) == CharClass::Whitespace {
+            index -= 1;
+        }
+        if index > 0 {
+            let class = char_class(text, index - 1);
+            while index > 0 && char_class(text, index - 1) == class {
+                index -= 1;
+            }
+        }
+        self.move_to_char(text, CharIndex(index));
+    }
+
+    /// Moves to the last character of the current word, or of the next one if
+    /// already there
+    pub fn move_to_word_end(&mut self, text: &Rope) {
+        let last = text.len_chars().saturating_sub(1);
+        let mut index = cmp::min(self.range.start.0 + 1, last);
+        while index < last && char_class(text, index) == CharClass::Whitespace {
+            index += 1;
+        }
+        let class = char_class(text, index);
+        while index < last && char_class(text, index + 1) == class {
+            index += 1;
+        }
+        self.move_to_char(text, CharIndex(index));
+    }
+
     pub fn move_to_start_of_buffer(&mut self, text: &Rope) {
         self.range = CharIndex(0)..next_grapheme_boundary(&text.slice(..), CharIndex(0));
         self.visual_horizontal_offset = None;
@@ -162,6 +212,18 @@ impl Cursor {
         self.visual_horizontal_offset = None;
     }
 
+    pub fn move_to_char(&mut self, text: &Rope, char_index: CharIndex) {
+        let char_index = cmp::min(char_index, CharIndex(text.len_chars().saturating_sub(1)));
+        let grapheme_end = next_grapheme_boundary(&text.slice(..), char_index);
+        let grapheme_start = prev_grapheme_boundary(&text.slice(..), grapheme_end);
+        self.range = if grapheme_start != grapheme_end {
+            grapheme_start..grapheme_end
+        } else {
+            CharIndex(0)..CharIndex(1)
+        };
+        self.visual_horizontal_offset = None;
+    }
+
     pub fn insert_char(&mut self, text: &mut Rope, character: char) -> OpaqueDiff {
         text.insert_char(self.range.start.0, character);
         ensure_trailing_newline_with_content(text);
@@ -304,7 +366,13 @@ impl Cursor {
         self.selection = None;
     }
 
-    fn move_vertically(&mut self, text: &Rope, current_line_index: usize, new_line_index: usize) {
+    fn move_vertically(
+        &mut self,
+        text: &Rope,
+        tab_width: usize,
+        current_line_index: usize,
+        new_line_index: usize,
+    ) {
         if new_line_index >= text.len_lines() {
             return;
         }
@@ -312,14 +380,17 @@ impl Cursor {
         let current_line_start = text.line_to_char(current_line_index);
         let cursor_range_start = self.range.start;
         let current_visual_x = self.visual_horizontal_offset.get_or_insert_with(|| {
-            utils::grapheme_width(&text.slice(current_line_start..cursor_range_start.0))
+            utils::visual_width(
+                &text.slice(current_line_start..cursor_range_start.0),
+                tab_width,
+            )
         });
 
         let new_line = text.line(new_line_index);
         let mut graphemes = RopeGraphemes::new(&new_line);
         let mut new_visual_x = 0;
         while let Some(grapheme) = graphemes.next() {
-            let width = utils::grapheme_width(&grapheme);
+            let width = utils::grapheme_width_at(&grapheme, new_visual_x, tab_width);
             if new_visual_x + width > *current_visual_x {
                 break;
             }
@@ -361,6 +432,24 @@ impl DeleteOperation {
 }
 
 /// Finds the previous grapheme boundary before the given char position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+fn char_class(text: &Rope, char_index: usize) -> CharClass {
+    if char_index >= text.len_chars() {
+        return CharClass::Whitespace;
+    }
+    match text.char(char_index) {
+        character if character.is_alphanumeric() || character == '_' => CharClass::Word,
+        character if !character.is_whitespace() => CharClass::Punctuation,
+        _ => CharClass::Whitespace,
+    }
+}
+
 pub fn prev_grapheme_boundary(slice: &RopeSlice, char_index: CharIndex) -> CharIndex {
     // Bounds check
     debug_assert!(char_index.0 <= slice.len_chars());
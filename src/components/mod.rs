@@ -10,6 +10,7 @@ pub use prompt::Prompt;
 pub use splash::Splash;
 pub use theme::Theme;
 
+use serde_derive::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 use std::{
     cmp::{self, Ordering},
@@ -20,9 +21,11 @@ use std::{
 
 use crate::{
     error::Result,
+    keymap::KeySequence,
     settings::Settings,
     task,
     terminal::{screen::Screen, Key, Position, Rect, Size},
+    vi,
 };
 
 pub type ComponentId = usize;
@@ -40,6 +43,9 @@ pub struct Context<'t> {
     pub theme: &'t Theme,
     pub path: &'t Path,
     pub settings: &'t Settings,
+    pub prefix_arg: PrefixArg,
+    /// The mode of the vi key bindings when they're used
+    pub vi_mode: Option<vi::Mode>,
 }
 
 impl<'t> Context<'t> {
@@ -52,6 +58,8 @@ impl<'t> Context<'t> {
             theme: self.theme,
             path: self.path,
             settings: self.settings,
+            prefix_arg: self.prefix_arg,
+            vi_mode: self.vi_mode,
         }
     }
 
@@ -64,13 +72,49 @@ impl<'t> Context<'t> {
             theme: self.theme,
             path: self.path,
             settings: self.settings,
+            prefix_arg: self.prefix_arg,
+            vi_mode: self.vi_mode,
         }
     }
 }
 
+pub const MAX_REPEAT_COUNT: usize = 10_000;
+
+/// The argument given to an action with `C-u` or `A-<digit>` before its keys
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PrefixArg {
+    #[default]
+    None,
+    /// `C-u` pressed a number of times without any digits
+    Universal(u32),
+    Number(usize),
+}
+
+impl PrefixArg {
+    /// How many times to repeat the action, each `C-u` multiplies it by 4
+    pub fn count(self) -> usize {
+        match self {
+            Self::None => 1,
+            Self::Universal(times) => 4usize.saturating_pow(times),
+            Self::Number(number) => number,
+        }
+    }
+
+    /// How many times to repeat the action, at most `MAX_REPEAT_COUNT` as the
+    /// repeats run on the UI thread and can't be interrupted
+    pub fn repeat_count(self) -> usize {
+        self.count().min(MAX_REPEAT_COUNT)
+    }
+
+    /// Whether the action was given a bare `C-u`, which switches some actions
+    /// to an alternate behaviour
+    pub fn is_universal(self) -> bool {
+        matches!(self, Self::Universal(_))
+    }
+}
+
 pub trait Component {
     type Action;
-    type Bindings: Bindings<Self::Action>;
 
     fn draw(
         &mut self,
@@ -89,10 +133,6 @@ pub trait Component {
         Ok(())
     }
 
-    fn bindings(&self) -> Option<&Self::Bindings> {
-        None
-    }
-
     fn path(&self) -> Option<&Path> {
         None
     }
@@ -158,6 +198,16 @@ impl Layout {
         }
     }
 
+    pub fn for_each_component_id(&self, f: &mut impl FnMut(ComponentId)) {
+        match *self {
+            Self::Node(ref node) => node
+                .children
+                .iter()
+                .for_each(|child| child.node.for_each_component_id(f)),
+            Self::Component(id) => f(id),
+        }
+    }
+
     pub fn compute(
         &self,
         frame: Rect,
@@ -205,7 +255,7 @@ impl LayoutNodeFlex {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
 pub enum LayoutDirection {
     Horizontal,
     Vertical,
@@ -363,12 +413,35 @@ impl<Action> BindingMatch<Action> {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct HashBindings<Action>(HashMap<SmallVec<[Key; 2]>, Action>);
+pub struct HashBindings<Action>(HashMap<KeySequence, Action>);
 
 impl<Action> HashBindings<Action> {
-    pub fn new(map: HashMap<SmallVec<[Key; 2]>, Action>) -> Self {
+    pub fn new(map: HashMap<KeySequence, Action>) -> Self {
         Self(map)
     }
+
+    /// Binds the keys to an action, replacing any previous binding
+    pub fn bind(&mut self, keys: KeySequence, action: Action) {
+        self.0.insert(keys, action);
+    }
+
+    pub fn unbind(&mut self, keys: &[Key]) -> Option<Action> {
+        self.0.remove(keys)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&KeySequence, &Action)> {
+        self.0.iter()
+    }
+
+    /// The bindings which start with `prefix` and need more keys pressed
+    pub fn completions<'a>(
+        &'a self,
+        prefix: &'a [Key],
+    ) -> impl Iterator<Item = (&'a KeySequence, &'a Action)> + 'a {
+        self.0
+            .iter()
+            .filter(move |(keys, _)| keys.len() > prefix.len() && keys.starts_with(prefix))
+    }
 }
 
 impl<Action: Clone> Bindings<Action> for HashBindings<Action> {
@@ -408,6 +481,22 @@ mod test {
         Fatality,
     }
 
+    #[test]
+    fn test_prefix_arg_count() {
+        assert_eq!(PrefixArg::None.count(), 1);
+        assert_eq!(PrefixArg::Universal(1).count(), 4);
+        assert_eq!(PrefixArg::Universal(2).count(), 16);
+        assert_eq!(PrefixArg::Number(0).count(), 0);
+        assert_eq!(PrefixArg::Number(8).count(), 8);
+        assert_eq!(PrefixArg::Universal(12).repeat_count(), MAX_REPEAT_COUNT);
+        assert_eq!(
+            PrefixArg::Number(usize::MAX).repeat_count(),
+            MAX_REPEAT_COUNT
+        );
+        assert!(PrefixArg::Universal(1).is_universal());
+        assert!(!PrefixArg::Number(4).is_universal());
+    }
+
     #[test]
     fn test_empty_binding_matches() {
         let bindings: HashBindings<TestAction> = HashBindings(HashMap::new());
@@ -469,4 +558,21 @@ mod test {
             BindingMatch::None
         );
     }
+
+    #[test]
+    fn test_binding_completions() {
+        let bindings = HashBindings(hashmap! {
+            smallvec![Key::Ctrl('a')] => TestAction::A,
+            smallvec![Key::Ctrl('x'), Key::Ctrl('a')] => TestAction::B,
+            smallvec![Key::Ctrl('x'), Key::Char('4'), Key::Ctrl('a')] => TestAction::C,
+        });
+        let mut completions: Vec<_> = bindings
+            .completions(&[Key::Ctrl('x')])
+            .map(|(_, action)| action.clone())
+            .collect();
+        completions.sort_by_key(|action| format!("{:?}", action));
+        assert_eq!(completions, vec![TestAction::B, TestAction::C]);
+        assert_eq!(bindings.completions(&[Key::Ctrl('a')]).count(), 0);
+        assert_eq!(bindings.completions(&[]).count(), 3);
+    }
 }
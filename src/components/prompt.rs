@@ -4,19 +4,24 @@ use ignore::WalkBuilder;
 use maplit::hashmap;
 use once_cell::sync::Lazy;
 use ropey::Rope;
-use smallvec::smallvec;
+use smallvec::{smallvec, SmallVec};
 use std::{
     borrow::Cow,
-    cmp, fs, iter, mem,
+    cmp,
+    collections::VecDeque,
+    fs, iter, mem,
     path::{Path, PathBuf},
 };
 
 use super::{
     cursor::{CharIndex, Cursor},
-    BindingMatch, Bindings, Component, Context, HashBindings, Position, Rect, Size,
+    BindingMatch, Bindings, Component, ComponentId, Context, HashBindings, Position, PrefixArg,
+    Rect, Size,
 };
 use crate::{
     error::{Error, Result},
+    indentation::DEFAULT_TAB_WIDTH,
+    keymap::{ActionInfo, CommandEntry, KeyHint, NamedAction, NamedCommand},
     task::{Scheduler, TaskId},
     terminal::{Background, Foreground, Key, Screen, Style},
     utils::{self, RopeGraphemes},
@@ -35,6 +40,74 @@ pub struct Theme {
 
 pub enum Command {
     OpenFile(PathBuf),
+    FileOperation(FileOperation, PathBuf),
+    Answer(Question, Answer),
+    Run(NamedCommand),
+    Input(Input, String),
+}
+
+/// Text read with `Prompt::read_input`, the editor acts on it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    MacroName,
+    MacroRepeatCount,
+    GotoLine,
+}
+
+/// Operations on the file of the focused buffer which need a new path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileOperation {
+    SaveAs,
+    Rename,
+    Duplicate,
+}
+
+/// A question asked with `Prompt::ask`. The editor acts on the answer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Question {
+    RecoverFile(ComponentId),
+    /// The prefix argument the save was started with, it's kept for the save
+    OverwriteFile(ComponentId, PrefixArg),
+    ReplaceFile(ComponentId, FileOperation, PathBuf),
+    DeleteFile(ComponentId),
+    ClosePane(ComponentId),
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Answer {
+    Yes,
+    No,
+    Diff,
+    Save,
+    SaveAll,
+}
+
+impl Answer {
+    fn key(self) -> char {
+        match self {
+            Self::Yes => 'y',
+            Self::No => 'n',
+            Self::Diff => 'd',
+            Self::Save | Self::SaveAll => 's',
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Yes => "yes",
+            Self::No => "no",
+            Self::Diff => "diff",
+            Self::Save => "save",
+            Self::SaveAll => "save all",
+        }
+    }
+}
+
+struct Confirmation {
+    question: Question,
+    message: String,
+    answers: SmallVec<[Answer; 4]>,
 }
 
 pub struct AsyncAction {
@@ -47,20 +120,32 @@ enum State {
     Inactive,
     PickingFileFromRepo,
     PickingFileFromDirectory,
+    PickingPath(FileOperation),
+    PickingCommand,
+    ReadingInput(Input),
+    Confirming,
 }
 
 impl State {
     fn is_active(&self) -> bool {
         *self != Self::Inactive
     }
+
+    fn is_picking_file(&self) -> bool {
+        matches!(
+            self,
+            Self::PickingFileFromRepo | Self::PickingFileFromDirectory | Self::PickingPath(_)
+        )
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SyncAction {
     // File pickers
     Clear,
     PickFileFromRepo,
     PickFileFromDirectory,
+    PickPath(FileOperation),
     OpenFile,
 
     // Cursor movement
@@ -83,6 +168,97 @@ pub enum SyncAction {
     SelectLast,
 }
 
+impl NamedAction for SyncAction {
+    const ACTIONS: &'static [ActionInfo<Self>] = &[
+        ActionInfo::new("clear", "Cancel picking a file", Self::Clear),
+        ActionInfo::new(
+            "pick-file-from-repo",
+            "Search recursively for a file to open",
+            Self::PickFileFromRepo,
+        ),
+        ActionInfo::new(
+            "pick-file-from-directory",
+            "Open a file from a directory",
+            Self::PickFileFromDirectory,
+        ),
+        ActionInfo::new(
+            "save-as",
+            "Save the buffer under a new name",
+            Self::PickPath(FileOperation::SaveAs),
+        ),
+        ActionInfo::new(
+            "rename-file",
+            "Rename the file of the buffer",
+            Self::PickPath(FileOperation::Rename),
+        ),
+        ActionInfo::new(
+            "duplicate-file",
+            "Copy the buffer to a new file and open it",
+            Self::PickPath(FileOperation::Duplicate),
+        ),
+        ActionInfo::new(
+            "open-file",
+            "Open the file or run the command which was typed or selected",
+            Self::OpenFile,
+        ),
+        ActionInfo::new(
+            "cursor-left",
+            "Move the cursor back a character",
+            Self::CursorLeft,
+        ),
+        ActionInfo::new(
+            "cursor-right",
+            "Move the cursor forward a character",
+            Self::CursorRight,
+        ),
+        ActionInfo::new(
+            "cursor-start-of-line",
+            "Move to the start of the input",
+            Self::CursorStartOfLine,
+        ),
+        ActionInfo::new(
+            "cursor-end-of-line",
+            "Move to the end of the input",
+            Self::CursorEndOfLine,
+        ),
+        ActionInfo::new(
+            "select-parent-directory",
+            "Go to the parent directory",
+            Self::SelectParentDirectory,
+        ),
+        ActionInfo::new(
+            "autocomplete-path",
+            "Fill in the selected path",
+            Self::AutocompletePath,
+        ),
+        ActionInfo::new(
+            "delete-forward",
+            "Delete the character after the cursor",
+            Self::DeleteForward,
+        ),
+        ActionInfo::new(
+            "delete-backward",
+            "Delete the character before the cursor",
+            Self::DeleteBackward,
+        ),
+        ActionInfo::new("select-up", "Select the entry above", Self::SelectUp),
+        ActionInfo::new("select-down", "Select the entry below", Self::SelectDown),
+        ActionInfo::new("select-first", "Select the first entry", Self::SelectFirst),
+        ActionInfo::new("select-last", "Select the last entry", Self::SelectLast),
+    ];
+}
+
+impl SyncAction {
+    /// Whether the action starts picking something, the rest only apply
+    /// while the prompt is active
+    pub fn opens_prompt(&self) -> bool {
+        matches!(
+            self,
+            Self::PickFileFromRepo | Self::PickFileFromDirectory | Self::PickPath(_)
+        )
+    }
+}
+
 pub enum Action {
     Sync(SyncAction),
     Async(Result<AsyncAction>),
@@ -94,6 +270,9 @@ static HASH_BINDINGS: Lazy<HashBindings<SyncAction>> = Lazy::new(|| {
         smallvec![Key::Ctrl('g')] => SyncAction::Clear,
         smallvec![Key::Ctrl('x'), Key::Ctrl('f')] => SyncAction::PickFileFromDirectory,
         smallvec![Key::Ctrl('x'), Key::Ctrl('v')] => SyncAction::PickFileFromRepo,
+        smallvec![Key::Ctrl('x'), Key::Ctrl('w')] => SyncAction::PickPath(FileOperation::SaveAs),
+        smallvec![Key::Ctrl('x'), Key::Ctrl('r')] => SyncAction::PickPath(FileOperation::Rename),
+        smallvec![Key::Ctrl('x'), Key::Ctrl('y')] => SyncAction::PickPath(FileOperation::Duplicate),
         smallvec![Key::Char('\n')] => SyncAction::OpenFile,
 
         // Cursor movement
@@ -123,7 +302,22 @@ static HASH_BINDINGS: Lazy<HashBindings<SyncAction>> = Lazy::new(|| {
     })
 });
 
-pub struct PromptBindings;
+pub fn default_bindings() -> HashBindings<SyncAction> {
+    HASH_BINDINGS.clone()
+}
+
+/// The bindings of the prompt, any other character is inserted
+pub struct PromptBindings(HashBindings<SyncAction>);
+
+impl PromptBindings {
+    pub fn new(bindings: HashBindings<SyncAction>) -> Self {
+        Self(bindings)
+    }
+
+    pub fn hash_bindings(&self) -> &HashBindings<SyncAction> {
+        &self.0
+    }
+}
 
 impl Bindings<Action> for PromptBindings {
     fn matches(&self, pressed: &[Key]) -> BindingMatch<Action> {
@@ -131,7 +325,7 @@ impl Bindings<Action> for PromptBindings {
             [Key::Char(character)] if *character != '\n' && *character != '\t' => {
                 BindingMatch::Full(Action::Sync(SyncAction::InsertChar(*character)))
             }
-            pressed => HASH_BINDINGS.matches(pressed).map_action(Action::Sync),
+            pressed => self.0.matches(pressed).map_action(Action::Sync),
         }
     }
 }
@@ -141,9 +335,12 @@ pub struct Prompt {
     cursor: Cursor,
     command: Option<Command>,
     state: State,
+    confirmations: VecDeque<Confirmation>,
     file_picker: FilePicker,
     file_picker_task: Option<TaskId>,
-    bindings: PromptBindings,
+    command_picker: CommandPicker,
+    key_hints: Vec<KeyHint>,
+    key_hint_columns: usize,
 }
 
 impl Prompt {
@@ -153,9 +350,12 @@ impl Prompt {
             cursor: Cursor::new(),
             command: None,
             state: State::Inactive,
+            confirmations: VecDeque::new(),
             file_picker: FilePicker::new(),
             file_picker_task: None,
-            bindings: PromptBindings,
+            command_picker: CommandPicker::default(),
+            key_hints: Vec::new(),
+            key_hint_columns: 1,
         }
     }
 
@@ -169,6 +369,73 @@ impl Prompt {
         command
     }
 
+    /// Asks the user a question, answered with a single key press. Questions
+    /// are queued if another one is waiting for an answer. Cancelling with
+    /// `C-g` answers `Answer::No`.
+    pub fn ask(&mut self, question: Question, message: String, answers: &[Answer]) {
+        if self.state != State::Confirming {
+            self.input.remove(..);
+            self.cursor = Cursor::new();
+            self.file_picker.clear();
+            self.file_picker_task = None;
+            self.state = State::Confirming;
+        }
+        self.confirmations.push_back(Confirmation {
+            question,
+            message,
+            answers: answers.iter().copied().collect(),
+        });
+    }
+
+    /// Starts the command palette, which runs the picked command
+    pub fn pick_command(&mut self, commands: Vec<CommandEntry>) {
+        if !self.is_active() {
+            self.input.remove(..);
+            self.cursor = Cursor::new();
+            self.command_picker.reset(commands);
+            self.state = State::PickingCommand;
+        }
+    }
+
+    /// Reads a line of text, which is sent back with `Command::Input`
+    pub fn read_input(&mut self, input: Input) {
+        if !self.is_active() {
+            self.input.remove(..);
+            self.cursor = Cursor::new();
+            self.state = State::ReadingInput(input);
+        }
+    }
+
+    /// Shows the bindings which complete the keys pressed so far, in as many
+    /// columns as fit in `width`
+    pub fn show_key_hints(&mut self, key_hints: Vec<KeyHint>, width: usize) {
+        let column_width = key_hints
+            .iter()
+            .map(|hint| hint.keys.len() + hint.name.len() + KEY_HINT_SPACING * 2)
+            .max()
+            .unwrap_or(1);
+        self.key_hint_columns = cmp::max(1, width / column_width);
+        self.key_hints = key_hints;
+    }
+
+    pub fn clear_key_hints(&mut self) {
+        self.key_hints.clear();
+    }
+
+    fn key_hint_rows(&self) -> usize {
+        let rows = self.key_hints.len().div_ceil(self.key_hint_columns);
+        cmp::min(rows, PROMPT_SELECT_HEIGHT)
+    }
+
+    fn answer(&mut self, answer: Answer) {
+        if let Some(Confirmation { question, .. }) = self.confirmations.pop_front() {
+            self.command = Some(Command::Answer(question, answer));
+        }
+        if self.confirmations.is_empty() {
+            self.state = State::Inactive;
+        }
+    }
+
     pub fn log_error(&mut self, message: String) {
         if !self.is_active() {
             self.input = Rope::from(message);
@@ -182,11 +449,13 @@ impl Prompt {
     }
 
     pub fn height(&self) -> usize {
-        if self.is_active() {
-            PROMPT_INPUT_HEIGHT + cmp::min(self.file_picker.filtered.len(), PROMPT_SELECT_HEIGHT)
-        } else {
-            PROMPT_INPUT_HEIGHT
-        }
+        let num_entries = match self.state {
+            State::Inactive => return PROMPT_INPUT_HEIGHT + self.key_hint_rows(),
+            State::Confirming | State::ReadingInput(_) => return PROMPT_INPUT_HEIGHT,
+            State::PickingCommand => self.command_picker.filtered.len(),
+            _ => self.file_picker.filtered.len(),
+        };
+        PROMPT_INPUT_HEIGHT + cmp::min(num_entries, PROMPT_SELECT_HEIGHT)
     }
 
     fn reduce_sync(
@@ -195,6 +464,27 @@ impl Prompt {
         scheduler: &mut Scheduler<<Self as Component>::Action>,
         context: &Context,
     ) -> Result<()> {
+        if self.state == State::Confirming {
+            let answer = match action {
+                SyncAction::Clear => Some(Answer::No),
+                SyncAction::InsertChar(character) => self
+                    .confirmations
+                    .front()
+                    .and_then(|confirmation| {
+                        confirmation
+                            .answers
+                            .iter()
+                            .find(|answer| answer.key() == character.to_ascii_lowercase())
+                    })
+                    .copied(),
+                _ => None,
+            };
+            if let Some(answer) = answer {
+                self.answer(answer);
+            }
+            return Ok(());
+        }
+
         match action {
             SyncAction::Clear => {
                 self.state = State::Inactive;
@@ -202,6 +492,17 @@ impl Prompt {
                 self.input.remove(..);
                 self.file_picker.clear();
                 self.file_picker_task = None;
+                self.command_picker.reset(Vec::new());
+                return Ok(());
+            }
+            SyncAction::OpenFile if self.state == State::PickingCommand => {
+                if let Some(entry) = self.command_picker.selected() {
+                    self.command = Some(Command::Run(entry.command.clone()));
+                    self.input.remove(..);
+                    self.cursor = Cursor::new();
+                    self.command_picker.reset(Vec::new());
+                    self.state = State::Inactive;
+                }
                 return Ok(());
             }
             SyncAction::PickFileFromDirectory if !self.is_active() => {
@@ -216,9 +517,20 @@ impl Prompt {
                 self.pick_from_repository(scheduler)?;
                 return Ok(());
             }
+            SyncAction::PickPath(operation) if !self.is_active() => {
+                self.state = State::PickingPath(operation);
+                self.set_input_to_path(context);
+                self.pick_from_directory(scheduler)?;
+                return Ok(());
+            }
             SyncAction::OpenFile if self.is_active() => {
-                let path_str: Cow<str> = self.input.slice(..).into();
-                self.command = Some(Command::OpenFile(PathBuf::from(path_str.trim())));
+                let input_str: Cow<str> = self.input.slice(..).into();
+                let path = PathBuf::from(input_str.trim());
+                self.command = Some(match self.state {
+                    State::PickingPath(operation) => Command::FileOperation(operation, path),
+                    State::ReadingInput(input) => Command::Input(input, input_str.trim().into()),
+                    _ => Command::OpenFile(path),
+                });
                 self.input.remove(..);
                 self.cursor = Cursor::new();
                 self.state = State::Inactive;
@@ -244,7 +556,7 @@ impl Prompt {
                     self.cursor.move_to_end_of_line(&self.input);
                     false
                 }
-                SyncAction::SelectParentDirectory => {
+                SyncAction::SelectParentDirectory if self.state.is_picking_file() => {
                     let path_str: String = self.input.slice(..).into();
                     self.input = Path::new(&path_str.trim())
                         .parent()
@@ -257,7 +569,18 @@ impl Prompt {
                     self.cursor.move_right(&self.input);
                     true
                 }
-                SyncAction::AutocompletePath => {
+                SyncAction::AutocompletePath if self.state == State::PickingCommand => {
+                    match self.command_picker.selected() {
+                        Some(entry) => {
+                            self.input = entry.name.as_str().into();
+                            utils::ensure_trailing_newline_with_content(&mut self.input);
+                            self.cursor.move_to_end_of_line(&self.input);
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                SyncAction::AutocompletePath if self.state.is_picking_file() => {
                     if let Some(path) = self.file_picker.selected() {
                         self.input = path.to_string_lossy().into();
                         utils::ensure_trailing_newline_with_content(&mut self.input);
@@ -272,19 +595,35 @@ impl Prompt {
                     }
                 }
                 SyncAction::SelectDown => {
-                    self.file_picker.move_down();
+                    if self.state == State::PickingCommand {
+                        self.command_picker.move_down();
+                    } else {
+                        self.file_picker.move_down();
+                    }
                     false
                 }
                 SyncAction::SelectUp => {
-                    self.file_picker.move_up();
+                    if self.state == State::PickingCommand {
+                        self.command_picker.move_up();
+                    } else {
+                        self.file_picker.move_up();
+                    }
                     false
                 }
                 SyncAction::SelectFirst => {
-                    self.file_picker.move_to_top();
+                    if self.state == State::PickingCommand {
+                        self.command_picker.move_to_top();
+                    } else {
+                        self.file_picker.move_to_top();
+                    }
                     false
                 }
                 SyncAction::SelectLast => {
-                    self.file_picker.move_to_bottom();
+                    if self.state == State::PickingCommand {
+                        self.command_picker.move_to_bottom();
+                    } else {
+                        self.file_picker.move_to_bottom();
+                    }
                     false
                 }
                 SyncAction::DeleteBackward => !self.cursor.backspace(&mut self.input).is_empty(),
@@ -299,9 +638,15 @@ impl Prompt {
 
             if input_changed {
                 match self.state {
-                    State::PickingFileFromDirectory => self.pick_from_directory(scheduler)?,
+                    State::PickingFileFromDirectory | State::PickingPath(_) => {
+                        self.pick_from_directory(scheduler)?
+                    }
                     State::PickingFileFromRepo => self.pick_from_repository(scheduler)?,
-                    State::Inactive => {}
+                    State::PickingCommand => {
+                        let filter: Cow<str> = self.input.slice(..).into();
+                        self.command_picker.set_filter(&filter);
+                    }
+                    State::Inactive | State::Confirming | State::ReadingInput(_) => {}
                 }
             }
         }
@@ -365,6 +710,16 @@ impl Prompt {
         Ok(())
     }
 
+    /// Starts from the path of the current file, so it's easy to pick a
+    /// similar name
+    #[inline]
+    fn set_input_to_path(&mut self, context: &Context) {
+        self.cursor.delete_line(&mut self.input);
+        self.cursor
+            .insert_chars(&mut self.input, context.path.to_str().unwrap_or("").chars());
+        self.cursor.move_to_end_of_line(&self.input);
+    }
+
     #[inline]
     fn set_input_to_cwd(&mut self, context: &Context) {
         self.cursor.delete_line(&mut self.input);
@@ -382,6 +737,65 @@ impl Prompt {
         self.cursor.insert_char(&mut self.input, '/');
         self.cursor.move_right(&self.input);
     }
+
+    /// Draws the key hints above the input, filling one column after the
+    /// other like the list of a file picker
+    fn draw_key_hints(&self, screen: &mut Screen, context: &Context) {
+        let theme = &context.theme.prompt;
+        let rows = self.key_hint_rows();
+        let frame = context.frame;
+        screen.clear_region(
+            Rect::new(frame.origin, Size::new(frame.size.width, rows)),
+            Style::normal(theme.item_unfocused_background, theme.item_file_foreground),
+        );
+
+        let column_width = frame.size.width / self.key_hint_columns;
+        for (index, hint) in self
+            .key_hints
+            .iter()
+            .take(rows * self.key_hint_columns)
+            .enumerate()
+        {
+            let screen_x = frame.origin.x + (index / rows) * column_width;
+            let screen_y = frame.origin.y + index % rows;
+            let keys_width = screen.draw_str(
+                screen_x,
+                screen_y,
+                Style::bold(
+                    theme.item_unfocused_background,
+                    theme.item_directory_foreground,
+                ),
+                &hint.keys,
+            );
+            screen.draw_str(
+                screen_x + keys_width + KEY_HINT_SPACING,
+                screen_y,
+                Style::normal(theme.item_unfocused_background, theme.item_file_foreground),
+                hint.name,
+            );
+        }
+    }
+
+    fn draw_confirmation(
+        &self,
+        screen: &mut Screen,
+        context: &Context,
+        confirmation: &Confirmation,
+    ) {
+        let theme = &context.theme.prompt;
+        let screen_y = context.frame.origin.y + self.height() - 1;
+        let mut screen_x = context.frame.origin.x;
+        screen_x += screen.draw_str(screen_x, screen_y, theme.input, &confirmation.message);
+        for (index, answer) in confirmation.answers.iter().enumerate() {
+            let separator = if index == 0 { " (" } else { ", " };
+            screen_x += screen.draw_str(screen_x, screen_y, theme.input, separator);
+            screen_x +=
+                screen.draw_str(screen_x, screen_y, theme.action, &answer.key().to_string());
+            screen_x += screen.draw_str(screen_x, screen_y, theme.input, &answer.label()[1..]);
+        }
+        screen_x += screen.draw_str(screen_x, screen_y, theme.input, ") ");
+        screen.draw_str(screen_x, screen_y, theme.cursor, " ");
+    }
 }
 
 fn repository_files_iter(path: impl AsRef<Path>) -> impl Iterator<Item = Result<PathBuf>> {
@@ -418,22 +832,23 @@ fn directory_files_iter(path: impl AsRef<Path>) -> Result<impl Iterator<Item = R
 
 impl Component for Prompt {
     type Action = Action;
-    type Bindings = PromptBindings;
 
     #[inline]
     fn draw(&mut self, screen: &mut Screen, _: &mut Scheduler<Self::Action>, context: &Context) {
         let theme = &context.theme.prompt;
 
         if self.is_active() {
-            self.file_picker.draw(
-                screen,
-                &context.set_frame(context.frame.inner_rect(SideOffsets2D::new(
-                    0,
-                    0,
-                    PROMPT_INPUT_HEIGHT,
-                    0,
-                ))),
-            );
+            let picker_context = context.set_frame(context.frame.inner_rect(SideOffsets2D::new(
+                0,
+                0,
+                PROMPT_INPUT_HEIGHT,
+                0,
+            )));
+            match self.state {
+                State::PickingCommand => self.command_picker.draw(screen, &picker_context),
+                State::ReadingInput(_) => {}
+                _ => self.file_picker.draw(screen, &picker_context),
+            }
         }
 
         assert!(self.height() >= PROMPT_INPUT_HEIGHT);
@@ -451,13 +866,32 @@ impl Component for Prompt {
             theme.input,
         );
 
+        if let (State::Confirming, Some(confirmation)) = (&self.state, self.confirmations.front()) {
+            self.draw_confirmation(screen, context, confirmation);
+            return;
+        }
+
+        if self.state == State::Inactive && !self.key_hints.is_empty() {
+            self.draw_key_hints(screen, context);
+        }
+
         // Draw prompt
         let prefix = match (&self.state, self.file_picker_task.is_some()) {
             (State::PickingFileFromRepo, true) => "repo*",
             (State::PickingFileFromRepo, false) => "repo ",
             (State::PickingFileFromDirectory, true) => "open*",
             (State::PickingFileFromDirectory, false) => "open ",
-            (State::Inactive, _) => "",
+            (State::PickingPath(FileOperation::SaveAs), true) => "save as*",
+            (State::PickingPath(FileOperation::SaveAs), false) => "save as ",
+            (State::PickingPath(FileOperation::Rename), true) => "rename*",
+            (State::PickingPath(FileOperation::Rename), false) => "rename ",
+            (State::PickingPath(FileOperation::Duplicate), true) => "copy to*",
+            (State::PickingPath(FileOperation::Duplicate), false) => "copy to ",
+            (State::PickingCommand, _) => "run ",
+            (State::ReadingInput(Input::MacroName), _) => "macro name ",
+            (State::ReadingInput(Input::MacroRepeatCount), _) => "repeat ",
+            (State::ReadingInput(Input::GotoLine), _) => "goto line ",
+            (State::Inactive, _) | (State::Confirming, _) => "",
         };
         let prefix_offset = if prefix.is_empty() {
             0
@@ -481,7 +915,7 @@ impl Component for Prompt {
             } else {
                 theme.input
             };
-            let grapheme_width = utils::grapheme_width(&grapheme);
+            let grapheme_width = utils::grapheme_width(&grapheme, DEFAULT_TAB_WIDTH);
 
             if grapheme_width == 0 {
                 screen.draw_str(screen_x, screen_y, style, " ");
@@ -506,10 +940,6 @@ impl Component for Prompt {
             Self::Action::Async(action) => self.reduce_async(action),
         }
     }
-
-    fn bindings(&self) -> Option<&Self::Bindings> {
-        Some(&self.bindings)
-    }
 }
 
 struct FilePicker {
@@ -670,6 +1100,143 @@ impl FilePicker {
     }
 }
 
+/// Fuzzy matches the commands of the command palette by name and
+/// description
+#[derive(Default)]
+struct CommandPicker {
+    offset: usize,
+    selected: usize,
+    commands: Vec<CommandEntry>,
+    filtered: Vec<(usize, i64)>, // (index, score)
+    matcher: SkimMatcherV2,
+}
+
+impl CommandPicker {
+    fn reset(&mut self, commands: Vec<CommandEntry>) {
+        self.commands = commands;
+        self.set_filter("");
+    }
+
+    fn set_filter(&mut self, filter: &str) {
+        let Self {
+            ref mut offset,
+            ref mut selected,
+            ref commands,
+            ref mut filtered,
+            ref matcher,
+        } = *self;
+        *offset = 0;
+        *selected = 0;
+        filtered.clear();
+        filtered.extend(commands.iter().enumerate().filter_map(|(index, command)| {
+            matcher
+                .fuzzy_match(
+                    &format!("{} {}", command.name, command.description),
+                    filter.trim(),
+                )
+                .map(|score| (index, score))
+        }));
+        // Stable, to keep the order of the commands with equal scores
+        filtered.sort_by_key(|(_, score)| -score);
+    }
+
+    fn move_up(&mut self) {
+        self.selected = cmp::min(self.selected + 1, self.filtered.len().saturating_sub(1));
+    }
+
+    fn move_down(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    fn move_to_top(&mut self) {
+        self.selected = self.filtered.len().saturating_sub(1);
+    }
+
+    fn move_to_bottom(&mut self) {
+        self.selected = 0;
+    }
+
+    fn selected(&self) -> Option<&CommandEntry> {
+        self.filtered
+            .get(self.selected)
+            .map(|(index, _)| &self.commands[*index])
+    }
+
+    fn draw(&mut self, screen: &mut Screen, context: &Context) {
+        let theme = &context.theme.prompt;
+        let height = context.frame.size.height;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected - self.offset > height.saturating_sub(1) {
+            self.offset = self.selected - height + 1;
+        }
+
+        screen.clear_region(
+            context.frame,
+            Style::normal(theme.item_unfocused_background, theme.item_file_foreground),
+        );
+
+        let name_width = self
+            .commands
+            .iter()
+            .map(|command| command.name.len())
+            .max()
+            .unwrap_or(0);
+        for (option_index, command) in self
+            .filtered
+            .iter()
+            .skip(self.offset)
+            .take(height)
+            .map(|(index, _)| &self.commands[*index])
+            .enumerate()
+        {
+            let frame_y = context.frame.origin.y + height - option_index - 1;
+            let background = if self.offset + option_index == self.selected {
+                screen.clear_region(
+                    Rect::new(
+                        Position::new(context.frame.origin.x, frame_y),
+                        Size::new(context.frame.size.width, 1),
+                    ),
+                    Style::normal(theme.item_focused_background, theme.item_file_foreground),
+                );
+                theme.item_focused_background
+            } else {
+                theme.item_unfocused_background
+            };
+
+            // The bindings are aligned to the right, the description is cut
+            // off to make room for them
+            let keys_x = (context.frame.origin.x + context.frame.size.width)
+                .saturating_sub(command.keys.len() + 1);
+            screen.draw_str(
+                keys_x,
+                frame_y,
+                Style::normal(background, theme.item_directory_foreground),
+                &command.keys,
+            );
+            let screen_x = context.frame.origin.x;
+            screen.draw_str(
+                screen_x,
+                frame_y,
+                Style::bold(background, theme.item_file_foreground),
+                &command.name,
+            );
+            let description_x = screen_x + name_width + 2;
+            let description: String = command
+                .description
+                .chars()
+                .take(keys_x.saturating_sub(description_x + 1))
+                .collect();
+            screen.draw_str(
+                description_x,
+                frame_y,
+                Style::normal(background, theme.item_file_foreground),
+                &description,
+            );
+        }
+    }
+}
+
 fn update_file_picker<FilesIterT>(
     file_picker: &mut FilePicker,
     path_str: String,
@@ -705,4 +1272,5 @@ fn pick_from_repository(file_picker: &mut FilePicker, path_str: String) -> Resul
 
 const PROMPT_INPUT_HEIGHT: usize = 1;
 const PROMPT_SELECT_HEIGHT: usize = 15;
+const KEY_HINT_SPACING: usize = 2;
 const MAX_FILES_IN_PICKER: usize = 65536;
@@ -1,4 +1,4 @@
-use super::{Component, Context, HashBindings, Scheduler};
+use super::{Component, Context, Scheduler};
 use crate::terminal::{Screen, Style};
 use once_cell::sync::Lazy;
 use pkg_version::{pkg_version_major, pkg_version_minor, pkg_version_patch};
@@ -16,7 +16,6 @@ pub struct Splash;
 
 impl Component for Splash {
     type Action = ();
-    type Bindings = HashBindings<()>;
 
     #[inline]
     fn draw(&mut self, screen: &mut Screen, _: &mut Scheduler<Self::Action>, context: &Context) {
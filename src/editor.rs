@@ -1,30 +1,40 @@
-use crossbeam_channel::select;
+use crossbeam_channel::{self, select, Receiver, Sender};
 use maplit::hashmap;
 use once_cell::sync::Lazy;
+use serde_json::{json, Value};
 use smallvec::{smallvec, SmallVec};
 use std::{
     cmp,
     collections::HashMap,
-    io, mem,
-    ops::Deref,
+    fs, io, mem,
     path::{Path, PathBuf},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use ttmap::TypeMap;
 
 use crate::{
     components::{
-        prompt::Command,
+        buffer::{self, SCRATCH_BUFFER_NAME},
+        cursor::CharIndex,
+        prompt::{self, Answer, Command, FileOperation, Input, Question},
         theme::{Theme, THEMES},
         BindingMatch, Bindings, Buffer, Component, ComponentId, Context, Flex, HashBindings,
         LaidComponentId, LaidComponentIds, Layout, LayoutDirection, LayoutNode, LayoutNodeFlex,
-        Prompt, Splash,
+        PrefixArg, Prompt, Splash, MAX_REPEAT_COUNT,
     },
     error::{Error, Result},
+    files,
     frontend::Frontend,
-    settings::Settings,
+    keymap::{self, ActionInfo, CommandEntry, Keymap, NamedAction, NamedCommand},
+    places, recovery,
+    rpc::{self, Method},
+    scripting::{BufferSnapshot, Hook, ScriptAction, Scripts},
+    server::{OpenRequest, Server},
+    session::{self, BufferSession, Session, SessionLayout},
+    settings::{self, KeymapStyle, Settings},
     task::{TaskId, TaskPool},
     terminal::{Key, Position, Rect, Screen},
+    vi::{self, Vi},
 };
 
 type Components<T> = HashMap<ComponentId, T>;
@@ -43,38 +53,198 @@ pub struct Editor {
     next_component_id: ComponentId,
     task_pool: TaskPool,
     current_path: PathBuf,
+    working_dir: PathBuf,
     controller: InputController,
+    keymap: Keymap,
+    // The modal key bindings, if used instead of the Emacs ones
+    vi: Option<Vi>,
+    // When the keys pressed so far became a prefix of a binding, to show the
+    // bindings which complete it after a delay
+    pending_prefix_since: Option<Instant>,
+    // Whether the next keys are described instead of handled
+    describing_key: bool,
+    // The keyboard macro being recorded, the last one recorded and whether
+    // keys are being played back through `handle_event`
+    recording_macro: Option<MacroRecording>,
+    last_macro: Option<Vec<Key>>,
+    playing_macro: bool,
+    last_autosave: Instant,
+    last_file_check: Instant,
+
+    // Buffers to close and whether to exit once their pending saves complete
+    close_when_saved: Vec<ComponentId>,
+    quit_when_saved: bool,
+
+    // Files to open from `zee --client`, and the clients waiting for the
+    // buffers they opened to be closed
+    client_requests: Receiver<OpenRequest>,
+    waiting_clients: Vec<(Vec<ComponentId>, Sender<String>)>,
+
+    // JSON-RPC calls, the connections subscribed to buffer changes and the
+    // edit count of each buffer when they were last told about it
+    rpc_calls: Receiver<rpc::Call>,
+    subscribers: Vec<Sender<String>>,
+    edit_counts: HashMap<ComponentId, usize>,
+
+    // The init script, the commands its scripts asked to run and the mode of
+    // each buffer when `on_mode_change` was last called for it. Hooks don't
+    // run for what scripts ask for, so they can't call themselves.
+    scripts: Scripts,
+    script_commands: Vec<String>,
+    script_modes: HashMap<ComponentId, String>,
+    running_script: bool,
 
     // Theme palettes and currently selected theme
     themes: &'static [(Theme, &'static str); 30],
     theme_index: usize,
     settings: Settings,
+
+    // Where the settings were read from, reloaded when the file changes
+    settings_path: Option<PathBuf>,
+    settings_modified: Option<SystemTime>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum EditorAction {
+    UniversalArgument,
+    ExecuteCommand,
+    DescribeKey,
+    DescribeBindings,
+    StartMacro,
+    StopMacro,
+    PlayMacro,
+    RepeatMacro,
+    NameMacro,
+    GotoLine,
     FocusNextComponent,
     FocusPreviousComponent,
     ClosePane,
+    NewScratchBuffer,
+    DeleteFile,
     ChangeTheme,
     Quit,
 }
 
 static EDITOR_BINDINGS: Lazy<HashBindings<EditorAction>> = Lazy::new(|| {
     HashBindings::new(hashmap! {
+        smallvec![Key::Ctrl('u')] => EditorAction::UniversalArgument,
+        smallvec![Key::Alt('x')] => EditorAction::ExecuteCommand,
+        smallvec![Key::Ctrl('h'), Key::Char('k')] => EditorAction::DescribeKey,
+        smallvec![Key::Ctrl('h'), Key::Char('b')] => EditorAction::DescribeBindings,
+        smallvec![Key::Ctrl('x'), Key::Char('(')] => EditorAction::StartMacro,
+        smallvec![Key::Ctrl('x'), Key::Char(')')] => EditorAction::StopMacro,
+        smallvec![Key::Ctrl('x'), Key::Char('e')] => EditorAction::PlayMacro,
+        smallvec![Key::Ctrl('x'), Key::Ctrl('k'), Key::Char('r')] => EditorAction::RepeatMacro,
+        smallvec![Key::Ctrl('x'), Key::Ctrl('k'), Key::Char('n')] => EditorAction::NameMacro,
+        smallvec![Key::Alt('g'), Key::Char('g')] => EditorAction::GotoLine,
+        smallvec![Key::Alt('g'), Key::Alt('g')] => EditorAction::GotoLine,
         smallvec![Key::Ctrl('x'), Key::Char('o')] => EditorAction::FocusNextComponent,
         smallvec![Key::Ctrl('x'), Key::Ctrl('o')] => EditorAction::FocusNextComponent,
         smallvec![Key::Ctrl('x'), Key::Char('O')] => EditorAction::FocusPreviousComponent,
         smallvec![Key::Ctrl('x'), Key::Ctrl('O')] => EditorAction::FocusPreviousComponent,
         smallvec![Key::Ctrl('x'), Key::Char('0')] => EditorAction::ClosePane,
+        smallvec![Key::Ctrl('x'), Key::Ctrl('n')] => EditorAction::NewScratchBuffer,
+        smallvec![Key::Ctrl('x'), Key::Ctrl('d')] => EditorAction::DeleteFile,
         smallvec![Key::Ctrl('t')] => EditorAction::ChangeTheme,
         smallvec![Key::Ctrl('x'), Key::Ctrl('c')] => EditorAction::Quit,
     })
 });
 
+pub fn default_bindings() -> HashBindings<EditorAction> {
+    EDITOR_BINDINGS.clone()
+}
+
+impl NamedAction for EditorAction {
+    const ACTIONS: &'static [ActionInfo<Self>] = &[
+        ActionInfo::new(
+            "universal-argument",
+            "Repeat the next command 4 times, or as many times as the digits typed next",
+            Self::UniversalArgument,
+        ),
+        ActionInfo::new(
+            "execute-command",
+            "Run a command by name",
+            Self::ExecuteCommand,
+        ),
+        ActionInfo::new(
+            "describe-key",
+            "Show which action the next keys run",
+            Self::DescribeKey,
+        ),
+        ActionInfo::new(
+            "describe-bindings",
+            "List every key binding in a new buffer",
+            Self::DescribeBindings,
+        ),
+        ActionInfo::new(
+            "start-macro",
+            "Start recording a keyboard macro",
+            Self::StartMacro,
+        ),
+        ActionInfo::new(
+            "stop-macro",
+            "Stop recording the keyboard macro",
+            Self::StopMacro,
+        ),
+        ActionInfo::new(
+            "play-macro",
+            "Play the last recorded keyboard macro",
+            Self::PlayMacro,
+        ),
+        ActionInfo::new(
+            "repeat-macro",
+            "Play the last recorded keyboard macro a number of times",
+            Self::RepeatMacro,
+        ),
+        ActionInfo::new(
+            "name-macro",
+            "Save the last recorded keyboard macro to the settings under a name",
+            Self::NameMacro,
+        ),
+        ActionInfo::new(
+            "goto-line",
+            "Move to a line, or a line:column, in the focused buffer",
+            Self::GotoLine,
+        ),
+        ActionInfo::new(
+            "focus-next-component",
+            "Switch focus to the next pane",
+            Self::FocusNextComponent,
+        ),
+        ActionInfo::new(
+            "focus-previous-component",
+            "Switch focus to the previous pane",
+            Self::FocusPreviousComponent,
+        ),
+        ActionInfo::new("close-pane", "Close the focused pane", Self::ClosePane),
+        ActionInfo::new(
+            "new-scratch-buffer",
+            "Open a buffer which isn't backed by a file until saved",
+            Self::NewScratchBuffer,
+        ),
+        ActionInfo::new(
+            "delete-file",
+            "Delete the file of the focused buffer",
+            Self::DeleteFile,
+        ),
+        ActionInfo::new(
+            "change-theme",
+            "Cycle through the available themes",
+            Self::ChangeTheme,
+        ),
+        ActionInfo::new("quit", "Quit zee", Self::Quit),
+    ];
+}
+
 impl Editor {
-    pub fn new(settings: Settings, current_path: PathBuf, task_pool: TaskPool) -> Self {
+    pub fn new(
+        settings: Settings,
+        settings_path: Option<PathBuf>,
+        current_path: PathBuf,
+        task_pool: TaskPool,
+    ) -> Self {
         let prompt = Prompt::new();
+        let settings_modified = settings_path.as_ref().and_then(settings::modified_time);
         Self {
             components: TypeMap::new(),
             layout: wrap_layout_with_prompt(prompt.height(), None),
@@ -84,12 +254,230 @@ impl Editor {
             focus: None,
             next_component_id: cmp::max(PROMPT_ID, SPLASH_ID) + 1,
             task_pool,
+            working_dir: current_path.clone(),
             current_path,
             controller: InputController::new(),
+            // The key bindings were checked when the settings were read
+            keymap: Keymap::new(&settings.keys).unwrap_or_default(),
+            vi: vi_for(&settings),
+            pending_prefix_since: None,
+            describing_key: false,
+            recording_macro: None,
+            last_macro: None,
+            playing_macro: false,
+            last_autosave: Instant::now(),
+            last_file_check: Instant::now(),
+            close_when_saved: Vec::new(),
+            quit_when_saved: false,
+            client_requests: crossbeam_channel::never(),
+            waiting_clients: Vec::new(),
+            rpc_calls: crossbeam_channel::never(),
+            subscribers: Vec::new(),
+            edit_counts: HashMap::new(),
+            scripts: Scripts::default(),
+            script_commands: Vec::new(),
+            script_modes: HashMap::new(),
+            running_script: false,
 
             themes: &THEMES,
             theme_index: settings.theme_index,
             settings,
+            settings_path,
+            settings_modified,
+        }
+    }
+
+    pub fn log_error(&mut self, message: String) {
+        self.prompt.log_error(message);
+    }
+
+    /// Opens the files sent by `zee --client` and answers JSON-RPC calls
+    /// while the editor is running
+    pub fn accept_clients(&mut self, server: &Server) {
+        self.client_requests = server.requests.clone();
+        self.rpc_calls = server.calls.clone();
+    }
+
+    /// Opens the files a client asked for. Errors are sent back to it.
+    fn handle_open_request(&mut self, request: OpenRequest) {
+        let mut opened = Vec::with_capacity(request.files.len());
+        for (path, position) in request.files {
+            let error = match self.open_file_at(&path, position, request.read_only) {
+                Ok(Some(id)) => {
+                    opened.push(id);
+                    continue;
+                }
+                Ok(None) => format!("could not open {}", path.display()),
+                Err(error) => format!("could not open {}: {}", path.display(), error),
+            };
+            let _ = request.errors.send(error);
+        }
+        if request.wait && !opened.is_empty() {
+            self.waiting_clients.push((opened, request.errors));
+        }
+    }
+
+    /// Dropping the sender tells a waiting client its buffers were closed
+    fn release_waiting_clients(&mut self) {
+        let Self {
+            ref mut components,
+            ref mut waiting_clients,
+            ..
+        } = *self;
+        let buffers = components.get_or_default::<Buffers>();
+        waiting_clients.retain(|(ids, _)| ids.iter().any(|id| buffers.contains_key(id)));
+    }
+
+    /// Answers a JSON-RPC call. Returns whether the editor should exit. Errors
+    /// are sent to the client, which can't make the editor exit with one.
+    fn handle_rpc_call(&mut self, call: rpc::Call, frame: Rect) -> bool {
+        let mut exit = false;
+        let result = match call.method {
+            Method::ListBuffers => Ok(self.list_buffers()),
+            Method::OpenFile {
+                path,
+                position,
+                read_only,
+            } => match self.open_file_at(&path, position, read_only) {
+                Ok(Some(id)) => Ok(json!({ "buffer": id })),
+                Ok(None) => Err(format!("could not open {}", path.display())),
+                Err(error) => Err(error.to_string()),
+            },
+            Method::GetCursor { buffer } => self.rpc_buffer(buffer).map(|id| {
+                let position = self.components.get_or_default::<Buffers>()[&id].cursor_location();
+                json!({ "buffer": id, "line": position.line, "column": position.column })
+            }),
+            Method::SetCursor { buffer, position } => self.rpc_buffer(buffer).and_then(|id| {
+                let action = buffer::SyncAction::GotoPosition(position);
+                self.rpc_buffer_action(id, action)
+            }),
+            Method::InsertText { buffer, text } => self.rpc_buffer(buffer).and_then(|id| {
+                let action = buffer::SyncAction::InsertText(text);
+                self.rpc_buffer_action(id, action)
+            }),
+            Method::RunCommand { name } => match self.named_command(&name) {
+                Some(command) => match self.run_command(command, frame) {
+                    Ok(exit_editor) => {
+                        exit = exit_editor;
+                        Ok(Value::Null)
+                    }
+                    Err(error) => Err(error.to_string()),
+                },
+                None => Err(format!("unknown command `{}`", name)),
+            },
+            Method::Subscribe => {
+                self.subscribers.push(call.connection.clone());
+                Ok(Value::Null)
+            }
+        };
+        if let Some(id) = call.id {
+            let _ = call.connection.send(match result {
+                Ok(result) => rpc::response(&id, result),
+                Err(message) => rpc::error_response(&id, rpc::EDITOR_ERROR, &message),
+            });
+        }
+        exit
+    }
+
+    fn list_buffers(&mut self) -> Value {
+        let focus = self.focus;
+        let mut buffers: Vec<_> = self
+            .components
+            .get_or_default::<Buffers>()
+            .iter()
+            .map(|(id, buffer)| {
+                json!({
+                    "buffer": id,
+                    "path": buffer.path(),
+                    "mode": buffer.mode_name(),
+                    "modified": buffer.is_modified(),
+                    "focused": focus == Some(*id),
+                })
+            })
+            .collect();
+        buffers.sort_by_key(|buffer| buffer["buffer"].as_u64());
+        Value::Array(buffers)
+    }
+
+    /// The buffer a call refers to, the focused one unless given
+    fn rpc_buffer(&mut self, id: Option<ComponentId>) -> std::result::Result<ComponentId, String> {
+        let buffers = self.components.get_or_default::<Buffers>();
+        match id {
+            Some(id) if buffers.contains_key(&id) => Ok(id),
+            Some(id) => Err(format!("no buffer {}", id)),
+            None => self
+                .focus
+                .filter(|id| buffers.contains_key(id))
+                .ok_or_else(|| "no buffer is focused".into()),
+        }
+    }
+
+    fn rpc_buffer_action(
+        &mut self,
+        id: ComponentId,
+        action: buffer::SyncAction,
+    ) -> std::result::Result<Value, String> {
+        self.reduce_buffer(id, buffer::Action::Sync(action), PrefixArg::None)
+            .map(|_| Value::Null)
+            .map_err(|error| error.to_string())
+    }
+
+    /// A command from the palette or a named macro
+    fn named_command(&self, name: &str) -> Option<NamedCommand> {
+        match self
+            .keymap
+            .commands()
+            .into_iter()
+            .find(|entry| entry.name == name)
+        {
+            Some(entry) => Some(entry.command),
+            None if self.settings.macros.contains_key(name) => {
+                Some(NamedCommand::Macro(name.into()))
+            }
+            None if self
+                .scripts
+                .commands()
+                .iter()
+                .any(|command| command.name == name) =>
+            {
+                Some(NamedCommand::Script(name.into()))
+            }
+            None => None,
+        }
+    }
+
+    /// Sends `buffer-changed` and `buffer-closed` notifications to the
+    /// connections which subscribed, dropping the ones which went away
+    fn notify_subscribers(&mut self) {
+        let Self {
+            ref mut components,
+            ref mut subscribers,
+            ref mut edit_counts,
+            ..
+        } = *self;
+        let buffers = components.get_or_default::<Buffers>();
+        let mut notifications = Vec::new();
+        for (id, buffer) in buffers.iter() {
+            if edit_counts.insert(*id, buffer.edit_count()) != Some(buffer.edit_count()) {
+                notifications.push(rpc::notification(
+                    "buffer-changed",
+                    json!({ "buffer": id, "path": buffer.path() }),
+                ));
+            }
+        }
+        edit_counts.retain(|id, _| {
+            let open = buffers.contains_key(id);
+            if !open {
+                notifications.push(rpc::notification("buffer-closed", json!({ "buffer": id })));
+            }
+            open
+        });
+        if !notifications.is_empty() {
+            subscribers.retain(|subscriber| {
+                notifications
+                    .iter()
+                    .all(|notification| subscriber.send(notification.clone()).is_ok())
+            });
         }
     }
 
@@ -97,14 +485,7 @@ impl Editor {
     where
         ComponentT: Component + 'static,
     {
-        let component_id = self.next_component_id;
-        self.next_component_id += 1;
-
-        self.components
-            .get_or_default::<Components<ComponentT>>()
-            .insert(component_id, component);
-        self.focus.get_or_insert(component_id);
-
+        let component_id = self.insert_component(component);
         let mut layout = Layout::Component(PROMPT_ID);
         mem::swap(&mut self.layout, &mut layout);
         self.layout = wrap_layout_with_prompt(
@@ -120,15 +501,45 @@ impl Editor {
         component_id
     }
 
-    pub fn open_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+    /// Stores a new component without adding it to the layout
+    fn insert_component<ComponentT>(&mut self, component: ComponentT) -> ComponentId
+    where
+        ComponentT: Component + 'static,
+    {
+        let component_id = self.next_component_id;
+        self.next_component_id += 1;
+
+        self.components
+            .get_or_default::<Components<ComponentT>>()
+            .insert(component_id, component);
+        self.focus.get_or_insert(component_id);
+        component_id
+    }
+
+    /// Opens a file in a new buffer and focuses it. Returns the buffer, or
+    /// `None` if the file couldn't be read, which is shown in the prompt.
+    pub fn open_file(&mut self, path: impl AsRef<Path>) -> Result<Option<ComponentId>> {
         let path = path.as_ref();
         if !path.exists() {
             self.prompt.log_error("[New file]".into());
         }
 
-        match Buffer::from_file(path.to_owned()) {
+        match Buffer::from_file(path.to_owned(), &self.settings) {
             Ok(buffer) => {
-                self.focus = Some(self.add_component(buffer));
+                let id = self.add_component(buffer);
+                self.focus = Some(id);
+                self.run_script_hook(Hook::Open, id);
+                if recovery::newer_recovery_file(path).is_some() {
+                    self.prompt.ask(
+                        Question::RecoverFile(id),
+                        format!(
+                            "{} has auto-saved changes which are more recent. Recover them?",
+                            path.display()
+                        ),
+                        &[Answer::Yes, Answer::No, Answer::Diff],
+                    );
+                }
+                return Ok(Some(id));
             }
             Err(Error::Io(ref error)) if error.kind() == io::ErrorKind::PermissionDenied => {
                 self.prompt.log_error(format!(
@@ -144,9 +555,621 @@ impl Editor {
                 error?;
             }
         }
+        Ok(None)
+    }
+
+    /// Opens a file and moves to `position` in it, e.g. when given as
+    /// `path:line:column`. A read only buffer refuses to be edited.
+    pub fn open_file_at(
+        &mut self,
+        path: impl AsRef<Path>,
+        position: Option<files::Position>,
+        read_only: bool,
+    ) -> Result<Option<ComponentId>> {
+        let id = self.open_file(path)?;
+        if let Some(id) = id {
+            if let Some(buffer) = self.components.get_or_default::<Buffers>().get_mut(&id) {
+                buffer.set_read_only(read_only);
+            }
+            self.move_opened_buffer(id, position);
+        }
+        Ok(id)
+    }
+
+    /// Focuses a buffer which didn't come from a file, e.g. text from stdin
+    pub fn open_buffer(&mut self, buffer: Buffer, position: Option<files::Position>) {
+        let id = self.add_component(buffer);
+        self.focus = Some(id);
+        self.move_opened_buffer(id, position);
+    }
+
+    /// The buffer isn't laid out yet, so it centres the position once drawn
+    fn move_opened_buffer(&mut self, id: ComponentId, position: Option<files::Position>) {
+        if let Some(position) = position {
+            let action = buffer::Action::Sync(buffer::SyncAction::GotoPosition(position));
+            if let Err(error) = self.reduce_buffer(id, action, PrefixArg::None) {
+                self.prompt.log_error(format!("{}", error));
+            }
+        }
+    }
+
+    /// Snapshot of the buffers currently laid out on screen
+    pub fn session(&mut self) -> Session {
+        let mut layout = Layout::Component(PROMPT_ID);
+        mem::swap(&mut self.layout, &mut layout);
+        let inner_layout = unwrap_prompt_from_layout(layout);
+        self.layout = wrap_layout_with_prompt(self.prompt.height(), inner_layout.clone());
+
+        let buffers = self.components.get_or_default::<Buffers>();
+        let mut buffer_ids = Vec::new();
+        let mut buffer_sessions = Vec::new();
+        if let Some(ref layout) = inner_layout {
+            layout.for_each_component_id(&mut |id| {
+                if let Some(path) = buffers.get(&id).and_then(|buffer| buffer.path()) {
+                    buffer_ids.push(id);
+                    buffer_sessions.push(BufferSession {
+                        path: path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+                        cursor: buffers[&id].cursor_position().0,
+                        first_line: buffers[&id].first_line(),
+                    });
+                }
+            });
+        }
+        let buffer_index = |id| buffer_ids.iter().position(|buffer_id| *buffer_id == id);
+
+        Session {
+            theme_index: self.theme_index,
+            focus: self.focus.and_then(buffer_index),
+            layout: inner_layout
+                .as_ref()
+                .and_then(|layout| SessionLayout::from_layout(layout, &buffer_index)),
+            buffers: buffer_sessions,
+        }
+    }
+
+    pub fn restore_session(&mut self, session: Session) -> Result<()> {
+        let Session {
+            theme_index,
+            focus,
+            buffers,
+            layout,
+        } = session;
+
+        let mut component_ids = Vec::with_capacity(buffers.len());
+        for BufferSession {
+            path,
+            cursor,
+            first_line,
+        } in buffers
+        {
+            match Buffer::from_file(path.clone(), &self.settings) {
+                Ok(mut buffer) => {
+                    buffer.set_position(CharIndex(cursor), first_line);
+                    component_ids.push(Some(self.insert_component(buffer)));
+                }
+                Err(error) => {
+                    self.prompt.log_error(format!(
+                        "Could not restore {}: {}",
+                        path.display(),
+                        error
+                    ));
+                    component_ids.push(None);
+                }
+            }
+        }
+
+        let component_id = |index: usize| component_ids.get(index).copied().flatten();
+        let restored_layout = layout.and_then(|layout| layout.to_layout(&component_id));
+        let mut layout = Layout::Component(PROMPT_ID);
+        mem::swap(&mut self.layout, &mut layout);
+        let mut layout = unwrap_prompt_from_layout(layout)
+            .and_then(|layout| layout.remove_component_id(SPLASH_ID));
+        match restored_layout {
+            Some(restored_layout) if layout.is_none() => {
+                layout = Some(restored_layout);
+            }
+            _ => {
+                // The session couldn't be laid out as it was saved, or there
+                // are already files open; put the restored buffers side by side
+                for id in component_ids.iter().flatten() {
+                    layout = Some(match layout {
+                        Some(layout) => layout.add_left(*id, Flex::Stretched),
+                        None => Layout::Component(*id),
+                    });
+                }
+            }
+        }
+        self.layout = wrap_layout_with_prompt(self.prompt.height(), layout);
+
+        if let Some(id) = focus.and_then(component_id) {
+            self.focus = Some(id);
+        }
+        self.theme_index = theme_index % self.themes.len();
+        Ok(())
+    }
+
+    fn save_session(&mut self) {
+        let session = self.session();
+        if session.buffers.is_empty() {
+            return;
+        }
+        if let Err(error) = session::write_session(&self.working_dir, &session) {
+            log::error!("Could not save session: {}", error);
+        }
+    }
+
+    /// Saves the cursor position in each buffer, to be restored when the
+    /// corresponding file is opened again
+    fn remember_places(&mut self, ids: impl IntoIterator<Item = ComponentId>) {
+        let buffers = self.components.get_or_default::<Buffers>();
+        let places = ids
+            .into_iter()
+            .filter_map(|id| buffers.get(&id).and_then(Buffer::place));
+        if let Err(error) = places::remember_places(places) {
+            log::error!("Could not save cursor positions: {}", error);
+        }
+    }
+
+    /// Buffers with unsaved changes, sorted by file name
+    fn modified_buffers(&mut self) -> Vec<(ComponentId, String)> {
+        let working_dir = &self.working_dir;
+        let mut modified: Vec<_> = self
+            .components
+            .get_or_default::<Buffers>()
+            .iter()
+            .filter(|(_, buffer)| buffer.is_modified())
+            .map(|(id, buffer)| {
+                let name = buffer
+                    .path()
+                    .map(|path| {
+                        path.strip_prefix(working_dir)
+                            .unwrap_or(path)
+                            .display()
+                            .to_string()
+                    })
+                    .unwrap_or_else(|| SCRATCH_BUFFER_NAME.into());
+                (*id, name)
+            })
+            .collect();
+        modified.sort_by(|(_, name1), (_, name2)| name1.cmp(name2));
+        modified
+    }
+
+    fn close_pane(&mut self, id: ComponentId, frame: Rect) {
+        self.remember_places(Some(id));
+        let mut layout = Layout::Component(PROMPT_ID);
+        mem::swap(&mut self.layout, &mut layout);
+        self.layout = wrap_layout_with_prompt(
+            self.prompt.height(),
+            unwrap_prompt_from_layout(layout).and_then(|layout| layout.remove_component_id(id)),
+        );
+        self.components.get_or_default::<Buffers>().remove(&id);
+        if self.focus == Some(id) {
+            self.cycle_focus(frame, CycleFocus::Previous);
+        }
+    }
+
+    fn quit(&mut self) {
+        log::info!("Exiting (user request)...");
+        self.save_session();
+        let buffer_ids: Vec<_> = self
+            .components
+            .get_or_default::<Buffers>()
+            .keys()
+            .copied()
+            .collect();
+        self.remember_places(buffer_ids);
+    }
+
+    /// Closes the buffers which were waiting to be saved, returns whether the
+    /// editor should exit.
+    fn handle_pending_closes(&mut self, frame: Rect) -> bool {
+        let buffers = self.components.get_or_default::<Buffers>();
+        let (saved, unsaved) = self
+            .close_when_saved
+            .iter()
+            .partition(|id| buffers.get(id).map_or(true, |buffer| !buffer.is_modified()));
+        self.close_when_saved = unsaved;
+        for id in saved {
+            self.close_pane(id, frame);
+        }
+
+        if self.quit_when_saved && self.modified_buffers().is_empty() {
+            self.quit();
+            return true;
+        }
+        false
+    }
+
+    /// Returns whether the editor should exit
+    fn handle_answer(&mut self, question: Question, answer: Answer, frame: Rect) -> Result<bool> {
+        match (question, answer) {
+            (Question::RecoverFile(id), Answer::Yes) => {
+                if let Err(error) = self.reduce_buffer(
+                    id,
+                    buffer::Action::Sync(buffer::SyncAction::RecoverFile),
+                    PrefixArg::None,
+                ) {
+                    self.prompt.log_error(format!("{}", error));
+                }
+            }
+            (Question::RecoverFile(id), Answer::No) => {
+                // Declined, don't ask again the next time the file is opened
+                let path = self
+                    .components
+                    .get_or_default::<Buffers>()
+                    .get(&id)
+                    .and_then(|buffer| buffer.path())
+                    .map(Path::to_path_buf);
+                if let Some(path) = path {
+                    if let Err(error) = recovery::remove_recovery_file(&path) {
+                        self.prompt.log_error(format!("{}", error));
+                    }
+                }
+            }
+            (Question::RecoverFile(id), Answer::Diff) => {
+                // Show how the recovery file differs from the file on disk
+                // next to the original, then ask again
+                let path = self
+                    .components
+                    .get_or_default::<Buffers>()
+                    .get(&id)
+                    .and_then(|buffer| buffer.path())
+                    .map(Path::to_path_buf);
+                if let Some(path) = path {
+                    match recovery::diff_recovery_file(&path) {
+                        Ok(diff) => {
+                            self.add_component(Buffer::new_scratch_with_text(diff.into()));
+                        }
+                        Err(error) => self.prompt.log_error(format!("{}", error)),
+                    }
+                    self.focus = Some(id);
+                    self.prompt.ask(
+                        Question::RecoverFile(id),
+                        format!("Recover auto-saved changes to {}?", path.display()),
+                        &[Answer::Yes, Answer::No],
+                    );
+                }
+            }
+            (Question::OverwriteFile(id, prefix_arg), Answer::Yes) => {
+                if let Err(error) = self.reduce_buffer(
+                    id,
+                    buffer::Action::Sync(buffer::SyncAction::OverwriteFile),
+                    prefix_arg,
+                ) {
+                    self.prompt.log_error(format!("{}", error));
+                }
+            }
+            (Question::ReplaceFile(id, operation, path), Answer::Yes) => {
+                self.file_operation(id, operation, path, true)
+            }
+            (Question::DeleteFile(id), Answer::Yes) => {
+                if let Err(error) = self.delete_file(id, frame) {
+                    self.prompt.log_error(format!("{}", error));
+                }
+            }
+            (Question::ClosePane(id), Answer::Yes) => self.close_pane(id, frame),
+            (Question::ClosePane(id), Answer::Save) => {
+                if self.save_buffer(id, PrefixArg::None) {
+                    self.close_when_saved.push(id);
+                }
+            }
+            (Question::Quit, Answer::Yes) => {
+                self.quit();
+                return Ok(true);
+            }
+            (Question::Quit, Answer::SaveAll) => {
+                let mut unnamed = Vec::new();
+                for (id, name) in self.modified_buffers() {
+                    let has_file = self
+                        .components
+                        .get_or_default::<Buffers>()
+                        .get(&id)
+                        .map_or(false, |buffer| buffer.path().is_some());
+                    if !has_file {
+                        unnamed.push(name);
+                    } else if !self.save_buffer(id, PrefixArg::None) {
+                        // The error, or the question about overwriting, is
+                        // shown instead of quitting
+                        return Ok(false);
+                    }
+                }
+                if !unnamed.is_empty() {
+                    self.prompt.log_error(format!(
+                        "Not quitting, {} has no file to save to",
+                        unnamed.join(", ")
+                    ));
+                    return Ok(false);
+                }
+                self.quit_when_saved = true;
+                return Ok(self.handle_pending_closes(frame));
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Saves, renames or copies the file of a buffer to `path`, asking first
+    /// if that would replace another file.
+    fn file_operation(
+        &mut self,
+        id: ComponentId,
+        operation: FileOperation,
+        path: PathBuf,
+        replace: bool,
+    ) {
+        let old_path = match self.components.get_or_default::<Buffers>().get(&id) {
+            Some(buffer) => buffer.path().map(Path::to_path_buf),
+            None => return,
+        };
+        // A duplicate is a new buffer, the others change this one's file
+        if operation != FileOperation::Duplicate && self.refuse_read_only(id) {
+            return;
+        }
+        if !replace && path.exists() && old_path.as_ref() != Some(&path) {
+            self.prompt.ask(
+                Question::ReplaceFile(id, operation, path.clone()),
+                format!("{} already exists. Replace it?", path.display()),
+                &[Answer::Yes, Answer::No],
+            );
+            return;
+        }
+
+        let result = match (operation, old_path) {
+            (FileOperation::Rename, Some(ref old_path)) if old_path.exists() => {
+                fs::rename(old_path, &path)
+                    .and_then(|_| recovery::remove_recovery_file(old_path))
+                    .map_err(Error::from)
+                    .and_then(|_| {
+                        self.components
+                            .get_or_default::<Buffers>()
+                            .get_mut(&id)
+                            .unwrap()
+                            .set_file_path(path, &self.settings)
+                    })
+            }
+            // A file which isn't on disk yet is renamed by saving it
+            (FileOperation::SaveAs, _) | (FileOperation::Rename, _) => self
+                .components
+                .get_or_default::<Buffers>()
+                .get_mut(&id)
+                .unwrap()
+                .set_file_path(path, &self.settings)
+                .and_then(|_| {
+                    self.reduce_buffer(
+                        id,
+                        buffer::Action::Sync(buffer::SyncAction::OverwriteFile),
+                        PrefixArg::None,
+                    )
+                }),
+            (FileOperation::Duplicate, _) => {
+                let duplicate = self.components.get_or_default::<Buffers>()[&id]
+                    .duplicate(path, &self.settings);
+                let duplicate_id = self.add_component(duplicate);
+                self.focus = Some(duplicate_id);
+                self.reduce_buffer(
+                    duplicate_id,
+                    buffer::Action::Sync(buffer::SyncAction::SaveBuffer),
+                    PrefixArg::None,
+                )
+            }
+        };
+        if let Err(error) = result {
+            self.prompt.log_error(format!("{}", error));
+        }
+    }
+
+    fn delete_file(&mut self, id: ComponentId, frame: Rect) -> Result<()> {
+        if self.refuse_read_only(id) {
+            return Ok(());
+        }
+        let path = self
+            .components
+            .get_or_default::<Buffers>()
+            .get(&id)
+            .and_then(|buffer| buffer.path())
+            .map(Path::to_path_buf);
+        if let Some(path) = path {
+            match fs::remove_file(&path) {
+                Err(ref error) if error.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+            recovery::remove_recovery_file(&path)?;
+            self.close_pane(id, frame);
+            self.prompt.log_error(format!("Deleted {}", path.display()));
+        }
+        Ok(())
+    }
+
+    /// Whether a buffer is read only, which is then shown in the prompt, as
+    /// its file can't be saved, moved or deleted
+    fn refuse_read_only(&mut self, id: ComponentId) -> bool {
+        let read_only = self
+            .components
+            .get_or_default::<Buffers>()
+            .get(&id)
+            .map_or(false, Buffer::is_read_only);
+        if read_only {
+            self.prompt.log_error(format!("{}", Error::ReadOnlyBuffer));
+        }
+        read_only
+    }
+
+    /// Sends an action to a buffer outside of the usual key handling, e.g. in
+    /// response to an answer in the prompt
+    fn reduce_buffer(
+        &mut self,
+        id: ComponentId,
+        action: buffer::Action,
+        prefix_arg: PrefixArg,
+    ) -> Result<()> {
+        let is_save = matches!(
+            action,
+            buffer::Action::Sync(buffer::SyncAction::SaveBuffer)
+                | buffer::Action::Sync(buffer::SyncAction::OverwriteFile)
+        );
+        if is_save
+            && self
+                .components
+                .get_or_default::<Buffers>()
+                .get(&id)
+                .map_or(false, Buffer::can_save)
+        {
+            self.run_script_hook(Hook::Save, id);
+        }
+        let Self {
+            ref mut components,
+            ref mut task_owners,
+            ref laid_components,
+            ref current_path,
+            ref settings,
+            ref task_pool,
+            ref themes,
+            theme_index,
+            ..
+        } = *self;
+        let laid = laid_components.iter().find(|laid| laid.id == id);
+        if let Some(component) = components.get_or_default::<Buffers>().get_mut(&id) {
+            let mut scheduler = task_pool.scheduler();
+            component.reduce(
+                action,
+                &mut scheduler,
+                &Context {
+                    time: Instant::now(),
+                    focused: false,
+                    frame: laid.map(|laid| laid.frame).unwrap_or_else(Rect::zero),
+                    frame_id: laid.map(|laid| laid.frame_id).unwrap_or(0),
+                    theme: &themes[theme_index].0,
+                    path: current_path.as_path(),
+                    settings,
+                    prefix_arg,
+                    vi_mode: None,
+                },
+            )?;
+            for task_id in scheduler.scheduled() {
+                task_owners.insert(task_id, id);
+            }
+        }
         Ok(())
     }
 
+    /// Auto-saves buffers and checks for files changed on disk when due
+    fn run_periodic_tasks(&mut self) {
+        let autosave = self.last_autosave.elapsed() >= AUTOSAVE_INTERVAL;
+        let check_files = self.last_file_check.elapsed() >= FILE_CHECK_INTERVAL;
+        if autosave {
+            self.last_autosave = Instant::now();
+        }
+        if check_files {
+            self.last_file_check = Instant::now();
+            self.check_settings_file();
+        }
+        if !autosave && !check_files {
+            return;
+        }
+
+        let Self {
+            ref mut components,
+            ref mut prompt,
+            ref mut task_owners,
+            ref task_pool,
+            ..
+        } = *self;
+        for (id, buffer) in components.get_or_default::<Buffers>().iter_mut() {
+            let mut scheduler = task_pool.scheduler();
+            if autosave {
+                if let Err(error) = buffer.spawn_autosave(&mut scheduler) {
+                    prompt.log_error(format!("{}", error));
+                }
+            }
+            if check_files {
+                if let Err(error) = buffer.spawn_check_file(&mut scheduler) {
+                    prompt.log_error(format!("{}", error));
+                }
+            }
+            for task_id in scheduler.scheduled() {
+                task_owners.insert(task_id, *id);
+            }
+        }
+    }
+
+    /// Reads the settings file again if it was modified and applies the new
+    /// settings. If they're invalid, the error is shown and the current
+    /// settings are kept.
+    fn check_settings_file(&mut self) {
+        let settings_path = match self.settings_path {
+            Some(ref settings_path) => settings_path,
+            None => return,
+        };
+        let modified = settings::modified_time(settings_path);
+        if modified == self.settings_modified {
+            return;
+        }
+        self.settings_modified = modified;
+
+        match settings::read_settings(settings_path) {
+            Ok(settings) => {
+                // Keep a theme picked with `C-t` unless the file changes it
+                if settings.theme_index != self.settings.theme_index {
+                    self.theme_index = settings.theme_index;
+                }
+                self.keymap = Keymap::new(&settings.keys).unwrap_or_default();
+                if settings.keymap != self.settings.keymap {
+                    self.vi = vi_for(&settings);
+                }
+                self.settings = settings;
+                let Self {
+                    ref mut components,
+                    ref settings,
+                    ..
+                } = *self;
+                for buffer in components.get_or_default::<Buffers>().values_mut() {
+                    buffer.update_mode(settings);
+                }
+                self.prompt.log_error("Settings reloaded".into());
+            }
+            Err(error) => self.prompt.log_error(format!("{}", error)),
+        }
+    }
+
+    /// Shows the bindings which complete a prefix once no key was pressed
+    /// for `KEY_HINTS_DELAY`. Returns whether they need to be drawn.
+    fn show_key_hints_when_due(&mut self, frame: Rect) -> bool {
+        match self.pending_prefix_since {
+            Some(since) if since.elapsed() >= KEY_HINTS_DELAY => {
+                self.pending_prefix_since = None;
+                let hints = self
+                    .keymap
+                    .key_hints(&self.controller.keys, self.prompt.is_active());
+                self.prompt.show_key_hints(hints, frame.size.width);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How long the event loop can wait before `run_periodic_tasks` is due,
+    /// or before key hints should be shown
+    fn time_to_periodic_tasks(&self) -> Duration {
+        let periodic_tasks = cmp::min(
+            AUTOSAVE_INTERVAL
+                .checked_sub(self.last_autosave.elapsed())
+                .unwrap_or_default(),
+            FILE_CHECK_INTERVAL
+                .checked_sub(self.last_file_check.elapsed())
+                .unwrap_or_default(),
+        );
+        match self.pending_prefix_since {
+            Some(since) => cmp::min(
+                periodic_tasks,
+                KEY_HINTS_DELAY
+                    .checked_sub(since.elapsed())
+                    .unwrap_or_default(),
+            ),
+            None => periodic_tasks,
+        }
+    }
+
     pub fn ui_loop(&mut self, mut screen: Screen, mut frontend: impl Frontend) -> Result<()> {
         let mut average = 0.0;
         let mut n = 0;
@@ -197,6 +1220,20 @@ impl Editor {
         let mut dirty = false;
 
         while !force_redraw {
+            self.run_periodic_tasks();
+            self.release_waiting_clients();
+            self.notify_subscribers();
+            self.run_mode_change_hooks();
+            if !self.script_commands.is_empty() {
+                dirty = true;
+                if self.run_script_commands(frame) {
+                    return Ok(PollState::Exit);
+                }
+            }
+            if self.show_key_hints_when_due(frame) {
+                dirty = true;
+            }
+
             let timeout = {
                 let since_last_drawn = last_drawn.elapsed();
                 if dirty && since_last_drawn >= REDRAW_LATENCY {
@@ -204,7 +1241,7 @@ impl Editor {
                 } else if dirty {
                     REDRAW_LATENCY - since_last_drawn
                 } else {
-                    Duration::from_millis(60000)
+                    self.time_to_periodic_tasks()
                 }
             };
 
@@ -219,6 +1256,8 @@ impl Editor {
                         ref task_pool,
                         ref themes,
                         ref focus,
+                        ref mut close_when_saved,
+                        ref mut quit_when_saved,
                         theme_index,
                         ..
                     } = *self;
@@ -233,6 +1272,8 @@ impl Editor {
                         theme: &themes[theme_index].0,
                         path: current_path.as_path(),
                         settings,
+                        prefix_arg: PrefixArg::None,
+                        vi_mode: None,
                     };
                     if component_id == Some(PROMPT_ID) {
                         let mut scheduler = task_pool.scheduler();
@@ -244,9 +1285,36 @@ impl Editor {
                         let mut scheduler = task_pool.scheduler();
                         if let Err(err) = component.reduce(task_result.unwrap_buffer().payload, &mut scheduler, &context) {
                             prompt.log_error(format!("{}", err));
+                            // The save may have failed, don't close the buffer
+                            close_when_saved.retain(|id| Some(*id) != component_id);
+                            *quit_when_saved = false;
+                        }
+                        for task_id in scheduler.scheduled() {
+                            task_owners.insert(task_id, component_id.unwrap());
                         }
                     }
                     dirty = true; // notify_task_done should return whether we need to rerender
+                    if self.handle_pending_closes(frame) {
+                        return Ok(PollState::Exit);
+                    }
+                }
+                recv(self.rpc_calls) -> call => {
+                    match call {
+                        Ok(call) => {
+                            if self.handle_rpc_call(call, frame) {
+                                return Ok(PollState::Exit);
+                            }
+                        }
+                        Err(_) => self.rpc_calls = crossbeam_channel::never(),
+                    }
+                    dirty = true;
+                }
+                recv(self.client_requests) -> request => {
+                    match request {
+                        Ok(request) => self.handle_open_request(request),
+                        Err(_) => self.client_requests = crossbeam_channel::never(),
+                    }
+                    dirty = true;
                 }
                 recv(frontend.events()) -> event => {
                     match event.map_err(anyhow::Error::from)? {
@@ -288,6 +1356,7 @@ impl Editor {
             ref settings,
             ref task_pool,
             ref themes,
+            ref vi,
             theme_index,
             ..
         } = *self;
@@ -306,6 +1375,8 @@ impl Editor {
                     theme: &themes[theme_index].0,
                     path: current_path.as_path(),
                     settings,
+                    prefix_arg: PrefixArg::None,
+                    vi_mode: vi.as_ref().map(Vi::mode),
                 };
 
                 if id == PROMPT_ID {
@@ -340,187 +1411,702 @@ impl Editor {
 
     #[inline]
     fn handle_event(&mut self, key: Key, frame: Rect) -> Result<bool> {
-        let time = Instant::now();
+        if !self.playing_macro {
+            if let Some(recording) = self.recording_macro.as_mut() {
+                if self.controller.keys.is_empty() && !self.prompt.is_active() {
+                    recording.sequence_start = recording.keys.len();
+                }
+                recording.keys.push(key);
+            }
+        }
+
         let mut is_prefix_to_binding = false;
         self.controller.push(key);
         self.prompt.clear_log();
+        self.prompt.clear_key_hints();
+        self.pending_prefix_since = None;
+
+        if self.describing_key {
+            self.describe_key();
+            return Ok(false);
+        }
+
+        if !self.prompt.is_active() && self.controller.take_digit_argument() {
+            self.prompt.log_error(format!("{}", self.controller));
+            return Ok(false);
+        }
 
         if !self.prompt.is_active() {
-            let editor_binding_match = self.controller.matches(EDITOR_BINDINGS.deref());
+            let editor_binding_match = self.controller.matches(&self.keymap.editor);
             is_prefix_to_binding = is_prefix_to_binding || editor_binding_match.is_prefix();
-            match editor_binding_match {
-                BindingMatch::Full(EditorAction::FocusNextComponent) => {
-                    self.cycle_focus(frame, CycleFocus::Next);
-                    return Ok(false);
+            if let BindingMatch::Full(action) = editor_binding_match {
+                if let Some(ref mut vi) = self.vi {
+                    vi.cancel_command();
                 }
-                BindingMatch::Full(EditorAction::FocusPreviousComponent) => {
-                    self.cycle_focus(frame, CycleFocus::Previous);
-                    return Ok(false);
+                let prefix_arg = self.controller.take_prefix_arg();
+                return self.run_editor_action(action, prefix_arg, frame);
+            }
+
+            if let Some(id) = self.focus {
+                if let Some(path) = self
+                    .components
+                    .get_or_default::<Buffers>()
+                    .get(&id)
+                    .and_then(|buffer| buffer.path())
+                {
+                    self.current_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
                 }
-                BindingMatch::Full(EditorAction::ClosePane) => {
-                    if let Some(focus) = self.focus {
-                        let mut layout = Layout::Component(PROMPT_ID);
-                        mem::swap(&mut self.layout, &mut layout);
-                        self.layout = wrap_layout_with_prompt(
-                            self.prompt.height(),
-                            unwrap_prompt_from_layout(layout)
-                                .and_then(|layout| layout.remove_component_id(focus)),
-                        );
-                        self.cycle_focus(frame, CycleFocus::Previous);
+                let vi_outcome = match self.vi {
+                    Some(ref mut vi)
+                        if !is_prefix_to_binding && self.controller.keys.len() == 1 =>
+                    {
+                        vi.handle_key(key)
                     }
-                    return Ok(false);
-                }
-                BindingMatch::Full(EditorAction::ChangeTheme) => {
-                    self.theme_index = (self.theme_index + 1) % self.themes.len();
-                    self.prompt.log_error(format!(
-                        "Theme changed to {}",
-                        self.themes[self.theme_index].1
-                    ));
-                    return Ok(false);
-                }
-                BindingMatch::Full(EditorAction::Quit) => {
-                    log::info!("Exiting (user request)...");
-                    return Ok(true);
-                }
-                _ => {}
-            };
-
-            if let Some(&id_with_focus) = self.focus.as_ref() {
-                self.lay_components(frame);
-
-                let Self {
-                    ref mut components,
-                    ref mut prompt,
-                    ref mut task_owners,
-                    ref mut current_path,
-                    ref mut controller,
-                    ref laid_components,
-                    ref themes,
-                    ref task_pool,
-                    ref settings,
-                    theme_index,
-                    ..
-                } = *self;
-                laid_components.iter().try_for_each(
-                    |&LaidComponentId {
-                         id,
-                         frame,
-                         frame_id,
-                     }|
-                     -> Result<()> {
-                        if id_with_focus != id {
-                            return Ok(());
-                        }
-
-                        let mut scheduler = task_pool.scheduler();
-                        let component =
-                            components.get_or_default::<Buffers>().get_mut(&id).unwrap();
-                        if let Some(path) = component.path() {
-                            *current_path =
-                                path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-                        }
-                        let binding_match = component
-                            .bindings()
-                            .map(|bindings| controller.matches(bindings));
-
-                        is_prefix_to_binding = is_prefix_to_binding
-                            || binding_match
-                                .as_ref()
-                                .map(|binding_match| binding_match.is_prefix())
-                                .unwrap_or(false);
-
-                        // log::info!("m: {:?} {}", binding_match, is_prefix_to_binding);
-                        if let Some(BindingMatch::Full(action)) = binding_match {
-                            if let Err(error) = component.reduce(
-                                action,
-                                &mut scheduler,
-                                &Context {
-                                    time,
-                                    focused: true,
-                                    frame,
-                                    frame_id,
-                                    theme: &themes[theme_index].0,
-                                    path: current_path.as_path(),
-                                    settings,
-                                },
-                            ) {
-                                prompt.log_error(format!("{}", error));
-                            }
+                    _ => vi::Outcome::Unhandled,
+                };
+                match vi_outcome {
+                    vi::Outcome::Pending => {
+                        self.controller.clear();
+                        if let Some(ref vi) = self.vi {
+                            self.prompt
+                                .log_error(keymap::format_keys(vi.pending_keys()));
                         }
-                        for task_id in scheduler.scheduled() {
-                            task_owners.insert(task_id, id);
+                        return Ok(false);
+                    }
+                    vi::Outcome::Run(actions, prefix_arg) => {
+                        self.controller.clear();
+                        for action in actions {
+                            self.run_buffer_action(
+                                id,
+                                buffer::Action::Sync(action),
+                                prefix_arg,
+                                frame,
+                            );
                         }
+                        return Ok(false);
+                    }
+                    vi::Outcome::Repeat(keys, count) => {
+                        self.controller.clear();
+                        return self.play_keys(&keys, count, frame);
+                    }
+                    vi::Outcome::Unhandled => {}
+                }
 
-                        Ok(())
-                    },
-                )?;
+                // Outside of insert mode, characters aren't inserted
+                let vi_command_mode = self
+                    .vi
+                    .as_ref()
+                    .map_or(false, |vi| vi.mode() != vi::Mode::Insert);
+                let is_editing_key = matches!(
+                    self.controller.keys.as_slice(),
+                    [Key::Char(_)] | [Key::Backspace] | [Key::Delete]
+                );
+                if !(vi_command_mode && is_editing_key) {
+                    let binding_match = self.controller.matches(&self.keymap.buffer);
+                    is_prefix_to_binding = is_prefix_to_binding || binding_match.is_prefix();
+                    if let BindingMatch::Full(action) = binding_match {
+                        let prefix_arg = self.controller.take_prefix_arg();
+                        self.run_buffer_action(id, action, prefix_arg, frame);
+                    }
+                }
             }
         }
 
         // Update prompt
-        {
-            let Self {
-                ref mut controller,
-                ref mut current_path,
-                ref mut prompt,
-                ref mut task_owners,
-                ref task_pool,
-                ref themes,
-                ref settings,
-                theme_index,
-                ..
-            } = *self;
-
-            let binding_match = prompt
-                .bindings()
-                .map(|bindings| controller.matches(bindings));
-
-            is_prefix_to_binding = is_prefix_to_binding
-                || binding_match
-                    .as_ref()
-                    .map(|binding_match| binding_match.is_prefix())
-                    .unwrap_or(false);
-
-            if let Some(BindingMatch::Full(action)) = binding_match {
-                let mut scheduler = task_pool.scheduler();
-                prompt.reduce(
-                    action,
-                    &mut scheduler,
-                    &Context {
-                        time,
-                        focused: false,
-                        frame,
-                        frame_id: 0,
-                        theme: &themes[theme_index].0,
-                        path: current_path.as_path(),
-                        settings,
-                    },
-                )?;
-                for task_id in scheduler.scheduled() {
-                    task_owners.insert(task_id, PROMPT_ID);
+        let binding_match = self.controller.matches(&self.keymap.prompt);
+        is_prefix_to_binding = is_prefix_to_binding || binding_match.is_prefix();
+        if let BindingMatch::Full(action) = binding_match {
+            self.reduce_prompt(action, frame)?;
+        }
+
+        match self.prompt.poll_and_clear() {
+            Some(Command::OpenFile(path)) => {
+                let (path, position) = files::split_location(path);
+                self.open_file_at(path, position, false)?;
+            }
+            Some(Command::FileOperation(operation, path)) => match self.focus {
+                Some(id)
+                    if self
+                        .components
+                        .get_or_default::<Buffers>()
+                        .contains_key(&id) =>
+                {
+                    self.file_operation(id, operation, path, false)
+                }
+                _ => self.prompt.log_error("No buffer is focused".into()),
+            },
+            Some(Command::Answer(question, answer)) => {
+                if self.handle_answer(question, answer, frame)? {
+                    return Ok(true);
                 }
             }
-        }
-        if let Some(Command::OpenFile(path)) = self.prompt.poll_and_clear() {
-            self.open_file(path)?;
+            Some(Command::Run(command)) => {
+                if self.run_command(command, frame)? {
+                    return Ok(true);
+                }
+            }
+            Some(Command::Input(input, text)) => {
+                if self.handle_input(input, text, frame)? {
+                    return Ok(true);
+                }
+            }
+            None => {}
         }
 
         if key == Key::Ctrl('g') {
             self.prompt.log_error("Cancel".into());
-            self.controller.keys.clear();
+            self.controller.clear();
         } else if !self.controller.keys.is_empty() {
             if !is_prefix_to_binding {
                 self.prompt
                     .log_error(format!("{}is undefined", self.controller));
-                self.controller.keys.clear();
+                self.controller.clear();
             } else {
                 self.prompt.log_error(format!("{}", self.controller));
+                self.pending_prefix_since = Some(Instant::now());
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Returns whether the editor should exit
+    fn run_editor_action(
+        &mut self,
+        action: EditorAction,
+        prefix_arg: PrefixArg,
+        frame: Rect,
+    ) -> Result<bool> {
+        match action {
+            EditorAction::UniversalArgument => {
+                self.controller.universal_argument(prefix_arg);
+                self.prompt.log_error(format!("{}", self.controller));
+            }
+            EditorAction::ExecuteCommand => {
+                let mut commands = self.keymap.commands();
+                let mut macros: Vec<_> = self.settings.macros.iter().collect();
+                macros.sort_unstable();
+                commands.extend(macros.into_iter().map(|(name, keys)| CommandEntry {
+                    name: name.clone(),
+                    description: format!("Play the keyboard macro {}", keys),
+                    keys: String::new(),
+                    command: NamedCommand::Macro(name.clone()),
+                }));
+                commands.extend(
+                    self.scripts
+                        .commands()
+                        .into_iter()
+                        .map(|command| CommandEntry {
+                            command: NamedCommand::Script(command.name.clone()),
+                            name: command.name,
+                            description: command.description,
+                            keys: String::new(),
+                        }),
+                );
+                self.prompt.pick_command(commands);
+            }
+            EditorAction::DescribeKey => {
+                self.describing_key = true;
+                self.prompt.log_error("Describe key: ".into());
+            }
+            EditorAction::DescribeBindings => {
+                let text = self.keymap.describe_bindings();
+                let id = self.add_component(Buffer::new_scratch_with_text(text.into()));
+                self.focus = Some(id);
+            }
+            EditorAction::StartMacro => {
+                if self.recording_macro.is_some() {
+                    self.prompt.log_error("Already recording a macro".into());
+                } else if !self.playing_macro {
+                    self.recording_macro = Some(MacroRecording::default());
+                    self.prompt.log_error("Recording macro...".into());
+                }
+            }
+            EditorAction::StopMacro => match self.recording_macro.take() {
+                Some(MacroRecording {
+                    mut keys,
+                    sequence_start,
+                }) => {
+                    // Leave out the keys which stopped the recording
+                    keys.truncate(sequence_start);
+                    if keys.is_empty() {
+                        self.prompt.log_error("The macro is empty".into());
+                    } else {
+                        self.prompt
+                            .log_error(format!("Recorded macro {}", keymap::format_keys(&keys)));
+                        self.last_macro = Some(keys);
+                    }
+                }
+                None if self.playing_macro => {}
+                None => self.prompt.log_error("Not recording a macro".into()),
+            },
+            EditorAction::PlayMacro => return self.play_last_macro(prefix_arg.count(), frame),
+            EditorAction::RepeatMacro | EditorAction::NameMacro if self.last_macro.is_none() => {
+                self.prompt.log_error("No macro was recorded".into());
+            }
+            EditorAction::RepeatMacro => self.prompt.read_input(Input::MacroRepeatCount),
+            EditorAction::NameMacro => self.prompt.read_input(Input::MacroName),
+            EditorAction::GotoLine => match prefix_arg {
+                PrefixArg::Number(line) => {
+                    self.goto_position(files::Position { line, column: 1 }, frame)
+                }
+                _ => self.prompt.read_input(Input::GotoLine),
+            },
+            EditorAction::FocusNextComponent => {
+                self.cycle_focus(frame, CycleFocus::Next);
+            }
+            EditorAction::FocusPreviousComponent => {
+                self.cycle_focus(frame, CycleFocus::Previous);
+            }
+            EditorAction::ClosePane => {
+                if let Some(focus) = self.focus {
+                    let modified = self.modified_buffers();
+                    match modified.iter().find(|(id, _)| *id == focus) {
+                        Some((_, name)) => self.prompt.ask(
+                            Question::ClosePane(focus),
+                            format!("{} has unsaved changes. Close it anyway?", name),
+                            &[Answer::Yes, Answer::No, Answer::Save],
+                        ),
+                        None => self.close_pane(focus, frame),
+                    }
+                }
+            }
+            EditorAction::NewScratchBuffer => {
+                let id = self.add_component(Buffer::new_scratch());
+                self.focus = Some(id);
+            }
+            EditorAction::DeleteFile => {
+                let focus = self.focus;
+                let path = focus
+                    .and_then(|id| self.components.get_or_default::<Buffers>().get(&id))
+                    .and_then(|buffer| buffer.path())
+                    .map(Path::to_path_buf);
+                match (focus, path) {
+                    (Some(id), Some(_)) if self.refuse_read_only(id) => {}
+                    (Some(id), Some(path)) => self.prompt.ask(
+                        Question::DeleteFile(id),
+                        format!("Delete {}?", path.display()),
+                        &[Answer::Yes, Answer::No],
+                    ),
+                    _ => self
+                        .prompt
+                        .log_error("The buffer isn't backed by a file".into()),
+                }
+            }
+            EditorAction::ChangeTheme => {
+                self.theme_index = (self.theme_index + 1) % self.themes.len();
+                self.prompt.log_error(format!(
+                    "Theme changed to {}",
+                    self.themes[self.theme_index].1
+                ));
+            }
+            EditorAction::Quit => {
+                let modified = self.modified_buffers();
+                if modified.is_empty() {
+                    self.quit();
+                    return Ok(true);
+                }
+                let names: Vec<_> = modified.into_iter().map(|(_, name)| name).collect();
+                self.prompt.ask(
+                    Question::Quit,
+                    format!("Unsaved changes in {}. Quit anyway?", names.join(", ")),
+                    &[Answer::Yes, Answer::No, Answer::SaveAll],
+                );
+            }
+        }
+        Ok(false)
+    }
+
+    /// Shows what the keys pressed after `C-h k` run, once they aren't a
+    /// prefix of a binding anymore
+    fn describe_key(&mut self) {
+        let buffer_focused = self.focus.map_or(false, |id| {
+            self.components
+                .get_or_default::<Buffers>()
+                .contains_key(&id)
+        });
+        match self.keymap.describe_key(
+            &self.controller.keys,
+            self.prompt.is_active(),
+            buffer_focused,
+        ) {
+            Some(description) => {
+                self.describing_key = false;
+                self.controller.keys.clear();
+                self.prompt.log_error(description);
+            }
+            None => self
+                .prompt
+                .log_error(format!("Describe key: {}", self.controller)),
+        }
+    }
+
+    /// Runs a command picked from the command palette. Returns whether the
+    /// editor should exit.
+    fn run_command(&mut self, command: NamedCommand, frame: Rect) -> Result<bool> {
+        match command {
+            NamedCommand::Editor(action) => {
+                return self.run_editor_action(action, PrefixArg::None, frame)
+            }
+            NamedCommand::Buffer(action) => match self.focus {
+                Some(id)
+                    if self
+                        .components
+                        .get_or_default::<Buffers>()
+                        .contains_key(&id) =>
+                {
+                    self.run_buffer_action(id, buffer::Action::Sync(action), PrefixArg::None, frame)
+                }
+                _ => self.prompt.log_error("No buffer is focused".into()),
+            },
+            NamedCommand::Prompt(action) => {
+                self.reduce_prompt(prompt::Action::Sync(action), frame)?
+            }
+            NamedCommand::Macro(name) => {
+                match self
+                    .settings
+                    .macros
+                    .get(&name)
+                    .map(|keys| keymap::parse_keys(keys))
+                {
+                    Some(Ok(keys)) => return self.play_macro(&keys, 1, frame),
+                    _ => self
+                        .prompt
+                        .log_error(format!("No macro is named `{}`", name)),
+                }
+            }
+            NamedCommand::Script(name) => {
+                let snapshot = self.focus.and_then(|id| self.buffer_snapshot(id));
+                match self.scripts.run_command(&name, snapshot) {
+                    Ok(actions) => self.apply_script_actions(self.focus, actions),
+                    Err(error) => self.prompt.log_error(format!("{}", error)),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs the init script's commands and hooks
+    pub fn set_scripts(&mut self, scripts: Scripts) {
+        self.scripts = scripts;
+    }
+
+    fn buffer_snapshot(&self, id: ComponentId) -> Option<BufferSnapshot> {
+        self.components
+            .get::<Buffers>()
+            .and_then(|buffers| buffers.get(&id))
+            .map(|buffer| BufferSnapshot {
+                text: buffer.text().clone(),
+                path: buffer.path().map(Path::to_path_buf),
+                mode: buffer.mode_name().into(),
+                cursor: buffer.cursor_location(),
+            })
+    }
+
+    fn run_script_hook(&mut self, hook: Hook, id: ComponentId) {
+        if self.running_script || !self.scripts.has_hook(hook) {
+            return;
+        }
+        if let Some(snapshot) = self.buffer_snapshot(id) {
+            match self.scripts.run_hook(hook, snapshot) {
+                Ok(actions) => self.apply_script_actions(Some(id), actions),
+                Err(error) => self.prompt.log_error(format!("{}", error)),
+            }
+        }
+    }
+
+    /// Calls `on_mode_change` for the buffers whose mode changed since the
+    /// last time, e.g. after being saved with a new extension
+    fn run_mode_change_hooks(&mut self) {
+        if !self.scripts.has_hook(Hook::ModeChange) {
+            return;
+        }
+        let Self {
+            ref mut components,
+            ref mut script_modes,
+            ..
+        } = *self;
+        let buffers = components.get_or_default::<Buffers>();
+        script_modes.retain(|id, _| buffers.contains_key(id));
+        let changed: Vec<_> = buffers
+            .iter()
+            .filter(|(id, buffer)| {
+                let previous = script_modes.insert(**id, buffer.mode_name().into());
+                matches!(previous, Some(previous) if previous != buffer.mode_name())
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in changed {
+            self.run_script_hook(Hook::ModeChange, id);
+        }
+    }
+
+    /// Makes the changes a script asked for in buffer `id`. Commands are
+    /// queued and run from the event loop, which has the frame they need.
+    fn apply_script_actions(&mut self, id: Option<ComponentId>, actions: Vec<ScriptAction>) {
+        let running_script = mem::replace(&mut self.running_script, true);
+        for action in actions {
+            let buffer_action = match action {
+                ScriptAction::Insert(text) => buffer::SyncAction::InsertText(text),
+                ScriptAction::SetCursor(position) => buffer::SyncAction::GotoPosition(position),
+                ScriptAction::RunCommand(name) => {
+                    self.script_commands.push(name);
+                    continue;
+                }
+                ScriptAction::Message(text) => {
+                    self.prompt.log_error(text);
+                    continue;
+                }
+                ScriptAction::OpenFile(location) => {
+                    let (path, position) =
+                        files::split_location(files::relative_to(&self.current_path, location));
+                    if let Err(error) = self.open_file_at(path, position, false) {
+                        self.prompt.log_error(format!("{}", error));
+                    }
+                    continue;
+                }
+            };
+            let result = match id {
+                Some(id) => {
+                    self.reduce_buffer(id, buffer::Action::Sync(buffer_action), PrefixArg::None)
+                }
+                None => Err(Error::Script("no buffer is focused".into())),
+            };
+            if let Err(error) = result {
+                self.prompt.log_error(format!("{}", error));
+            }
+        }
+        self.running_script = running_script;
+    }
+
+    /// Runs the commands asked for by scripts. Returns whether the editor
+    /// should exit.
+    fn run_script_commands(&mut self, frame: Rect) -> bool {
+        let running_script = mem::replace(&mut self.running_script, true);
+        let mut exit = false;
+        for name in mem::take(&mut self.script_commands) {
+            match self
+                .named_command(&name)
+                .map(|command| self.run_command(command, frame))
+            {
+                Some(Ok(true)) => {
+                    exit = true;
+                    break;
+                }
+                Some(Ok(false)) => {}
+                Some(Err(error)) => self.prompt.log_error(format!("{}", error)),
+                None => self
+                    .prompt
+                    .log_error(format!("No command is named `{}`", name)),
             }
         }
+        self.running_script = running_script;
+        exit
+    }
 
+    /// Acts on text read with the prompt. Returns whether the editor should
+    /// exit.
+    fn handle_input(&mut self, input: Input, text: String, frame: Rect) -> Result<bool> {
+        match input {
+            Input::MacroRepeatCount => match text.parse() {
+                Ok(count) => return self.play_last_macro(count, frame),
+                Err(_) => self
+                    .prompt
+                    .log_error(format!("`{}` isn't a number of times", text)),
+            },
+            Input::MacroName => {
+                let keys = match self.last_macro {
+                    Some(ref keys) => keymap::format_keys(keys),
+                    None => return Ok(false),
+                };
+                let saved = match self.settings_path {
+                    Some(ref path) => settings::save_macro(path, &text, &keys),
+                    None => Err(Error::Config("There's no settings file to save to".into())),
+                };
+                match saved {
+                    Ok(()) => {
+                        self.prompt.log_error(format!("Saved macro `{}`", text));
+                        self.settings.macros.insert(text, keys);
+                    }
+                    Err(error) => self.prompt.log_error(format!("{}", error)),
+                }
+            }
+            Input::GotoLine => match files::Position::parse(&text) {
+                Some(position) => self.goto_position(position, frame),
+                None => self
+                    .prompt
+                    .log_error(format!("`{}` isn't a line or line:column", text)),
+            },
+        }
         Ok(false)
     }
 
+    fn goto_position(&mut self, position: files::Position, frame: Rect) {
+        match self.focus {
+            Some(id)
+                if self
+                    .components
+                    .get_or_default::<Buffers>()
+                    .contains_key(&id) =>
+            {
+                self.run_buffer_action(
+                    id,
+                    buffer::Action::Sync(buffer::SyncAction::GotoPosition(position)),
+                    PrefixArg::None,
+                    frame,
+                )
+            }
+            _ => self.prompt.log_error("No buffer is focused".into()),
+        }
+    }
+
+    fn play_last_macro(&mut self, count: usize, frame: Rect) -> Result<bool> {
+        match self.last_macro.clone() {
+            Some(keys) => self.play_macro(&keys, count, frame),
+            None => {
+                self.prompt.log_error("No macro was recorded".into());
+                Ok(false)
+            }
+        }
+    }
+
+    /// Feeds the keys of a macro through `handle_event` `count` times, at most
+    /// `MAX_REPEAT_COUNT`. The edits made to each buffer are undone together.
+    /// Returns whether the editor should exit.
+    fn play_macro(&mut self, keys: &[Key], count: usize, frame: Rect) -> Result<bool> {
+        if self.playing_macro {
+            return Ok(false);
+        }
+        if self.recording_macro.is_some() {
+            self.prompt
+                .log_error("Can't play a macro while recording one".into());
+            return Ok(false);
+        }
+
+        self.play_keys(keys, count.min(MAX_REPEAT_COUNT), frame)
+    }
+
+    /// Handles `keys` again `count` times, to play a macro or repeat a vi
+    /// change. The keys aren't recorded in a macro.
+    fn play_keys(&mut self, keys: &[Key], count: usize, frame: Rect) -> Result<bool> {
+        let was_playing = mem::replace(&mut self.playing_macro, true);
+        self.controller.clear();
+        if !was_playing {
+            for buffer in self.components.get_or_default::<Buffers>().values_mut() {
+                buffer.begin_undo_group();
+            }
+        }
+        let mut result = Ok(false);
+        'playing: for _ in 0..count {
+            for key in keys {
+                result = self.handle_event(*key, frame);
+                if !matches!(result, Ok(false)) {
+                    break 'playing;
+                }
+            }
+        }
+        if !was_playing {
+            for buffer in self.components.get_or_default::<Buffers>().values_mut() {
+                buffer.end_undo_group();
+            }
+        }
+        self.controller.clear();
+        self.pending_prefix_since = None;
+        self.playing_macro = was_playing;
+        result
+    }
+
+    /// Runs an action of the focused buffer on behalf of the user, asking
+    /// before saving over a file which changed on disk
+    fn run_buffer_action(
+        &mut self,
+        id: ComponentId,
+        action: buffer::Action,
+        prefix_arg: PrefixArg,
+        frame: Rect,
+    ) {
+        self.lay_components(frame);
+        if let buffer::Action::Sync(buffer::SyncAction::SaveBuffer) = action {
+            self.save_buffer(id, prefix_arg);
+        } else if let Err(error) = self.reduce_buffer(id, action, prefix_arg) {
+            self.prompt.log_error(format!("{}", error));
+        }
+    }
+
+    /// Saves a buffer, first asking whether to overwrite its file if that
+    /// changed on disk since it was read. Returns whether the save started.
+    /// With `C-u` the text is saved as is.
+    fn save_buffer(&mut self, id: ComponentId, prefix_arg: PrefixArg) -> bool {
+        if self.refuse_read_only(id) {
+            return false;
+        }
+        let Self {
+            ref mut components,
+            ref current_path,
+            ..
+        } = *self;
+        let path = components
+            .get_or_default::<Buffers>()
+            .get(&id)
+            .filter(|buffer| buffer.changed_on_disk())
+            .map(|buffer| buffer.path().unwrap_or(current_path).to_path_buf());
+        if let Some(path) = path {
+            self.prompt.ask(
+                Question::OverwriteFile(id, prefix_arg),
+                format!(
+                    "{} changed on disk since it was read. Overwrite it?",
+                    path.display()
+                ),
+                &[Answer::Yes, Answer::No],
+            );
+            return false;
+        }
+        match self.reduce_buffer(
+            id,
+            buffer::Action::Sync(buffer::SyncAction::SaveBuffer),
+            prefix_arg,
+        ) {
+            Ok(()) => true,
+            Err(error) => {
+                self.prompt.log_error(format!("{}", error));
+                false
+            }
+        }
+    }
+
+    fn reduce_prompt(&mut self, action: prompt::Action, frame: Rect) -> Result<()> {
+        let Self {
+            ref mut prompt,
+            ref mut task_owners,
+            ref current_path,
+            ref task_pool,
+            ref themes,
+            ref settings,
+            theme_index,
+            ..
+        } = *self;
+        let mut scheduler = task_pool.scheduler();
+        prompt.reduce(
+            action,
+            &mut scheduler,
+            &Context {
+                time: Instant::now(),
+                focused: false,
+                frame,
+                frame_id: 0,
+                theme: &themes[theme_index].0,
+                path: current_path.as_path(),
+                settings,
+                prefix_arg: PrefixArg::None,
+                vi_mode: None,
+            },
+        )?;
+        for task_id in scheduler.scheduled() {
+            task_owners.insert(task_id, PROMPT_ID);
+        }
+        Ok(())
+    }
+
     #[inline]
     fn lay_components(&mut self, frame: Rect) {
         let mut layout = Layout::Component(PROMPT_ID);
@@ -611,15 +2197,38 @@ const SPLASH_ID: ComponentId = 1;
 
 const REDRAW_LATENCY: Duration = Duration::from_millis(10);
 const SUSTAINED_IO_REDRAW_LATENCY: Duration = Duration::from_millis(100);
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const KEY_HINTS_DELAY: Duration = Duration::from_millis(800);
+
+fn vi_for(settings: &Settings) -> Option<Vi> {
+    match settings.keymap {
+        KeymapStyle::Emacs => None,
+        KeymapStyle::Vi => Some(Vi::new()),
+    }
+}
+
+#[derive(Default)]
+struct MacroRecording {
+    keys: Vec<Key>,
+    // Where the key sequence being pressed started
+    sequence_start: usize,
+}
 
 struct InputController {
     keys: SmallVec<[Key; 8]>,
+    prefix_arg: PrefixArg,
+    // Set after `C-u` or `A-<digit>`, while digits are read as part of the
+    // prefix argument rather than inserted
+    reading_digits: bool,
 }
 
 impl InputController {
     fn new() -> Self {
         Self {
             keys: SmallVec::new(),
+            prefix_arg: PrefixArg::None,
+            reading_digits: false,
         }
     }
 
@@ -628,6 +2237,52 @@ impl InputController {
         log::info!("keys: {:?}", self.keys);
     }
 
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.prefix_arg = PrefixArg::None;
+        self.reading_digits = false;
+    }
+
+    fn universal_argument(&mut self, prefix_arg: PrefixArg) {
+        self.prefix_arg = match prefix_arg {
+            PrefixArg::Universal(times) => PrefixArg::Universal(times.saturating_add(1)),
+            _ => PrefixArg::Universal(1),
+        };
+        self.reading_digits = true;
+    }
+
+    /// Adds the last key to the prefix argument if it's `A-<digit>`, or a
+    /// digit following `C-u`. Returns whether it was.
+    fn take_digit_argument(&mut self) -> bool {
+        let digit = match self.keys.as_slice() {
+            [Key::Alt(character)] => character.to_digit(10),
+            [Key::Char(character)] if self.reading_digits => character.to_digit(10),
+            _ => None,
+        };
+        match digit {
+            Some(digit) => {
+                let number = match self.prefix_arg {
+                    PrefixArg::Number(number) => number,
+                    _ => 0,
+                };
+                self.prefix_arg =
+                    PrefixArg::Number(number.saturating_mul(10).saturating_add(digit as usize));
+                self.reading_digits = true;
+                self.keys.clear();
+                true
+            }
+            None => {
+                self.reading_digits = false;
+                false
+            }
+        }
+    }
+
+    fn take_prefix_arg(&mut self) -> PrefixArg {
+        self.reading_digits = false;
+        mem::take(&mut self.prefix_arg)
+    }
+
     fn matches<Action>(&mut self, bindings: &impl Bindings<Action>) -> BindingMatch<Action> {
         let binding_match = bindings.matches(&self.keys);
         if let BindingMatch::Full(_) = binding_match {
@@ -639,18 +2294,17 @@ impl InputController {
 
 impl std::fmt::Display for InputController {
     fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
-        for key in self.keys.iter() {
-            match key {
-                Key::Char(' ') => write!(formatter, "SPC ")?,
-                Key::Char('\n') => write!(formatter, "RET ")?,
-                Key::Char('\t') => write!(formatter, "TAB ")?,
-                Key::Char(char) => write!(formatter, "{} ", char)?,
-                Key::Ctrl(char) => write!(formatter, "C-{} ", char)?,
-                Key::Alt(char) => write!(formatter, "A-{} ", char)?,
-                Key::F(number) => write!(formatter, "F{} ", number)?,
-                Key::Esc => write!(formatter, "ESC ")?,
-                key => write!(formatter, "{:?} ", key)?,
+        match self.prefix_arg {
+            PrefixArg::None => {}
+            PrefixArg::Universal(times) => {
+                for _ in 0..times {
+                    write!(formatter, "C-u ")?;
+                }
             }
+            PrefixArg::Number(number) => write!(formatter, "C-u {} ", number)?,
+        }
+        for key in self.keys.iter() {
+            write!(formatter, "{} ", keymap::format_key(key))?;
         }
         Ok(())
     }
@@ -0,0 +1,567 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    encoding::{Encoding, LineEnding},
+    indentation::{IndentStyle, Indentation},
+};
+
+/// The EditorConfig properties which apply to a file, see
+/// https://editorconfig.org. Keys and values are lowercased as the
+/// specification requires.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties(HashMap<String, String>);
+
+impl Properties {
+    pub fn trim_trailing_whitespace(&self) -> Option<bool> {
+        self.get_bool("trim_trailing_whitespace")
+    }
+
+    pub fn insert_final_newline(&self) -> Option<bool> {
+        self.get_bool("insert_final_newline")
+    }
+
+    /// Overrides the parts of `indentation` which are set. As the
+    /// specification requires, `indent_size = tab` means the tab width and
+    /// each of `indent_size` and `tab_width` defaults to the other.
+    pub fn apply_indentation(&self, indentation: &mut Indentation) {
+        match self.get("indent_style") {
+            Some("tab") => indentation.style = IndentStyle::Tabs,
+            Some("space") => indentation.style = IndentStyle::Spaces,
+            _ => {}
+        }
+
+        let tab_width = self.get_number("tab_width");
+        let indent_size = match self.get("indent_size") {
+            Some("tab") => tab_width.or(Some(indentation.tab_width)),
+            _ => self.get_number("indent_size"),
+        };
+        match (indent_size, tab_width) {
+            (Some(indent_size), Some(tab_width)) => {
+                indentation.size = indent_size;
+                indentation.tab_width = tab_width;
+            }
+            (Some(size), None) | (None, Some(size)) => {
+                indentation.size = size;
+                indentation.tab_width = size;
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// `cr` line endings aren't supported and are ignored
+    pub fn end_of_line(&self) -> Option<LineEnding> {
+        match self.get("end_of_line")? {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::CrLf),
+            _ => None,
+        }
+    }
+
+    pub fn charset(&self) -> Option<Encoding> {
+        match self.get("charset")? {
+            "utf-8" => Some(Encoding::Utf8),
+            "utf-8-bom" => Some(Encoding::Utf8Bom),
+            "utf-16le" => Some(Encoding::Utf16Le),
+            "utf-16be" => Some(Encoding::Utf16Be),
+            "latin1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    /// `off` is the same as not set
+    pub fn max_line_length(&self) -> Option<usize> {
+        self.get_number("max_line_length")
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(key)
+            .map(String::as_str)
+            .filter(|value| *value != "unset")
+    }
+
+    fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    fn get_number(&self, key: &str) -> Option<usize> {
+        self.get(key)?.parse().ok().filter(|number| *number > 0)
+    }
+}
+
+/// Looks up the properties for a file in the `.editorconfig` files of its
+/// directory and all parent directories up to one marked with `root = true`.
+/// Files closer to the path take precedence. Unreadable files are skipped.
+pub fn properties(path: impl AsRef<Path>) -> Properties {
+    let path = absolute_path(path.as_ref());
+    let mut config_files = Vec::new();
+    for directory in path.ancestors().skip(1) {
+        let config_path = directory.join(EDITORCONFIG_FILE_NAME);
+        if !config_path.is_file() {
+            continue;
+        }
+        match fs::read_to_string(&config_path) {
+            Ok(contents) => {
+                let config_file = ConfigFile::parse(&contents);
+                let is_root = config_file.root;
+                config_files.push((directory, config_file));
+                if is_root {
+                    break;
+                }
+            }
+            Err(error) => log::error!("Could not read `{}`: {}", config_path.display(), error),
+        }
+    }
+
+    let mut properties = Properties::default();
+    for (directory, config_file) in config_files.iter().rev() {
+        config_file.apply(directory, &path, &mut properties);
+    }
+    properties
+}
+
+/// Whether a file matches a glob with the same syntax as section names. Globs
+/// without a `/` match the file name, globs starting with one the absolute
+/// path and other globs the path relative to the working directory.
+pub fn glob_matches(glob: &str, path: impl AsRef<Path>) -> bool {
+    let path = absolute_path(path.as_ref());
+    let path = if glob.contains('/') && !glob.starts_with('/') {
+        let current_dir = match std::env::current_dir() {
+            Ok(current_dir) => current_dir,
+            Err(_) => return false,
+        };
+        let relative_path = path.strip_prefix(&current_dir).or_else(|_| {
+            current_dir
+                .canonicalize()
+                .map_err(|_| ())
+                .and_then(|current_dir| path.strip_prefix(current_dir).map_err(|_| ()))
+        });
+        match relative_path {
+            Ok(relative_path) => Path::new("/").join(relative_path),
+            Err(_) => return false,
+        }
+    } else {
+        path
+    };
+    let text: Vec<char> = path.to_string_lossy().chars().collect();
+    parse_glob(glob)
+        .iter()
+        .any(|pattern| matches(pattern, &text))
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    let path = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) if !parent.as_os_str().is_empty() => parent
+            .canonicalize()
+            .map(|parent| parent.join(file_name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    };
+    if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir()
+            .map(|current_dir| current_dir.join(&path))
+            .unwrap_or(path)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConfigFile {
+    root: bool,
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    /// All expansions of the glob's braces
+    patterns: Vec<Vec<Token>>,
+    properties: Vec<(String, String)>,
+}
+
+impl ConfigFile {
+    fn parse(contents: &str) -> Self {
+        let mut config_file = ConfigFile::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                config_file.sections.push(Section {
+                    patterns: parse_glob(&line[1..line.len() - 1]),
+                    properties: Vec::new(),
+                });
+            } else if let Some(separator) = line.find('=') {
+                let key = line[..separator].trim().to_lowercase();
+                let value = line[separator + 1..].trim().to_lowercase();
+                match config_file.sections.last_mut() {
+                    Some(section) => section.properties.push((key, value)),
+                    None if key == "root" => config_file.root = value == "true",
+                    None => {}
+                }
+            }
+        }
+        config_file
+    }
+
+    /// Sets the properties of all sections matching `path`, which is relative
+    /// to `directory`. Later sections take precedence.
+    fn apply(&self, directory: &Path, path: &Path, properties: &mut Properties) {
+        let relative_path = match path.strip_prefix(directory) {
+            Ok(relative_path) => relative_path,
+            Err(_) => return,
+        };
+        let mut text = vec!['/'];
+        for (index, component) in relative_path.iter().enumerate() {
+            if index > 0 {
+                text.push('/');
+            }
+            text.extend(component.to_string_lossy().chars());
+        }
+
+        for section in self.sections.iter() {
+            if section
+                .patterns
+                .iter()
+                .any(|pattern| matches(pattern, &text))
+            {
+                for (key, value) in section.properties.iter() {
+                    properties.0.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Char(char),
+    /// `?`, any character except `/`
+    AnyChar,
+    /// `*`, any string without a `/`
+    AnyChars,
+    /// `**`, any string
+    AnyPath,
+    /// `[a-z]` or `[!a-z]`
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    /// `{1..10}`, an integer in the range
+    Number(i64, i64),
+}
+
+/// Parses a section name into patterns matched against paths which start
+/// with `/`. Globs without a `/` match files in any subdirectory.
+fn parse_glob(glob: &str) -> Vec<Vec<Token>> {
+    let glob: Vec<char> = glob.chars().collect();
+    let prefix: &[char] = if !glob.contains(&'/') {
+        &['*', '*', '/']
+    } else if glob.first() == Some(&'/') {
+        &[]
+    } else {
+        &['/']
+    };
+    let glob: Vec<char> = prefix.iter().chain(glob.iter()).copied().collect();
+    parse_sequence(&glob)
+}
+
+fn parse_sequence(glob: &[char]) -> Vec<Vec<Token>> {
+    let mut expansions = vec![Vec::new()];
+    let push = |expansions: &mut Vec<Vec<Token>>, token: Token| {
+        for expansion in expansions.iter_mut() {
+            expansion.push(token.clone());
+        }
+    };
+
+    let mut index = 0;
+    while index < glob.len() {
+        match glob[index] {
+            '\\' if index + 1 < glob.len() => {
+                push(&mut expansions, Token::Char(glob[index + 1]));
+                index += 2;
+                continue;
+            }
+            '*' if glob.get(index + 1) == Some(&'*') => {
+                push(&mut expansions, Token::AnyPath);
+                index += 2;
+                continue;
+            }
+            '*' => push(&mut expansions, Token::AnyChars),
+            '?' => push(&mut expansions, Token::AnyChar),
+            '[' => {
+                if let Some((token, end)) = parse_class(glob, index) {
+                    push(&mut expansions, token);
+                    index = end + 1;
+                    continue;
+                }
+                push(&mut expansions, Token::Char('['));
+            }
+            '{' => {
+                if let Some(end) = find_closing_brace(glob, index) {
+                    let contents = &glob[index + 1..end];
+                    if let Some(token) = parse_number_range(contents) {
+                        push(&mut expansions, token);
+                        index = end + 1;
+                        continue;
+                    }
+                    let alternatives = split_alternatives(contents);
+                    if alternatives.len() > 1 {
+                        let alternatives: Vec<_> =
+                            alternatives.into_iter().flat_map(parse_sequence).collect();
+                        expansions = expansions
+                            .iter()
+                            .flat_map(|expansion| {
+                                alternatives.iter().map(move |alternative| {
+                                    expansion.iter().chain(alternative).cloned().collect()
+                                })
+                            })
+                            .collect();
+                        index = end + 1;
+                        continue;
+                    }
+                }
+                push(&mut expansions, Token::Char('{'));
+            }
+            character => push(&mut expansions, Token::Char(character)),
+        }
+        index += 1;
+    }
+    expansions
+}
+
+fn parse_class(glob: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut index = start + 1;
+    let negated = match glob.get(index) {
+        Some('!') | Some('^') => {
+            index += 1;
+            true
+        }
+        _ => false,
+    };
+    let mut ranges = Vec::new();
+    while index < glob.len() {
+        let character = match glob[index] {
+            ']' if !ranges.is_empty() => return Some((Token::Class { negated, ranges }, index)),
+            '/' => return None,
+            '\\' if index + 1 < glob.len() => {
+                index += 1;
+                glob[index]
+            }
+            character => character,
+        };
+        if glob.get(index + 1) == Some(&'-') && glob.get(index + 2).map_or(false, |c| *c != ']') {
+            ranges.push((character, glob[index + 2]));
+            index += 3;
+        } else {
+            ranges.push((character, character));
+            index += 1;
+        }
+    }
+    None
+}
+
+fn find_closing_brace(glob: &[char], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut index = start;
+    while index < glob.len() {
+        match glob[index] {
+            '\\' => index += 1,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+/// Splits the contents of braces at the commas which aren't nested
+fn split_alternatives(contents: &[char]) -> Vec<&[char]> {
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut index = 0;
+    while index < contents.len() {
+        match contents[index] {
+            '\\' => index += 1,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                alternatives.push(&contents[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    alternatives.push(&contents[start..]);
+    alternatives
+}
+
+fn parse_number_range(contents: &[char]) -> Option<Token> {
+    let contents: String = contents.iter().collect();
+    let separator = contents.find("..")?;
+    let start = contents[..separator].parse().ok()?;
+    let end = contents[separator + 2..].parse().ok()?;
+    Some(Token::Number(start, end))
+}
+
+fn matches(pattern: &[Token], text: &[char]) -> bool {
+    let (token, rest) = match pattern.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+    match *token {
+        Token::Char(expected) => text.first() == Some(&expected) && matches(rest, &text[1..]),
+        Token::AnyChar => text.first().map_or(false, |c| *c != '/') && matches(rest, &text[1..]),
+        Token::AnyChars => {
+            for index in 0..=text.len() {
+                if matches(rest, &text[index..]) {
+                    return true;
+                }
+                if text.get(index) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Token::AnyPath => (0..=text.len()).any(|index| matches(rest, &text[index..])),
+        Token::Class {
+            negated,
+            ref ranges,
+        } => match text.first() {
+            Some('/') | None => false,
+            Some(character) => {
+                ranges
+                    .iter()
+                    .any(|(start, end)| start <= character && character <= end)
+                    != negated
+                    && matches(rest, &text[1..])
+            }
+        },
+        Token::Number(start, end) => {
+            let sign = if text.first() == Some(&'-') { 1 } else { 0 };
+            let num_digits = text[sign..]
+                .iter()
+                .take_while(|character| character.is_ascii_digit())
+                .count();
+            (sign + 1..=sign + num_digits).any(|length| {
+                let number: String = text[..length].iter().collect();
+                number.parse::<i64>().map_or(false, |number| {
+                    in_range(start, end, number) && matches(rest, &text[length..])
+                })
+            })
+        }
+    }
+}
+
+fn in_range(start: i64, end: i64, number: i64) -> bool {
+    start.min(end) <= number && number <= start.max(end)
+}
+
+const EDITORCONFIG_FILE_NAME: &str = ".editorconfig";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_like_editorconfig() {
+        assert!(glob_matches("*", "/README.md"));
+        assert!(glob_matches("*.md", "/docs/guide.md"));
+        assert!(!glob_matches("*.md", "/docs/guide.rs"));
+        assert!(glob_matches("/docs/*.md", "/docs/guide.md"));
+        // Relative to the working directory
+        assert!(!glob_matches("docs/*.md", "docs/nested/guide.md"));
+        assert!(glob_matches("docs/**.md", "docs/nested/guide.md"));
+        assert!(!glob_matches("docs/**.md", "/docs/nested/guide.md"));
+        assert!(glob_matches("*.{js,ts}", "/src/index.ts"));
+        assert!(glob_matches("{package.json,.travis.yml}", "/.travis.yml"));
+        assert!(glob_matches("file[0-9].txt", "/file7.txt"));
+        assert!(!glob_matches("file[!0-9].txt", "/file7.txt"));
+        assert!(glob_matches("test{1..12}.rs", "/test10.rs"));
+        assert!(!glob_matches("test{1..12}.rs", "/test13.rs"));
+        assert!(glob_matches("a?c", "/abc"));
+        assert!(glob_matches("{literal}", "/{literal}"));
+    }
+
+    #[test]
+    fn later_sections_and_closer_files_take_precedence() {
+        let root = ConfigFile::parse(
+            "root = true\n\
+             [*]\n\
+             trim_trailing_whitespace = true\n\
+             insert_final_newline = true\n\
+             [*.md]\n\
+             trim_trailing_whitespace = false\n",
+        );
+        let nested = ConfigFile::parse("[*.md]\nINSERT_FINAL_NEWLINE = False\n");
+        assert!(root.root);
+        assert!(!nested.root);
+
+        let path = Path::new("/project/docs/guide.md");
+        let mut properties = Properties::default();
+        root.apply(Path::new("/project"), path, &mut properties);
+        nested.apply(Path::new("/project/docs"), path, &mut properties);
+        assert_eq!(properties.trim_trailing_whitespace(), Some(false));
+        assert_eq!(properties.insert_final_newline(), Some(false));
+
+        let mut properties = Properties::default();
+        root.apply(
+            Path::new("/project"),
+            Path::new("/project/main.rs"),
+            &mut properties,
+        );
+        assert_eq!(properties.trim_trailing_whitespace(), Some(true));
+        assert_eq!(properties.insert_final_newline(), Some(true));
+    }
+
+    fn properties_for(contents: &str) -> Properties {
+        let mut properties = Properties::default();
+        ConfigFile::parse(contents).apply(
+            Path::new("/project"),
+            Path::new("/project/main.c"),
+            &mut properties,
+        );
+        properties
+    }
+
+    #[test]
+    fn indentation_and_file_format() {
+        let mut indentation = Indentation::default();
+        properties_for("[*]\nindent_style = space\nindent_size = 2\n")
+            .apply_indentation(&mut indentation);
+        assert_eq!(indentation.style, IndentStyle::Spaces);
+        assert_eq!((indentation.size, indentation.tab_width), (2, 2));
+
+        let mut indentation = Indentation::default();
+        properties_for("[*]\nindent_style = tab\nindent_size = tab\ntab_width = 8\n")
+            .apply_indentation(&mut indentation);
+        assert_eq!(indentation.style, IndentStyle::Tabs);
+        assert_eq!((indentation.size, indentation.tab_width), (8, 8));
+
+        let properties =
+            properties_for("[*]\nend_of_line = CRLF\ncharset = utf-8-bom\nmax_line_length = 100\n");
+        assert_eq!(properties.end_of_line(), Some(LineEnding::CrLf));
+        assert_eq!(properties.charset(), Some(Encoding::Utf8Bom));
+        assert_eq!(properties.max_line_length(), Some(100));
+
+        let properties = properties_for("[*]\nend_of_line = cr\nmax_line_length = off\n");
+        assert_eq!(properties.end_of_line(), None);
+        assert_eq!(properties.max_line_length(), None);
+    }
+}
@@ -0,0 +1,246 @@
+use encoding_rs::{UTF_16BE, UTF_16LE, WINDOWS_1252};
+use ropey::Rope;
+use std::io::{self, Write};
+
+/// How a file is stored on disk. Buffers always hold UTF-8 text with `\n`
+/// line endings, the original format is restored when saving.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Decoded as windows-1252, the superset of ISO-8859-1 Windows tools
+    /// actually write
+    Latin1,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "UTF-8",
+            Self::Utf8Bom => "UTF-8 BOM",
+            Self::Utf16Le => "UTF-16LE",
+            Self::Utf16Be => "UTF-16BE",
+            Self::Latin1 => "Latin-1",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Utf8 => Self::Utf8Bom,
+            Self::Utf8Bom => Self::Utf16Le,
+            Self::Utf16Le => Self::Utf16Be,
+            Self::Utf16Be => Self::Latin1,
+            Self::Latin1 => Self::Utf8,
+        }
+    }
+
+    fn bom(self) -> &'static [u8] {
+        match self {
+            Self::Utf8Bom => UTF8_BOM,
+            Self::Utf16Le => UTF16LE_BOM,
+            Self::Utf16Be => UTF16BE_BOM,
+            Self::Utf8 | Self::Latin1 => &[],
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Utf8
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Lf => Self::CrLf,
+            Self::CrLf => Self::Lf,
+        }
+    }
+}
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        Self::Lf
+    }
+}
+
+/// Detects the encoding and dominant line ending of a file's contents and
+/// returns the normalised text. Files which aren't valid in the encoding of
+/// their BOM, or valid UTF-8 without one, are assumed to be Latin-1, which
+/// never fails to decode. Saving them again keeps their bytes.
+pub fn decode(bytes: &[u8]) -> (Rope, FileFormat) {
+    let decoded = if bytes.starts_with(UTF8_BOM) {
+        std::str::from_utf8(&bytes[UTF8_BOM.len()..])
+            .ok()
+            .map(|text| (text.to_owned(), Encoding::Utf8Bom))
+    } else if bytes.starts_with(UTF16LE_BOM) {
+        decode_utf16(UTF_16LE, &bytes[UTF16LE_BOM.len()..]).map(|text| (text, Encoding::Utf16Le))
+    } else if bytes.starts_with(UTF16BE_BOM) {
+        decode_utf16(UTF_16BE, &bytes[UTF16BE_BOM.len()..]).map(|text| (text, Encoding::Utf16Be))
+    } else {
+        std::str::from_utf8(bytes)
+            .ok()
+            .map(|text| (text.to_owned(), Encoding::Utf8))
+    };
+    let (text, encoding) = decoded.unwrap_or_else(|| {
+        (
+            WINDOWS_1252
+                .decode_without_bom_handling(bytes)
+                .0
+                .into_owned(),
+            Encoding::Latin1,
+        )
+    });
+
+    let num_crlf = text.matches("\r\n").count();
+    let num_lf = text.matches('\n').count() - num_crlf;
+    let (text, line_ending) = if num_crlf > num_lf {
+        (text.replace("\r\n", "\n"), LineEnding::CrLf)
+    } else {
+        (text, LineEnding::Lf)
+    };
+
+    (
+        Rope::from(text),
+        FileFormat {
+            encoding,
+            line_ending,
+        },
+    )
+}
+
+/// Writes the text back in its original format. Fails rather than write a
+/// lossy file if a character can't be represented in the encoding.
+pub fn encode(text: &Rope, format: FileFormat, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(format.encoding.bom())?;
+    for chunk in text.chunks() {
+        let chunk = match format.line_ending {
+            LineEnding::Lf => chunk.into(),
+            LineEnding::CrLf => chunk.replace('\n', "\r\n"),
+        };
+        match format.encoding {
+            Encoding::Utf8 | Encoding::Utf8Bom => writer.write_all(chunk.as_bytes())?,
+            Encoding::Utf16Le => {
+                for unit in chunk.encode_utf16() {
+                    writer.write_all(&unit.to_le_bytes())?;
+                }
+            }
+            Encoding::Utf16Be => {
+                for unit in chunk.encode_utf16() {
+                    writer.write_all(&unit.to_be_bytes())?;
+                }
+            }
+            Encoding::Latin1 => {
+                let (bytes, _, had_unmappable) = WINDOWS_1252.encode(&chunk);
+                if had_unmappable {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The text contains characters which can't be saved as Latin-1",
+                    ));
+                }
+                writer.write_all(&bytes)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `None` if `bytes` aren't valid UTF-16, e.g. there's an unpaired surrogate
+fn decode_utf16(encoding: &'static encoding_rs::Encoding, bytes: &[u8]) -> Option<String> {
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    if had_errors {
+        None
+    } else {
+        Some(text.into_owned())
+    }
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(bytes: &[u8]) -> (String, FileFormat) {
+        let (text, format) = decode(bytes);
+        let mut encoded = Vec::new();
+        encode(&text, format, &mut encoded).unwrap();
+        assert_eq!(encoded, bytes);
+        (text.to_string(), format)
+    }
+
+    #[test]
+    fn detects_utf8_and_line_endings() {
+        let (text, format) = roundtrip(b"fn main() {\r\n}\r\n");
+        assert_eq!(text, "fn main() {\n}\n");
+        assert_eq!(format.encoding, Encoding::Utf8);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+
+        let (text, format) = roundtrip("\u{feff}caf\u{e9}\n".as_bytes());
+        assert_eq!(text, "caf\u{e9}\n");
+        assert_eq!(format.encoding, Encoding::Utf8Bom);
+        assert_eq!(format.line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn detects_utf16_and_latin1() {
+        let (text, format) = roundtrip(&[0xFF, 0xFE, b'h', 0, b'i', 0, b'\r', 0, b'\n', 0]);
+        assert_eq!(text, "hi\n");
+        assert_eq!(format.encoding, Encoding::Utf16Le);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+
+        let (text, format) = roundtrip(&[0xFE, 0xFF, 0, b'h', 0, b'i']);
+        assert_eq!(text, "hi");
+        assert_eq!(format.encoding, Encoding::Utf16Be);
+
+        let (text, format) = roundtrip(b"caf\xe9 \x93quoted\x94\n");
+        assert_eq!(text, "caf\u{e9} \u{201c}quoted\u{201d}\n");
+        assert_eq!(format.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn invalid_text_after_a_bom_is_latin1() {
+        let (text, format) = roundtrip(b"\xef\xbb\xbfcaf\xe9\n");
+        assert_eq!(text, "\u{ef}\u{bb}\u{bf}caf\u{e9}\n");
+        assert_eq!(format.encoding, Encoding::Latin1);
+
+        // An unpaired surrogate
+        let (_, format) = roundtrip(&[0xFF, 0xFE, 0x00, 0xD8, b'h', 0]);
+        assert_eq!(format.encoding, Encoding::Latin1);
+        let (_, format) = roundtrip(&[0xFE, 0xFF, 0, b'h', 0xDC, 0x00]);
+        assert_eq!(format.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn encode_refuses_unrepresentable_characters() {
+        let format = FileFormat {
+            encoding: Encoding::Latin1,
+            line_ending: LineEnding::Lf,
+        };
+        assert!(encode(&Rope::from("\u{3bb}"), format, &mut Vec::new()).is_err());
+    }
+}
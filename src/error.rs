@@ -1,5 +1,5 @@
 use ignore;
-use std::io;
+use std::{io, path::PathBuf};
 use thiserror::Error;
 use tree_sitter::LanguageError;
 
@@ -21,6 +21,27 @@ pub enum Error {
     #[error("{0}")]
     FilePicker(#[from] ignore::Error),
 
+    #[error("{} was modified on disk, saving will ask before overwriting it", .0.display())]
+    FileChangedOnDisk(PathBuf),
+
+    #[error("{} was deleted on disk", .0.display())]
+    FileDeletedOnDisk(PathBuf),
+
+    #[error("buffer isn't backed by a file, use C-x C-w to save it")]
+    UnnamedBuffer,
+
+    #[error("buffer is read only")]
+    ReadOnlyBuffer,
+
+    #[error("{0}")]
+    Server(String),
+
+    #[error("script error: {0}")]
+    Script(String),
+
+    #[error("formatting with `{0}` failed: {1}")]
+    FormatOnSave(String, String),
+
     #[error("task error: {0}")]
     TaskPool(Box<dyn std::error::Error + Send>),
 
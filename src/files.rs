@@ -0,0 +1,321 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Writes a file without ever leaving it half written. The contents go to a
+/// temporary file in the same directory which is then renamed over the
+/// original. Symlinks are followed, so the file they point to is replaced
+/// rather than the link itself, and the original's permissions and (on unix)
+/// ownership are kept.
+///
+/// If `backup` is set, the previous version of the file is copied to `file~`
+/// before it's replaced.
+pub fn write_atomically(
+    path: impl AsRef<Path>,
+    backup: bool,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let path = resolve_symlinks(path.as_ref())?;
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error),
+    };
+
+    let temp_path = temp_path(&path)?;
+    let result = write_temp_file(&temp_path, metadata.as_ref(), write).and_then(|_| {
+        if backup && metadata.is_some() {
+            fs::copy(&path, backup_path(&path)?)?;
+        }
+        fs::rename(&temp_path, &path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+
+    // Make sure the rename itself is persisted, not fatal if it can't be
+    if let Some(parent) = path.parent() {
+        let _ = File::open(parent).and_then(|directory| directory.sync_all());
+    }
+    Ok(())
+}
+
+/// The backup of a file, named Emacs style, e.g. `src/main.rs~`
+pub fn backup_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    let mut file_name = file_name(path)?;
+    file_name.push("~");
+    Ok(path.with_file_name(file_name))
+}
+
+fn write_temp_file(
+    temp_path: &Path,
+    metadata: Option<&fs::Metadata>,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        if let Some(metadata) = metadata {
+            options.mode(metadata.permissions().mode());
+        }
+    }
+
+    let mut writer = BufWriter::new(options.open(temp_path)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    let file = writer.into_inner().map_err(|error| error.into_error())?;
+
+    if let Some(metadata) = metadata {
+        // The mode passed when creating the file is subject to the umask
+        file.set_permissions(metadata.permissions())?;
+        preserve_ownership(&file, metadata)?;
+    }
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn preserve_ownership(file: &File, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::{fs::MetadataExt, io::AsRawFd};
+
+    let temp_metadata = file.metadata()?;
+    if temp_metadata.uid() == metadata.uid() && temp_metadata.gid() == metadata.gid() {
+        return Ok(());
+    }
+    // Only root can give away files, but we can usually still set the group.
+    // Failing that, the file ends up owned by us, same as any other editor.
+    for (uid, gid) in &[(metadata.uid(), metadata.gid()), (u32::MAX, metadata.gid())] {
+        if unsafe { libc::fchown(file.as_raw_fd(), *uid, *gid) } == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn preserve_ownership(_file: &File, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// A line and column in a file, both counted from 1 like compilers print them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Parses `line` or `line:column`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.trim().splitn(2, ':');
+        let line = parts.next()?.parse().ok()?;
+        let column = match parts.next() {
+            Some(column) => column.parse().ok()?,
+            None => 1,
+        };
+        Some(Self { line, column })
+    }
+}
+
+/// Resolves a relative path from the directory of `current_path`, which is
+/// either the focused file or, with none, the working directory
+pub fn relative_to(current_path: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let dir = if current_path.is_dir() {
+        current_path
+    } else {
+        current_path.parent().unwrap_or(current_path)
+    };
+    dir.join(path)
+}
+
+/// Splits a trailing `:line` or `:line:column` off a path, as in
+/// `src/main.rs:10:5:` copied from compiler output. A path which exists as
+/// given is left alone, even if it ends in digits after a colon.
+pub fn split_location(location: impl AsRef<Path>) -> (PathBuf, Option<Position>) {
+    let path = location.as_ref();
+    let text = match path.to_str() {
+        Some(text) if !path.exists() => text.trim_end_matches(':'),
+        _ => return (path.to_path_buf(), None),
+    };
+
+    let mut rest = text;
+    let mut numbers = Vec::with_capacity(2);
+    while numbers.len() < 2 {
+        match rest.rfind(':') {
+            Some(index) if index > 0 => match rest[index + 1..].parse::<usize>() {
+                Ok(number) => {
+                    numbers.push(number);
+                    rest = &rest[..index];
+                }
+                Err(_) => break,
+            },
+            _ => break,
+        }
+    }
+    let position = match numbers[..] {
+        [line] => Position { line, column: 1 },
+        [column, line] => Position { line, column },
+        _ => return (path.to_path_buf(), None),
+    };
+    (PathBuf::from(rest), Some(position))
+}
+
+/// Follows symlinks until reaching a path which isn't one. Unlike
+/// `fs::canonicalize`, the final target doesn't have to exist.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) if target.is_relative() => parent.join(target),
+                    _ => target,
+                };
+            }
+            Ok(_) => return Ok(path),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Too many levels of symbolic links: {}", path.display()),
+    ))
+}
+
+/// A name next to `path` which no other save, in this or another process,
+/// is writing to
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    static SAVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name(path)?);
+    temp_name.push(format!(
+        ".{}-{}.zee-tmp",
+        process::id(),
+        SAVE_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(path.with_file_name(temp_name))
+}
+
+fn file_name(path: &Path) -> io::Result<OsString> {
+    path.file_name().map(OsString::from).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not a file: {}", path.display()),
+        )
+    })
+}
+
+const MAX_SYMLINKS: usize = 40;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zee-files-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_str(path: &Path, backup: bool, contents: &str) -> io::Result<()> {
+        write_atomically(path, backup, |writer| writer.write_all(contents.as_bytes()))
+    }
+
+    #[test]
+    fn relative_paths_start_from_the_current_directory() {
+        let dir = test_dir("relative");
+        let file = dir.join("main.rs");
+        fs::write(&file, "").unwrap();
+        assert_eq!(relative_to(&file, "lib.rs:3"), dir.join("lib.rs:3"));
+        assert_eq!(relative_to(&dir, "src/lib.rs"), dir.join("src/lib.rs"));
+        assert_eq!(relative_to(&file, "/etc/hosts"), Path::new("/etc/hosts"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locations_are_split_into_paths_and_positions() {
+        let position = |line, column| Some(Position { line, column });
+        assert_eq!(Position::parse("12"), position(12, 1));
+        assert_eq!(Position::parse(" 12:7 "), position(12, 7));
+        assert_eq!(Position::parse("12:x"), None);
+        assert_eq!(Position::parse(""), None);
+
+        assert_eq!(
+            split_location("src/main.rs:10:5:"),
+            (PathBuf::from("src/main.rs"), position(10, 5))
+        );
+        assert_eq!(
+            split_location("src/main.rs:10"),
+            (PathBuf::from("src/main.rs"), position(10, 1))
+        );
+        assert_eq!(
+            split_location("a:b:3:4"),
+            (PathBuf::from("a:b"), position(3, 4))
+        );
+        assert_eq!(
+            split_location("src/main.rs"),
+            (PathBuf::from("src/main.rs"), None)
+        );
+        assert_eq!(split_location(":12"), (PathBuf::from(":12"), None));
+
+        let dir = test_dir("location");
+        let path = dir.join("notes:3");
+        fs::write(&path, "").unwrap();
+        assert_eq!(split_location(&path), (path.clone(), None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_replaces_contents_and_keeps_a_backup() {
+        let dir = test_dir("backup");
+        let path = dir.join("file.txt");
+        write_str(&path, true, "first").unwrap();
+        assert!(!backup_path(&path).unwrap().exists());
+
+        write_str(&path, true, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(dir.join("file.txt~")).unwrap(), "first");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Saves of the same file at once don't share a temporary file
+        assert_ne!(temp_path(&path).unwrap(), temp_path(&path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_follows_symlinks_and_keeps_permissions() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = test_dir("symlink");
+        let target = dir.join("target.sh");
+        let link = dir.join("link.sh");
+        fs::write(&target, "old").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o754)).unwrap();
+        symlink("target.sh", &link).unwrap();
+
+        write_str(&link, false, "new").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o754
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
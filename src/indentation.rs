@@ -0,0 +1,181 @@
+use ropey::Rope;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How a buffer is indented and how wide tabs are drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Indentation {
+    pub style: IndentStyle,
+    /// The number of columns of one level of indentation
+    pub size: usize,
+    pub tab_width: usize,
+}
+
+impl Indentation {
+    /// The text inserted by pressing tab
+    pub fn unit(&self) -> String {
+        match self.style {
+            IndentStyle::Tabs => "\t".into(),
+            IndentStyle::Spaces => " ".repeat(self.size),
+        }
+    }
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Self {
+            style: IndentStyle::default(),
+            size: DEFAULT_TAB_WIDTH,
+            tab_width: DEFAULT_TAB_WIDTH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndentStyle {
+    Tabs,
+    Spaces,
+}
+
+impl Default for IndentStyle {
+    fn default() -> Self {
+        Self::Spaces
+    }
+}
+
+/// The indentation a file's content appears to use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetectedIndentation {
+    Tabs,
+    /// Spaces with the most common width of one level of indentation
+    Spaces(usize),
+}
+
+impl DetectedIndentation {
+    pub fn apply(self, indentation: &mut Indentation) {
+        match self {
+            Self::Tabs => {
+                indentation.style = IndentStyle::Tabs;
+                indentation.size = indentation.tab_width;
+            }
+            Self::Spaces(size) => {
+                indentation.style = IndentStyle::Spaces;
+                indentation.size = size;
+            }
+        }
+    }
+}
+
+/// Guesses the indentation of a file by looking at its first lines. Lines
+/// indented with tabs are counted against lines indented with spaces, and the
+/// width of space indentation is the most common increase from one line to
+/// the next. `None` if there's no indentation to go by.
+pub fn detect(text: &Rope) -> Option<DetectedIndentation> {
+    let mut num_tabs = 0;
+    let mut num_spaces = 0;
+    let mut increases = HashMap::new();
+    let mut previous_indent = 0;
+    for line in text.lines().take(MAX_LINES_TO_DETECT) {
+        let mut chars = line.chars().peekable();
+        match chars.peek() {
+            Some('\t') => num_tabs += 1,
+            Some(' ') => {
+                let indent = chars.take_while(|character| *character == ' ').count();
+                // Ignore alignment, e.g. of the stars in a block comment
+                if indent > 1 {
+                    num_spaces += 1;
+                }
+                if indent > previous_indent + 1 {
+                    *increases.entry(indent - previous_indent).or_insert(0) += 1;
+                }
+                previous_indent = indent;
+            }
+            Some('\n') | Some('\r') | None => {}
+            Some(_) => previous_indent = 0,
+        }
+    }
+
+    if num_tabs > num_spaces {
+        Some(DetectedIndentation::Tabs)
+    } else {
+        increases
+            .into_iter()
+            .filter(|(size, _)| *size <= MAX_DETECTED_SIZE)
+            .max_by_key(|&(size, count)| (count, std::cmp::Reverse(size)))
+            .map(|(size, _)| DetectedIndentation::Spaces(size))
+    }
+}
+
+/// Rewrites the leading whitespace of every line in `text`, keeping its
+/// visual width. With tabs, any remainder narrower than a tab is kept as
+/// spaces.
+pub fn convert(text: &str, style: IndentStyle, tab_width: usize) -> String {
+    let mut converted = String::with_capacity(text.len());
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            converted.push('\n');
+        }
+        let content = line.trim_start_matches(&[' ', '\t'][..]);
+        let width = line[..line.len() - content.len()]
+            .chars()
+            .fold(0, |column, character| match character {
+                '\t' => column + tab_width - column % tab_width,
+                _ => column + 1,
+            });
+        match style {
+            IndentStyle::Tabs => {
+                converted.push_str(&"\t".repeat(width / tab_width));
+                converted.push_str(&" ".repeat(width % tab_width));
+            }
+            IndentStyle::Spaces => converted.push_str(&" ".repeat(width)),
+        }
+        converted.push_str(content);
+    }
+    converted
+}
+
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+const MAX_LINES_TO_DETECT: usize = 1000;
+const MAX_DETECTED_SIZE: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tabs_and_space_widths() {
+        assert_eq!(
+            detect(&Rope::from("func main() {\n\tif x {\n\t\ty()\n\t}\n}\n")),
+            Some(DetectedIndentation::Tabs)
+        );
+        assert_eq!(
+            detect(&Rope::from(
+                "def f():\n  if x:\n    y()\n  return\n\nclass A:\n  pass\n"
+            )),
+            Some(DetectedIndentation::Spaces(2))
+        );
+        assert_eq!(
+            detect(&Rope::from(
+                "/*\n * comment\n */\nfn f() {\n    g(\n        x,\n    );\n}\n"
+            )),
+            Some(DetectedIndentation::Spaces(4))
+        );
+        assert_eq!(detect(&Rope::from("no\nindentation\n")), None);
+    }
+
+    #[test]
+    fn converts_indentation_keeping_its_width() {
+        let text = "a\n    b\n      c \td\n\t  e\n";
+        assert_eq!(
+            convert(text, IndentStyle::Tabs, 4),
+            "a\n\tb\n\t  c \td\n\t  e\n"
+        );
+        assert_eq!(
+            convert(text, IndentStyle::Spaces, 4),
+            "a\n    b\n      c \td\n      e\n"
+        );
+        assert_eq!(convert(" \tx", IndentStyle::Spaces, 8), "        x");
+    }
+}
@@ -0,0 +1,741 @@
+use smallvec::SmallVec;
+use std::collections::HashMap;
+
+use crate::{
+    components::{
+        buffer::{self, BufferBindings},
+        prompt::{self, PromptBindings},
+        BindingMatch, Bindings, HashBindings,
+    },
+    editor::{self, EditorAction},
+    error::{Error, Result},
+    settings::KeySettings,
+    terminal::Key,
+};
+
+/// A sequence of key presses bound to an action, e.g. `C-x C-s`
+pub type KeySequence = SmallVec<[Key; 2]>;
+
+/// The stable name of an action, used in the settings file and the command
+/// palette, and what it does
+pub struct ActionInfo<Action> {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub action: Action,
+}
+
+impl<Action> ActionInfo<Action> {
+    pub const fn new(name: &'static str, description: &'static str, action: Action) -> Self {
+        Self {
+            name,
+            description,
+            action,
+        }
+    }
+}
+
+/// Actions which can be bound to keys and run by name
+pub trait NamedAction: Clone + PartialEq + Sized + 'static {
+    /// Every action which can be run by name, named in kebab-case
+    const ACTIONS: &'static [ActionInfo<Self>];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ACTIONS
+            .iter()
+            .find(|info| info.name == name)
+            .map(|info| info.action.clone())
+    }
+
+    fn info(&self) -> Option<&'static ActionInfo<Self>> {
+        Self::ACTIONS.iter().find(|info| info.action == *self)
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        self.info().map(|info| info.name)
+    }
+}
+
+/// An action of any of the editor, the focused buffer or the prompt, a named
+/// keyboard macro or a command of the init script, as picked from the command
+/// palette
+#[derive(Clone, Debug, PartialEq)]
+pub enum NamedCommand {
+    Editor(EditorAction),
+    Buffer(buffer::SyncAction),
+    Prompt(prompt::SyncAction),
+    Macro(String),
+    Script(String),
+}
+
+/// An entry in the command palette
+#[derive(Clone, Debug)]
+pub struct CommandEntry {
+    pub name: String,
+    pub description: String,
+    /// The keys bound to the command, e.g. `C-x C-s, C-x s`
+    pub keys: String,
+    pub command: NamedCommand,
+}
+
+/// The key bindings of the editor, the focused buffer and the prompt. They
+/// start out as the defaults and are changed by the `[keys]` section of the
+/// settings file.
+pub struct Keymap {
+    pub editor: HashBindings<EditorAction>,
+    pub buffer: BufferBindings,
+    pub prompt: PromptBindings,
+}
+
+impl Keymap {
+    pub fn new(settings: &KeySettings) -> Result<Self> {
+        let editor = configure(editor::default_bindings(), &settings.editor, "editor")?;
+        let buffer = configure(buffer::default_bindings(), &settings.buffer, "buffer")?;
+        let prompt = configure(prompt::default_bindings(), &settings.prompt, "prompt")?;
+        check_prefixes(&editor, &buffer, &prompt)?;
+        Ok(Self {
+            editor,
+            buffer: BufferBindings::new(buffer),
+            prompt: PromptBindings::new(prompt),
+        })
+    }
+}
+
+impl Keymap {
+    /// Every command which can be run from the command palette. Prompt
+    /// actions which only make sense while picking something are left out.
+    pub fn commands(&self) -> Vec<CommandEntry> {
+        let mut commands = Vec::new();
+        commands.extend(EditorAction::ACTIONS.iter().map(|info| CommandEntry {
+            name: info.name.into(),
+            description: info.description.into(),
+            keys: bound_keys(&self.editor, &info.action),
+            command: NamedCommand::Editor(info.action.clone()),
+        }));
+        commands.extend(buffer::SyncAction::ACTIONS.iter().map(|info| CommandEntry {
+            name: info.name.into(),
+            description: info.description.into(),
+            keys: bound_keys(self.buffer.hash_bindings(), &info.action),
+            command: NamedCommand::Buffer(info.action.clone()),
+        }));
+        commands.extend(
+            prompt::SyncAction::ACTIONS
+                .iter()
+                .filter(|info| info.action.opens_prompt())
+                .map(|info| CommandEntry {
+                    name: info.name.into(),
+                    description: info.description.into(),
+                    keys: bound_keys(self.prompt.hash_bindings(), &info.action),
+                    command: NamedCommand::Prompt(info.action.clone()),
+                }),
+        );
+        commands
+    }
+}
+
+/// A binding which continues the keys pressed so far, shown while waiting
+/// for the rest
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyHint {
+    /// The keys left to press, e.g. `C-s` after `C-x`
+    pub keys: String,
+    pub name: &'static str,
+}
+
+impl Keymap {
+    /// The bindings which complete `prefix`. The editor's and the buffer's
+    /// only apply while the prompt is inactive.
+    pub fn key_hints(&self, prefix: &[Key], prompt_active: bool) -> Vec<KeyHint> {
+        let mut hints: Vec<(KeySequence, &'static str)> = Vec::new();
+        if !prompt_active {
+            hints.extend(completions(&self.editor, prefix));
+            hints.extend(completions(self.buffer.hash_bindings(), prefix));
+        }
+        hints.extend(completions(self.prompt.hash_bindings(), prefix));
+        hints.sort_by_key(|(keys, _)| (keys.len(), format_keys(keys)));
+        hints.dedup_by(|(keys, _), (other_keys, _)| keys == other_keys);
+        hints
+            .into_iter()
+            .map(|(keys, name)| KeyHint {
+                keys: format_keys(&keys[prefix.len()..]),
+                name,
+            })
+            .collect()
+    }
+}
+
+fn completions<'a, Action: NamedAction>(
+    bindings: &'a HashBindings<Action>,
+    prefix: &'a [Key],
+) -> impl Iterator<Item = (KeySequence, &'static str)> + 'a {
+    bindings
+        .completions(prefix)
+        .filter_map(|(keys, action)| action.name().map(|name| (keys.clone(), name)))
+}
+
+impl Keymap {
+    /// Describes what pressing `keys` does, checking the editor's, the
+    /// buffer's and the prompt's bindings in the order keys are handled.
+    /// `None` while the keys are a prefix of a binding.
+    pub fn describe_key(
+        &self,
+        keys: &[Key],
+        prompt_active: bool,
+        buffer_focused: bool,
+    ) -> Option<String> {
+        let keys_str = format_keys(keys);
+        let mut is_prefix = false;
+
+        if !prompt_active {
+            match self.editor.matches(keys) {
+                BindingMatch::Full(action) => return Some(describe(&keys_str, "Editor", &action)),
+                binding_match => is_prefix |= binding_match.is_prefix(),
+            }
+        }
+        if !prompt_active && buffer_focused {
+            match self.buffer.matches(keys) {
+                BindingMatch::Full(buffer::Action::Sync(buffer::SyncAction::InsertChar(_))) => {
+                    return Some(format!("{} inserts itself (Buffer)", keys_str))
+                }
+                BindingMatch::Full(buffer::Action::Sync(action)) => {
+                    return Some(describe(&keys_str, "Buffer", &action))
+                }
+                binding_match => is_prefix |= binding_match.is_prefix(),
+            }
+        }
+        match self.prompt.matches(keys) {
+            BindingMatch::Full(prompt::Action::Sync(prompt::SyncAction::InsertChar(_))) => {
+                return Some(format!("{} inserts itself (Prompt)", keys_str))
+            }
+            BindingMatch::Full(prompt::Action::Sync(action)) => {
+                return Some(describe(&keys_str, "Prompt", &action))
+            }
+            binding_match => is_prefix |= binding_match.is_prefix(),
+        }
+
+        if is_prefix {
+            None
+        } else {
+            Some(format!("{} is undefined", keys_str))
+        }
+    }
+
+    /// Lists every binding, grouped by whether it's handled by the editor,
+    /// the focused buffer or the prompt
+    pub fn describe_bindings(&self) -> String {
+        let mut text = String::from("Key bindings\n");
+        describe_scope(&mut text, "Editor", &self.editor);
+        describe_scope(&mut text, "Buffer", self.buffer.hash_bindings());
+        text.push_str("  Any other character inserts itself\n");
+        describe_scope(&mut text, "Prompt", self.prompt.hash_bindings());
+        text.push_str("  Any other character inserts itself\n");
+        text
+    }
+}
+
+fn describe<Action: NamedAction>(keys: &str, scope: &str, action: &Action) -> String {
+    match action.info() {
+        Some(info) => format!(
+            "{} runs {} ({}): {}",
+            keys, info.name, scope, info.description
+        ),
+        None => format!("{} runs an unnamed action ({})", keys, scope),
+    }
+}
+
+fn describe_scope<Action: NamedAction>(
+    text: &mut String,
+    scope: &str,
+    bindings: &HashBindings<Action>,
+) {
+    let mut lines: Vec<_> = bindings
+        .iter()
+        .filter_map(|(keys, action)| action.info().map(|info| (format_keys(keys), info)))
+        .collect();
+    lines.sort_by(|(keys, _), (other_keys, _)| keys.cmp(other_keys));
+    let keys_width = lines.iter().map(|(keys, _)| keys.len()).max().unwrap_or(0);
+    let name_width = lines
+        .iter()
+        .map(|(_, info)| info.name.len())
+        .max()
+        .unwrap_or(0);
+
+    text.push('\n');
+    text.push_str(scope);
+    text.push('\n');
+    for (keys, info) in lines {
+        text.push_str(&format!(
+            "  {:keys_width$}  {:name_width$}  {}\n",
+            keys,
+            info.name,
+            info.description,
+            keys_width = keys_width,
+            name_width = name_width
+        ));
+    }
+}
+
+/// Formats every binding of an action, shortest first
+fn bound_keys<Action: PartialEq>(bindings: &HashBindings<Action>, action: &Action) -> String {
+    let mut keys: Vec<_> = bindings
+        .iter()
+        .filter(|(_, bound)| *bound == action)
+        .map(|(keys, _)| (keys.len(), format_keys(keys)))
+        .collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|(_, keys)| keys)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            editor: editor::default_bindings(),
+            buffer: BufferBindings::new(buffer::default_bindings()),
+            prompt: PromptBindings::new(prompt::default_bindings()),
+        }
+    }
+}
+
+/// The value which removes a default binding instead of naming an action
+const UNBIND: &str = "unbind";
+
+/// Applies the bindings from a `[keys.<section>]` table to the defaults.
+/// Unbinding comes first, so it doesn't matter which of two spellings of the
+/// same keys is read first.
+fn configure<Action: NamedAction>(
+    mut bindings: HashBindings<Action>,
+    overrides: &HashMap<String, String>,
+    section: &str,
+) -> Result<HashBindings<Action>> {
+    let parsed = overrides
+        .iter()
+        .map(|(keys, action)| {
+            let sequence = parse_keys(keys).map_err(|error| {
+                Error::Config(format!(
+                    "`[keys.{}]` invalid keys `{}`: {}",
+                    section, keys, error
+                ))
+            })?;
+            match sequence.first() {
+                Some(Key::Char(character)) if *character != '\n' && *character != '\t' => {
+                    return Err(Error::Config(format!(
+                        "`[keys.{}]` `{}` can't be bound, `{}` inserts itself",
+                        section,
+                        keys,
+                        format_key(&Key::Char(*character))
+                    )))
+                }
+                _ => {}
+            }
+            Ok((sequence, action))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (sequence, _) in parsed.iter().filter(|(_, action)| *action == UNBIND) {
+        bindings.unbind(sequence);
+    }
+    for (sequence, name) in parsed.into_iter().filter(|(_, action)| *action != UNBIND) {
+        let action = Action::from_name(name).ok_or_else(|| {
+            Error::Config(format!(
+                "`[keys.{}]` unknown action `{}`, expected one of {} or `{}`",
+                section,
+                name,
+                Action::ACTIONS
+                    .iter()
+                    .map(|info| info.name)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                UNBIND
+            ))
+        })?;
+        bindings.bind(sequence, action);
+    }
+    Ok(bindings)
+}
+
+/// Checks that no binding can't be reached because a shorter one is matched
+/// first. The buffer and the prompt see the same keys, so they are checked
+/// against each other, but may bind the same keys, e.g. `C-g` to cancel
+/// both. The editor's bindings are matched before either of them and can't
+/// share keys with them.
+fn check_prefixes(
+    editor: &HashBindings<EditorAction>,
+    buffer: &HashBindings<buffer::SyncAction>,
+    prompt: &HashBindings<prompt::SyncAction>,
+) -> Result<()> {
+    let sequences: Vec<(&str, &KeySequence)> = editor
+        .iter()
+        .map(|(keys, _)| ("editor", keys))
+        .chain(buffer.iter().map(|(keys, _)| ("buffer", keys)))
+        .chain(prompt.iter().map(|(keys, _)| ("prompt", keys)))
+        .collect();
+
+    for (index, (section, keys)) in sequences.iter().enumerate() {
+        for (other_section, other_keys) in sequences[index + 1..].iter() {
+            let is_prefix = keys.starts_with(other_keys) || other_keys.starts_with(keys);
+            let conflicts = if keys.len() == other_keys.len() {
+                is_prefix && section != other_section && *section == "editor"
+            } else {
+                is_prefix
+            };
+            if conflicts {
+                let ((shorter_section, shorter), (longer_section, longer)) =
+                    if keys.len() <= other_keys.len() {
+                        ((section, keys), (other_section, other_keys))
+                    } else {
+                        ((other_section, other_keys), (section, keys))
+                    };
+                return Err(Error::Config(if shorter.len() == longer.len() {
+                    format!(
+                        "`{}` is bound in both `[keys.{}]` and `[keys.{}]`",
+                        format_keys(shorter),
+                        shorter_section,
+                        longer_section
+                    )
+                } else {
+                    format!(
+                        "`{}` in `[keys.{}]` is a prefix of `{}` in `[keys.{}]`",
+                        format_keys(shorter),
+                        shorter_section,
+                        format_keys(longer),
+                        longer_section
+                    )
+                }));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a sequence of keys separated by spaces, e.g. `C-x C-s` or
+/// `C-x RET f`
+pub fn parse_keys(keys: &str) -> std::result::Result<KeySequence, String> {
+    let sequence = keys
+        .split_whitespace()
+        .map(parse_key)
+        .collect::<std::result::Result<KeySequence, _>>()?;
+    if sequence.is_empty() {
+        return Err("no keys".into());
+    }
+    Ok(sequence)
+}
+
+/// Parses a single key, either a character, a character with the `C-` (Ctrl)
+/// or `M-`/`A-` (Alt) modifier, or a special key such as `RET` or `<left>`
+pub fn parse_key(key: &str) -> std::result::Result<Key, String> {
+    if let Some(rest) = key.strip_prefix("C-") {
+        return match modified_char(rest) {
+            Some(' ') | Some('@') => Ok(Key::Null),
+            Some(character) => Ok(Key::Ctrl(character)),
+            None => Err(format!("`{}` isn't a key that can be used with Ctrl", rest)),
+        };
+    }
+    if let Some(rest) = key.strip_prefix("M-").or_else(|| key.strip_prefix("A-")) {
+        return modified_char(rest)
+            .map(Key::Alt)
+            .ok_or_else(|| format!("`{}` isn't a key that can be used with Alt", rest));
+    }
+
+    let mut chars = key.chars();
+    if let (Some(character), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Char(character));
+    }
+
+    let name = key
+        .strip_prefix('<')
+        .and_then(|name| name.strip_suffix('>'))
+        .unwrap_or(key)
+        .to_ascii_lowercase();
+    let parsed = match name.as_str() {
+        "ret" | "return" | "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "spc" | "space" => Key::Char(' '),
+        "esc" | "escape" => Key::Esc,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "prior" | "pageup" => Key::PageUp,
+        "next" | "pagedown" => Key::PageDown,
+        "backtab" => Key::BackTab,
+        name => match name
+            .strip_prefix('f')
+            .and_then(|number| number.parse::<u8>().ok())
+        {
+            Some(number) if (1..=12).contains(&number) => Key::F(number),
+            _ => return Err(format!("unknown key `{}`", key)),
+        },
+    };
+    Ok(parsed)
+}
+
+/// The character after a modifier, a single character or `SPC`
+fn modified_char(key: &str) -> Option<char> {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(character), None) => Some(character),
+        _ if key == "SPC" => Some(' '),
+        _ => None,
+    }
+}
+
+/// Formats keys the way they're written in the settings file
+pub fn format_keys(keys: &[Key]) -> String {
+    keys.iter().map(format_key).collect::<Vec<_>>().join(" ")
+}
+
+pub fn format_key(key: &Key) -> String {
+    match key {
+        Key::Char(' ') => "SPC".into(),
+        Key::Char('\n') => "RET".into(),
+        Key::Char('\t') => "TAB".into(),
+        Key::Char(character) => character.to_string(),
+        Key::Ctrl(character) => format!("C-{}", character),
+        Key::Alt(' ') => "A-SPC".into(),
+        Key::Alt(character) => format!("A-{}", character),
+        Key::Null => "C-SPC".into(),
+        Key::Esc => "ESC".into(),
+        Key::F(number) => format!("<f{}>", number),
+        Key::Backspace => "<backspace>".into(),
+        Key::Delete => "<delete>".into(),
+        Key::Insert => "<insert>".into(),
+        Key::Left => "<left>".into(),
+        Key::Right => "<right>".into(),
+        Key::Up => "<up>".into(),
+        Key::Down => "<down>".into(),
+        Key::Home => "<home>".into(),
+        Key::End => "<end>".into(),
+        Key::PageUp => "<prior>".into(),
+        Key::PageDown => "<next>".into(),
+        Key::BackTab => "<backtab>".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap(editor: &str, buffer: &str, prompt: &str) -> Result<Keymap> {
+        let parse = |table: &str| -> HashMap<String, String> {
+            toml::de::from_str(table).expect("valid toml")
+        };
+        Keymap::new(&KeySettings {
+            editor: parse(editor),
+            buffer: parse(buffer),
+            prompt: parse(prompt),
+        })
+    }
+
+    fn error_message(result: Result<Keymap>) -> String {
+        match result {
+            Err(Error::Config(message)) => message,
+            Err(error) => panic!("expected a config error, got {}", error),
+            Ok(_) => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn parses_and_formats_keys() {
+        assert_eq!(
+            parse_keys("C-x RET  f").unwrap().as_slice(),
+            &[Key::Ctrl('x'), Key::Char('\n'), Key::Char('f')]
+        );
+        assert_eq!(parse_key("M-<").unwrap(), Key::Alt('<'));
+        assert_eq!(parse_key("A-v").unwrap(), Key::Alt('v'));
+        assert_eq!(parse_key("C-SPC").unwrap(), Key::Null);
+        assert_eq!(parse_key("<F5>").unwrap(), Key::F(5));
+        assert_eq!(parse_key("PageDown").unwrap(), Key::PageDown);
+        assert!(parse_key("C-M-x").is_err());
+        assert!(parse_key("<f13>").is_err());
+        assert!(parse_keys("  ").is_err());
+
+        for keys in &["C-x C-s", "A-w", "C-SPC", "<f1> TAB SPC", "ESC <prior> x"] {
+            assert_eq!(format_keys(&parse_keys(keys).unwrap()), *keys);
+        }
+    }
+
+    #[test]
+    fn formatted_keys_parse_back() {
+        // Recorded macros are saved to the settings formatted
+        let keys = [
+            Key::Char('<'),
+            Key::Char('-'),
+            Key::Char('C'),
+            Key::Char(' '),
+            Key::Char('\n'),
+            Key::Ctrl('-'),
+            Key::Alt('>'),
+            Key::Null,
+            Key::Backspace,
+            Key::PageUp,
+        ];
+        assert_eq!(parse_keys(&format_keys(&keys)).unwrap().as_slice(), &keys);
+    }
+
+    #[test]
+    fn binds_and_unbinds_named_actions() {
+        let keymap = keymap(
+            "\"C-x k\" = \"close-pane\"\n\"C-x 0\" = \"unbind\"",
+            "\"C-x C-s\" = \"unbind\"\n\"<f2>\" = \"save-buffer\"",
+            "\"C-x C-b\" = \"pick-file-from-directory\"",
+        )
+        .unwrap();
+        assert_eq!(
+            keymap.editor.matches(&[Key::Ctrl('x'), Key::Char('k')]),
+            BindingMatch::Full(EditorAction::ClosePane)
+        );
+        assert_eq!(
+            keymap.editor.matches(&[Key::Ctrl('x'), Key::Char('0')]),
+            BindingMatch::None
+        );
+        let buffer_match = |keys: &[Key]| keymap.buffer.matches(keys).map_action(|_| ());
+        assert_eq!(
+            buffer_match(&[Key::Ctrl('x'), Key::Ctrl('s')]),
+            BindingMatch::None
+        );
+        assert_eq!(buffer_match(&[Key::F(2)]), BindingMatch::Full(()));
+        assert_eq!(
+            keymap
+                .prompt
+                .matches(&[Key::Ctrl('x'), Key::Ctrl('b')])
+                .map_action(|_| ()),
+            BindingMatch::Full(())
+        );
+
+        assert!(
+            error_message(keymap_with_buffer("\"C-q\" = \"frobnicate\""))
+                .contains("unknown action `frobnicate`")
+        );
+        assert!(
+            error_message(keymap_with_buffer("\"C-x C-M-s\" = \"undo\""))
+                .contains("invalid keys `C-x C-M-s`")
+        );
+        assert!(error_message(keymap_with_buffer("\"q\" = \"undo\"")).contains("inserts itself"));
+    }
+
+    #[test]
+    fn hints_complete_a_prefix() {
+        let keymap = Keymap::default();
+        let hints = keymap.key_hints(&[Key::Ctrl('x'), Key::Char('\n')], false);
+        assert_eq!(
+            hints,
+            vec![
+                KeyHint {
+                    keys: "f".into(),
+                    name: "cycle-encoding"
+                },
+                KeyHint {
+                    keys: "l".into(),
+                    name: "cycle-line-ending"
+                },
+            ]
+        );
+
+        let hints = keymap.key_hints(&[Key::Ctrl('x')], false);
+        assert!(hints.contains(&KeyHint {
+            keys: "C-c".into(),
+            name: "quit"
+        }));
+        assert!(hints.contains(&KeyHint {
+            keys: "RET f".into(),
+            name: "cycle-encoding"
+        }));
+        assert!(keymap
+            .key_hints(&[Key::Ctrl('x')], true)
+            .iter()
+            .all(|hint| hint.name != "quit"));
+    }
+
+    #[test]
+    fn describes_keys_from_the_live_bindings() {
+        let keymap = keymap("", "\"<f2>\" = \"save-buffer\"", "").unwrap();
+        let describe = |keys: &str, prompt_active| {
+            keymap.describe_key(&parse_keys(keys).unwrap(), prompt_active, true)
+        };
+        assert_eq!(
+            describe("C-x C-c", false).unwrap(),
+            "C-x C-c runs quit (Editor): Quit zee"
+        );
+        assert_eq!(
+            describe("<f2>", false).unwrap(),
+            "<f2> runs save-buffer (Buffer): Save the buffer to its file"
+        );
+        assert_eq!(describe("C-x", false), None);
+        assert_eq!(describe("C-x C-c", true).unwrap(), "C-x C-c is undefined");
+        assert_eq!(describe("q", true).unwrap(), "q inserts itself (Prompt)");
+        assert!(describe("C-g", false)
+            .unwrap()
+            .contains("clear-selection (Buffer)"));
+
+        let bindings = keymap.describe_bindings();
+        let buffer = bindings.find("\nBuffer\n").unwrap();
+        let prompt = bindings.find("\nPrompt\n").unwrap();
+        assert!(bindings.find("  C-x C-c").unwrap() < buffer);
+        let f2 = bindings.find("  <f2>").unwrap();
+        assert!(buffer < f2 && f2 < prompt);
+    }
+
+    #[test]
+    fn commands_have_unique_names_and_their_bindings() {
+        let commands = Keymap::default().commands();
+        let mut names: Vec<_> = commands
+            .iter()
+            .map(|command| command.name.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        // `delete-forward` and `delete-backward` are shared by buffers and
+        // the prompt, but the prompt's aren't in the palette
+        assert_eq!(names.len(), commands.len());
+
+        let keys = |name: &str| {
+            commands
+                .iter()
+                .find(|command| command.name == name)
+                .map(|command| command.keys.as_str())
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(keys("save-buffer"), "C-x C-s, C-x s");
+        assert_eq!(keys("up"), "<up>, C-p");
+        assert_eq!(keys("execute-command"), "A-x");
+        assert_eq!(keys("pick-file-from-directory"), "C-x C-f");
+        assert!(commands.iter().all(|command| command.name != "select-up"));
+    }
+
+    fn keymap_with_buffer(buffer: &str) -> Result<Keymap> {
+        keymap("", buffer, "")
+    }
+
+    #[test]
+    fn rejects_conflicting_prefixes() {
+        assert_eq!(
+            error_message(keymap_with_buffer("\"C-x\" = \"undo\""))
+                .split(" in ")
+                .next(),
+            Some("`C-x`")
+        );
+        assert!(error_message(keymap("", "\"C-x C-s C-s\" = \"undo\"", ""))
+            .contains("`C-x C-s` in `[keys.buffer]` is a prefix of `C-x C-s C-s`"));
+        assert!(
+            error_message(keymap("\"C-g\" = \"quit\"", "", "")).contains("`C-g` is bound in both")
+        );
+
+        // The buffer and the prompt can share keys, and freeing up a prefix
+        // makes it available
+        assert!(keymap("", "\"C-x C-f\" = \"undo\"", "").is_ok());
+        assert!(keymap(
+            "",
+            "\"C-x RET f\" = \"unbind\"\n\"C-x RET l\" = \"unbind\"\n\"C-x RET\" = \"undo\"",
+            ""
+        )
+        .is_ok());
+    }
+}
@@ -1,8 +1,19 @@
 mod components;
 mod editor;
+mod editorconfig;
+mod encoding;
 mod error;
+mod files;
 mod frontend;
+mod indentation;
+mod keymap;
 mod mode;
+mod places;
+mod recovery;
+mod rpc;
+mod scripting;
+mod server;
+mod session;
 mod settings;
 mod smallstring;
 mod syntax;
@@ -10,16 +21,24 @@ mod task;
 mod terminal;
 mod undo;
 mod utils;
+mod vi;
 
 use clap;
 use flexi_logger::{opt_format, Logger};
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
 use structopt::StructOpt;
 
 use crate::{
+    components::Buffer,
     editor::Editor,
-    error::Result,
+    error::{Error, Result},
+    files::Position,
     frontend::{Frontend, FrontendKind, DEFAULT_FRONTEND_STR},
+    scripting::Scripts,
     task::TaskPool,
     terminal::Screen,
 };
@@ -28,13 +47,42 @@ use crate::{
 #[structopt(global_settings(&[clap::AppSettings::ColoredHelp]))]
 struct Args {
     #[structopt(name = "file", parse(from_os_str))]
-    /// Open file to edit
+    /// Open file to edit, as `file`, `file:line:column` or `+line file`. A
+    /// `-` reads the text to edit from stdin.
     files: Vec<PathBuf>,
 
+    #[structopt(long = "mode", parse(try_from_str = parse_mode))]
+    /// The mode of the text read from stdin, otherwise detected from the text
+    mode: Option<String>,
+
+    #[structopt(long = "read-only")]
+    /// Open the files without allowing changes to them
+    read_only: bool,
+
+    #[structopt(long = "server")]
+    /// Listen for `zee --client` on a Unix socket, to open files in this editor
+    server: bool,
+
+    #[structopt(long = "client", conflicts_with = "server")]
+    /// Open the files in the running `zee --server` instead of a new editor
+    client: bool,
+
+    #[structopt(long = "wait", requires = "client")]
+    /// With `--client`, return once the files are closed, e.g. as $EDITOR
+    wait: bool,
+
+    #[structopt(long = "socket", parse(from_os_str))]
+    /// The socket of the server, by default `zee.sock` in the runtime directory
+    socket_path: Option<PathBuf>,
+
     #[structopt(long = "settings-path", parse(from_os_str))]
     /// Path to the configuration file. It's usually ~/.config/zee on Linux.
     settings_path: Option<PathBuf>,
 
+    #[structopt(long = "restore-session")]
+    /// Reopen the files and panes from the last session in the current directory
+    restore_session: bool,
+
     #[structopt(long = "create-settings")]
     /// Writes the default configuration to file, if the file doesn't exist
     create_settings: bool,
@@ -49,6 +97,81 @@ struct Args {
     enable_logging: bool,
 }
 
+/// A file to open given on the command line
+#[derive(Debug, PartialEq)]
+enum FileArg {
+    Path(PathBuf, Option<Position>),
+    Stdin(Option<Position>),
+}
+
+/// Pairs `+line` arguments with the file after them and splits positions off
+/// `file:line:column` arguments
+fn parse_file_args(args: &[PathBuf]) -> Result<Vec<FileArg>> {
+    let mut files = Vec::with_capacity(args.len());
+    let mut line_arg = None;
+    for arg in args {
+        let plus_position = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix('+'))
+            .and_then(Position::parse);
+        if plus_position.is_some() {
+            line_arg = plus_position;
+            continue;
+        }
+        files.push(if arg.to_str() == Some("-") {
+            FileArg::Stdin(line_arg.take())
+        } else {
+            let (path, position) = files::split_location(arg);
+            FileArg::Path(path, line_arg.take().or(position))
+        });
+    }
+    match line_arg {
+        Some(Position { line, .. }) => Err(Error::Config(format!(
+            "`+{}` isn't followed by a file to open",
+            line
+        ))),
+        None => Ok(files),
+    }
+}
+
+fn parse_mode(name: &str) -> std::result::Result<String, String> {
+    mode::find_by_name(name)
+        .map(|mode| mode.name.to_string())
+        .ok_or_else(|| {
+            format!(
+                "unknown mode, one of: {}",
+                mode::names().collect::<Vec<_>>().join(", ")
+            )
+        })
+}
+
+fn run_client(
+    socket_path: &Path,
+    file_args: Vec<FileArg>,
+    current_dir: &Path,
+    args: &Args,
+) -> Result<()> {
+    let files = file_args
+        .into_iter()
+        .map(|file_arg| match file_arg {
+            FileArg::Path(path, position) => Ok((current_dir.join(path), position)),
+            FileArg::Stdin(_) => Err(Error::Server("stdin can't be sent to the server".into())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let errors = server::open_in_server(socket_path, &files, args.wait, args.read_only)?;
+    for error in errors.iter() {
+        eprintln!("zee: {}", error);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Server(format!(
+            "{} file(s) were not opened",
+            errors.len()
+        )))
+    }
+}
+
 fn run_editor_ui_loop(frontend_kind: &FrontendKind, mut editor: Editor) -> Result<()> {
     match frontend_kind {
         #[cfg(feature = "frontend-termion")]
@@ -81,13 +204,26 @@ fn start_editor() -> Result<()> {
         configure_logging()?;
     }
     let current_dir = env::current_dir()?;
+    let file_args = parse_file_args(&args.files)?;
+    let socket_path = args.socket_path.clone().unwrap_or_else(server::socket_path);
+    if args.client {
+        return run_client(&socket_path, file_args, &current_dir, &args);
+    }
 
     // Read the current settings. If we cannot for any reason, we'll use the
-    // default ones -- ensure the editor opens in any environment.
-    let settings = args
+    // default ones -- ensure the editor opens in any environment. The error is
+    // shown once the editor is up.
+    let settings_path = args
         .settings_path
-        .or_else(|| settings::settings_path().map(Some).unwrap_or(None))
-        .map_or_else(Default::default, settings::read_settings);
+        .or_else(|| settings::settings_path().map(Some).unwrap_or(None));
+    let (settings, settings_error) = match settings_path.as_ref().map(settings::read_settings) {
+        Some(Ok(settings)) => (settings, None),
+        Some(Err(error)) => {
+            log::error!("{}", error);
+            (Default::default(), Some(error))
+        }
+        None => (Default::default(), None),
+    };
 
     // Create a default settings file if user requested it
     if args.create_settings {
@@ -103,15 +239,88 @@ fn start_editor() -> Result<()> {
     }
 
     // Instantiate editor and open any files specified as arguments
-    let mut editor = Editor::new(settings, current_dir, TaskPool::new()?);
-    for file_path in args.files.iter() {
-        editor.open_file(file_path)?;
+    let restore_session =
+        args.restore_session || (args.files.is_empty() && settings.restore_session);
+    let mut editor = Editor::new(
+        settings,
+        settings_path,
+        current_dir.clone(),
+        TaskPool::new()?,
+    );
+    if let Some(error) = settings_error {
+        editor.log_error(format!("{}", error));
+    }
+    match scripting::init_script_path() {
+        Ok(path) if path.exists() => match Scripts::load(&path) {
+            Ok(scripts) => editor.set_scripts(scripts),
+            Err(error) => {
+                log::error!("{}", error);
+                editor.log_error(format!("{}", error));
+            }
+        },
+        _ => {}
+    }
+    let server = if args.server {
+        Some(server::listen(&socket_path)?)
+    } else {
+        None
+    };
+    if let Some(ref server) = server {
+        editor.accept_clients(server);
+    }
+    for file_arg in file_args {
+        match file_arg {
+            FileArg::Path(path, position) => {
+                editor.open_file_at(path, position, args.read_only)?;
+            }
+            FileArg::Stdin(position) => {
+                let mut text = Vec::new();
+                io::stdin().read_to_end(&mut text)?;
+                let mode = args.mode.as_deref().and_then(mode::find_by_name);
+                let mut buffer = Buffer::new_scratch_from_bytes(&text, mode);
+                buffer.set_read_only(args.read_only);
+                editor.open_buffer(buffer, position);
+            }
+        }
+    }
+
+    // Reopen the buffers from the last session in this directory
+    if restore_session {
+        match session::read_session(&current_dir) {
+            Ok(Some(session)) => editor.restore_session(session)?,
+            Ok(None) => log::info!("No session to restore in `{}`", current_dir.display()),
+            Err(error) => log::error!("{}", error),
+        }
     }
 
     // Start the UI loop
     run_editor_ui_loop(&args.frontend_kind, editor)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_args_take_positions() {
+        let args: Vec<PathBuf> = ["+12", "a.rs", "b.rs:3:4:", "+5:2", "-", "c.rs"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let position = |line, column| Some(Position { line, column });
+        assert_eq!(
+            parse_file_args(&args).unwrap(),
+            vec![
+                FileArg::Path("a.rs".into(), position(12, 1)),
+                FileArg::Path("b.rs".into(), position(3, 4)),
+                FileArg::Stdin(position(5, 2)),
+                FileArg::Path("c.rs".into(), None),
+            ]
+        );
+        assert!(parse_file_args(&["a.rs".into(), "+3".into()]).is_err());
+    }
+}
+
 fn main() -> Result<()> {
     start_editor().map_err(|error| {
         log::error!("Zee exited with: {}", error);
@@ -1,5 +1,6 @@
 use once_cell::sync::Lazy;
-use std::{ffi::OsStr, path::Path};
+use ropey::Rope;
+use std::{borrow::Cow, ffi::OsStr, iter, path::Path};
 use tree_sitter::Language;
 use zee_grammar as grammar;
 use zee_highlight::{
@@ -8,12 +9,15 @@ use zee_highlight::{
     TYPESCRIPT_RULES,
 };
 
-use crate::smallstring::SmallString;
+use crate::{editorconfig, indentation::IndentStyle, settings::Settings, smallstring::SmallString};
 
 pub struct Mode {
     pub name: SmallString,
     file: Vec<FilenamePattern>,
     pub parser: Option<SyntaxParser>,
+    /// For languages which require it, the indentation to use when it can't
+    /// be detected and isn't configured
+    pub indent_style: Option<IndentStyle>,
 }
 
 impl Mode {
@@ -45,6 +49,7 @@ impl Default for Mode {
             name: "Plain".into(),
             file: vec![],
             parser: None,
+            indent_style: None,
         }
     }
 }
@@ -81,14 +86,87 @@ impl FilenamePattern {
     }
 }
 
-pub fn find_by_filename(filename: impl AsRef<Path>) -> &'static Mode {
+/// The mode to open a file in. The `file_patterns` in the settings take
+/// precedence over the modes' own. When the patterns of several modes match,
+/// the longest pattern wins, then the mode whose name sorts first.
+pub fn find(filename: impl AsRef<Path>, settings: &Settings) -> &'static Mode {
+    let filename = filename.as_ref();
+    let mut matching: Vec<_> = settings
+        .modes
+        .iter()
+        .filter_map(|(name, mode_settings)| {
+            mode_settings
+                .file_patterns
+                .iter()
+                .filter(|pattern| editorconfig::glob_matches(pattern, filename))
+                .map(|pattern| pattern.len())
+                .max()
+                .map(|length| (length, name))
+        })
+        .collect();
+    matching.sort_unstable_by(|(length1, name1), (length2, name2)| {
+        length2.cmp(length1).then_with(|| name1.cmp(name2))
+    });
+    matching
+        .into_iter()
+        .find_map(|(_, name)| find_by_name(name))
+        .unwrap_or_else(|| find_by_filename(filename))
+}
+
+/// Looks up a mode by name, ignoring case
+pub fn find_by_name(name: &str) -> Option<&'static Mode> {
+    all_modes().find(|mode| mode.name.eq_ignore_ascii_case(name))
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    all_modes().map(|mode| &*mode.name)
+}
+
+fn all_modes() -> impl Iterator<Item = &'static Mode> {
+    LANGUAGE_MODES.iter().chain(iter::once(&*PLAIN_TEXT_MODE))
+}
+
+/// The mode of text which doesn't come from a file, e.g. piped to stdin,
+/// guessed from a `#!` line or an HTML document type
+pub fn find_by_content(text: &Rope) -> &'static Mode {
+    let first_line: Cow<str> = text.line(0).into();
+    let first_line = first_line.trim();
+    let name = if let Some(command) = first_line.strip_prefix("#!") {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .map(|program| program.rsplit('/').next().unwrap_or(program));
+        let program = match program {
+            Some("env") => words.find(|word| !word.starts_with('-')),
+            program => program,
+        };
+        match program.unwrap_or_default() {
+            program if program.starts_with("python") => "Python",
+            "sh" | "bash" | "zsh" | "dash" | "ksh" => "Shell Script",
+            "node" | "deno" => "Javascript",
+            "make" => "Makefile",
+            _ => return &PLAIN_TEXT_MODE,
+        }
+    } else if first_line
+        .to_ascii_lowercase()
+        .starts_with("<!doctype html")
+        || first_line.starts_with("<html")
+    {
+        "HTML"
+    } else {
+        return &PLAIN_TEXT_MODE;
+    };
+    find_by_name(name).unwrap_or(&PLAIN_TEXT_MODE)
+}
+
+fn find_by_filename(filename: impl AsRef<Path>) -> &'static Mode {
     LANGUAGE_MODES
         .iter()
         .find(|&mode| mode.matches_by_filename(filename.as_ref()))
         .unwrap_or(&PLAIN_TEXT_MODE)
 }
 
-static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
+static LANGUAGE_MODES: Lazy<[Mode; 15]> = Lazy::new(|| {
     [
         Mode {
             name: "Shell Script".into(),
@@ -97,6 +175,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::BASH,
                 highlights: BASH_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Rust".into(),
@@ -105,6 +184,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::RUST,
                 highlights: RUST_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Python".into(),
@@ -126,6 +206,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::PYTHON,
                 highlights: PYTHON_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Javascript".into(),
@@ -134,6 +215,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::JAVASCRIPT,
                 highlights: JAVASCRIPT_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "HTML".into(),
@@ -147,6 +229,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::HTML,
                 highlights: HTML_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "JSON".into(),
@@ -158,6 +241,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::JSON,
                 highlights: JSON_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "C".into(),
@@ -166,6 +250,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::C,
                 highlights: C_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "C++".into(),
@@ -188,6 +273,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::CPP,
                 highlights: CPP_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "CSS".into(),
@@ -196,6 +282,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::CSS,
                 highlights: CSS_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Markdown".into(),
@@ -204,6 +291,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::MARKDOWN,
                 highlights: MARKDOWN_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Typescript".into(),
@@ -212,6 +300,7 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::TYPESCRIPT,
                 highlights: TYPESCRIPT_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Typescript TSX".into(),
@@ -220,11 +309,13 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::TSX,
                 highlights: TSX_RULES.clone(),
             }),
+            indent_style: None,
         },
         Mode {
             name: "Dockerfile".into(),
             file: vec![FilenamePattern::name("Dockerfile")],
             parser: None,
+            indent_style: None,
         },
         Mode {
             name: "Go".into(),
@@ -233,8 +324,36 @@ static LANGUAGE_MODES: Lazy<[Mode; 14]> = Lazy::new(|| {
                 language: *grammar::GO,
                 highlights: GO_RULES.clone(),
             }),
+            indent_style: Some(IndentStyle::Tabs),
+        },
+        Mode {
+            name: "Makefile".into(),
+            file: vec![
+                FilenamePattern::name("Makefile"),
+                FilenamePattern::name("makefile"),
+                FilenamePattern::name("GNUmakefile"),
+                FilenamePattern::suffix(".mk"),
+            ],
+            parser: None,
+            indent_style: Some(IndentStyle::Tabs),
         },
     ]
 });
 
 pub static PLAIN_TEXT_MODE: Lazy<Mode> = Lazy::new(Default::default);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_are_found_by_content() {
+        let name = |text| &*find_by_content(&Rope::from_str(text)).name;
+        assert_eq!(name("#!/usr/bin/env -S python3 -u\nprint()\n"), "Python");
+        assert_eq!(name("#!/bin/bash\n"), "Shell Script");
+        assert_eq!(name("<!DOCTYPE html>\n<html>\n"), "HTML");
+        assert_eq!(name("#!/usr/bin/perl\n"), "Plain");
+        assert_eq!(name("error[E0308]: mismatched types\n"), "Plain");
+        assert_eq!(name(""), "Plain");
+    }
+}
@@ -0,0 +1,140 @@
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    files, settings,
+};
+
+/// Where the cursor was in a file when it was last closed, similar to Emacs'
+/// saveplace or Vim's `'"` mark.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Place {
+    pub path: PathBuf,
+    pub cursor: usize,
+    pub first_line: usize,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Places {
+    /// Ordered from the least to the most recently closed file
+    #[serde(default)]
+    place: Vec<Place>,
+}
+
+impl Places {
+    fn update(&mut self, new_places: impl IntoIterator<Item = Place>) {
+        for new_place in new_places {
+            self.place.retain(|place| place.path != new_place.path);
+            self.place.push(new_place);
+        }
+        let num_places = self.place.len();
+        if num_places > MAX_PLACES {
+            self.place.drain(..num_places - MAX_PLACES);
+        }
+    }
+}
+
+pub fn places_path() -> Result<PathBuf> {
+    let mut path = settings::config_dir()?;
+    path.push("places.toml");
+    Ok(path)
+}
+
+/// Looks up the last known cursor position in a file. Failing to read the
+/// places file is not fatal, the file will simply open at the beginning.
+pub fn find_place(file_path: impl AsRef<Path>) -> Option<Place> {
+    let file_path = file_path.as_ref().canonicalize().ok()?;
+    read_places()
+        .map_err(|error| log::error!("{}", error))
+        .ok()?
+        .place
+        .into_iter()
+        .rev()
+        .find(|place| place.path == file_path)
+}
+
+/// Adds the places to the places file. A places file which can't be read is
+/// replaced rather than stopping newer places from being remembered.
+pub fn remember_places(places: impl IntoIterator<Item = Place>) -> Result<()> {
+    let mut stored = read_places().unwrap_or_else(|error| {
+        log::error!("{}", error);
+        Places::default()
+    });
+    stored.update(places);
+
+    let path = places_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let places_str = toml::to_string_pretty(&stored).map_err(|err| {
+        Error::Config(format!(
+            "Could not serialize places to file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    files::write_atomically(&path, false, |writer| {
+        writer.write_all(places_str.as_bytes())
+    })?;
+    Ok(())
+}
+
+fn read_places() -> Result<Places> {
+    let path = places_path()?;
+    if !path.exists() {
+        return Ok(Places::default());
+    }
+
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| {
+            Error::Config(format!(
+                "Could not read places file `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    toml::de::from_str(&contents).map_err(|err| {
+        Error::Config(format!(
+            "Could not parse places file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+const MAX_PLACES: usize = 1000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(path: &str, cursor: usize) -> Place {
+        Place {
+            path: path.into(),
+            cursor,
+            first_line: 0,
+        }
+    }
+
+    #[test]
+    fn update_moves_places_to_the_end_and_drops_the_oldest() {
+        let mut places = Places {
+            place: (0..MAX_PLACES)
+                .map(|index| place(&format!("/{}", index), index))
+                .collect(),
+        };
+        places.update(vec![place("/1", 42), place("/new", 7)]);
+
+        assert_eq!(places.place.len(), MAX_PLACES);
+        assert_eq!(places.place[0], place("/2", 2));
+        assert_eq!(places.place[MAX_PLACES - 2], place("/1", 42));
+        assert_eq!(places.place[MAX_PLACES - 1], place("/new", 7));
+    }
+}
@@ -0,0 +1,210 @@
+use ropey::Rope;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use crate::encoding;
+
+/// Above this many pairs of changed lines to compare, the changed lines are
+/// shown as all removed, then all added
+const MAX_DIFF_SIZE: usize = 4_000_000;
+
+/// The auto-save file for `path`, named Emacs style, e.g. `src/#main.rs#`
+pub fn recovery_path(path: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let file_name = path.file_name()?.to_str()?;
+    Some(path.with_file_name(format!("#{}#", file_name)))
+}
+
+/// Returns the recovery file for `path` if there's one which was written after
+/// the file itself was last modified.
+pub fn newer_recovery_file(path: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let recovery_path = recovery_path(path)?;
+    let recovery_modified = fs::metadata(&recovery_path).and_then(|metadata| metadata.modified());
+    match (
+        recovery_modified,
+        fs::metadata(path).and_then(|metadata| metadata.modified()),
+    ) {
+        (Ok(recovery_modified), Ok(modified)) if recovery_modified > modified => {
+            Some(recovery_path)
+        }
+        (Ok(_), Err(ref error)) if error.kind() == io::ErrorKind::NotFound => Some(recovery_path),
+        _ => None,
+    }
+}
+
+/// Writes the recovery file, readable only by us as it may be next to a file
+/// others can't read.
+pub fn write_recovery_file(path: impl AsRef<Path>, text: &Rope) -> io::Result<()> {
+    let recovery_path = recovery_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(recovery_path)?;
+    #[cfg(unix)]
+    {
+        // The mode is only used when creating the file, an older one keeps its own
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    text.write_to(BufWriter::new(file))
+}
+
+pub fn read_recovery_file(path: impl AsRef<Path>) -> io::Result<Rope> {
+    let recovery_path = recovery_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    Rope::from_reader(BufReader::new(File::open(recovery_path)?))
+}
+
+/// Deletes the recovery file, if any. Called once the file has been saved.
+pub fn remove_recovery_file(path: impl AsRef<Path>) -> io::Result<()> {
+    match recovery_path(path).map(fs::remove_file) {
+        Some(Err(ref error)) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Some(result) => result,
+        None => Ok(()),
+    }
+}
+
+/// A line diff of the file on disk against its recovery file, for deciding
+/// whether to recover it. A missing file is diffed as empty.
+pub fn diff_recovery_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let path = path.as_ref();
+    let original = match fs::read(path) {
+        Ok(bytes) => encoding::decode(&bytes).0,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Rope::new(),
+        Err(error) => return Err(error),
+    };
+    let recovered = read_recovery_file(path)?;
+    let recovery_path = recovery_path(path).unwrap_or_default();
+    Ok(format!(
+        "--- {}\n+++ {}\n{}",
+        path.display(),
+        recovery_path.display(),
+        diff_lines(&lines(&original), &lines(&recovered))
+    ))
+}
+
+fn lines(text: &Rope) -> Vec<String> {
+    let mut lines: Vec<_> = text
+        .lines()
+        .map(|line| {
+            line.to_string()
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned()
+        })
+        .collect();
+    // The empty line after a final newline
+    if text.len_chars() == 0 || text.char(text.len_chars() - 1) == '\n' {
+        lines.pop();
+    }
+    lines
+}
+
+/// Every line of `new`, and of `old` where they differ, prefixed by `- ` if
+/// it's only in `old`, `+ ` if it's only in `new`
+fn diff_lines(old: &[String], new: &[String]) -> String {
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let removed = &old[prefix..old.len() - suffix];
+    let added = &new[prefix..new.len() - suffix];
+
+    let mut diff = String::new();
+    let mut push = |marker: &str, line: &str| {
+        diff.push_str(marker);
+        diff.push_str(line);
+        diff.push('\n');
+    };
+    new[..prefix].iter().for_each(|line| push("  ", line));
+    if removed.len().saturating_mul(added.len()) > MAX_DIFF_SIZE {
+        removed.iter().for_each(|line| push("- ", line));
+        added.iter().for_each(|line| push("+ ", line));
+    } else {
+        // Length of the longest common subsequence of `removed[i..]` and
+        // `added[j..]`, at `i * (added.len() + 1) + j`
+        let width = added.len() + 1;
+        let mut common = vec![0u32; (removed.len() + 1) * width];
+        for i in (0..removed.len()).rev() {
+            for j in (0..added.len()).rev() {
+                common[i * width + j] = if removed[i] == added[j] {
+                    common[(i + 1) * width + j + 1] + 1
+                } else {
+                    common[(i + 1) * width + j].max(common[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < removed.len() || j < added.len() {
+            if i < removed.len() && j < added.len() && removed[i] == added[j] {
+                push("  ", &added[j]);
+                i += 1;
+                j += 1;
+            } else if j == added.len()
+                || (i < removed.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+            {
+                push("- ", &removed[i]);
+                i += 1;
+            } else {
+                push("+ ", &added[j]);
+                j += 1;
+            }
+        }
+    }
+    new[new.len() - suffix..]
+        .iter()
+        .for_each(|line| push("  ", line));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_lines() {
+        let diff = |old: &str, new: &str| {
+            diff_lines(&lines(&Rope::from_str(old)), &lines(&Rope::from_str(new)))
+        };
+        assert_eq!(diff("a\nb\n", "a\nb\n"), "  a\n  b\n");
+        assert_eq!(
+            diff("a\nb\nc\nd\n", "a\nc\nx\nd"),
+            "  a\n- b\n  c\n+ x\n  d\n"
+        );
+        assert_eq!(diff("", "a\n"), "+ a\n");
+        assert_eq!(diff("a\r\nb\n", ""), "- a\n- b\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recovery_files_are_private() {
+        use std::{env, os::unix::fs::PermissionsExt, process};
+
+        let path = env::temp_dir().join(format!("zee-recovery-{}.txt", process::id()));
+        let recovery_path = recovery_path(&path).unwrap();
+        fs::write(&recovery_path, "old").unwrap();
+        fs::set_permissions(&recovery_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_recovery_file(&path, &Rope::from_str("recovered\n")).unwrap();
+        let mode = fs::metadata(&recovery_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read_recovery_file(&path).unwrap(), "recovered\n");
+        remove_recovery_file(&path).unwrap();
+        assert!(!recovery_path.exists());
+    }
+}
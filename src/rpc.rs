@@ -0,0 +1,287 @@
+use crossbeam_channel::{self, Sender};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    thread,
+};
+
+use crate::{
+    components::ComponentId,
+    error::{Error, Result},
+    files::Position,
+};
+
+// Error codes from the JSON-RPC 2.0 spec, and one for requests the editor
+// couldn't carry out
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const EDITOR_ERROR: i64 = -32000;
+
+/// A JSON-RPC call read from a client of the server, run by the editor
+pub struct Call {
+    /// `None` for notifications, which aren't answered
+    pub id: Option<Value>,
+    pub method: Method,
+    /// Takes the response and, once subscribed, the change notifications
+    pub connection: Sender<String>,
+}
+
+/// What a client can ask the editor. Buffers are the focused one unless an
+/// id from `list-buffers` is given.
+#[derive(Debug, PartialEq)]
+pub enum Method {
+    ListBuffers,
+    OpenFile {
+        path: PathBuf,
+        position: Option<Position>,
+        read_only: bool,
+    },
+    GetCursor {
+        buffer: Option<ComponentId>,
+    },
+    SetCursor {
+        buffer: Option<ComponentId>,
+        position: Position,
+    },
+    InsertText {
+        buffer: Option<ComponentId>,
+        text: String,
+    },
+    RunCommand {
+        name: String,
+    },
+    Subscribe,
+}
+
+#[derive(Deserialize)]
+struct RawCall {
+    jsonrpc: String,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpenFileParams {
+    path: PathBuf,
+    line: Option<usize>,
+    column: Option<usize>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BufferParams {
+    buffer: Option<ComponentId>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetCursorParams {
+    buffer: Option<ComponentId>,
+    line: usize,
+    column: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InsertTextParams {
+    buffer: Option<ComponentId>,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunCommandParams {
+    name: String,
+}
+
+/// Parses a call, or returns the error response to send back
+pub fn parse_call(line: &str) -> std::result::Result<(Option<Value>, Method), String> {
+    let call: RawCall = serde_json::from_str(line).map_err(|error| {
+        let code = if error.is_data() {
+            INVALID_REQUEST
+        } else {
+            PARSE_ERROR
+        };
+        error_response(&Value::Null, code, &error.to_string())
+    })?;
+    let id = call.id.clone().unwrap_or(Value::Null);
+    if call.jsonrpc != "2.0" {
+        return Err(error_response(&id, INVALID_REQUEST, "jsonrpc must be 2.0"));
+    }
+    let invalid_params =
+        |error: serde_json::Error| error_response(&id, INVALID_PARAMS, &error.to_string());
+
+    let method = match call.method.as_str() {
+        "list-buffers" => Method::ListBuffers,
+        "open-file" => {
+            let params: OpenFileParams = params(call.params).map_err(invalid_params)?;
+            let column = params.column.unwrap_or(1);
+            Method::OpenFile {
+                path: params.path,
+                position: params.line.map(|line| Position { line, column }),
+                read_only: params.read_only,
+            }
+        }
+        "get-cursor" => {
+            let params: BufferParams = params(call.params).map_err(invalid_params)?;
+            Method::GetCursor {
+                buffer: params.buffer,
+            }
+        }
+        "set-cursor" => {
+            let params: SetCursorParams = params(call.params).map_err(invalid_params)?;
+            Method::SetCursor {
+                buffer: params.buffer,
+                position: Position {
+                    line: params.line,
+                    column: params.column.unwrap_or(1),
+                },
+            }
+        }
+        "insert-text" => {
+            let params: InsertTextParams = params(call.params).map_err(invalid_params)?;
+            Method::InsertText {
+                buffer: params.buffer,
+                text: params.text,
+            }
+        }
+        "run-command" => {
+            let params: RunCommandParams = params(call.params).map_err(invalid_params)?;
+            Method::RunCommand { name: params.name }
+        }
+        "subscribe" => Method::Subscribe,
+        method => {
+            return Err(error_response(
+                &id,
+                METHOD_NOT_FOUND,
+                &format!("unknown method `{}`", method),
+            ))
+        }
+    };
+    Ok((call.id, method))
+}
+
+/// Methods without parameters may leave them out
+fn params<T: DeserializeOwned>(params: Value) -> serde_json::Result<T> {
+    match params {
+        Value::Null => serde_json::from_value(json!({})),
+        params => serde_json::from_value(params),
+    }
+}
+
+pub fn response(id: &Value, result: Value) -> String {
+    json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string()
+}
+
+pub fn error_response(id: &Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message},
+    })
+    .to_string()
+}
+
+pub fn notification(method: &str, params: Value) -> String {
+    json!({"jsonrpc": "2.0", "method": method, "params": params}).to_string()
+}
+
+/// Reads one call per line and sends them to the editor. Responses and
+/// notifications are written from another thread, as they can come at any
+/// time after subscribing.
+pub fn serve_connection(
+    lines: impl Iterator<Item = io::Result<String>>,
+    mut writer: impl Write + Send + 'static,
+    calls: &Sender<Call>,
+) -> Result<()> {
+    let (connection, outgoing) = crossbeam_channel::unbounded::<String>();
+    thread::spawn(move || {
+        for message in outgoing.iter() {
+            if writeln!(writer, "{}", message).is_err() {
+                break;
+            }
+        }
+    });
+
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_call(&line) {
+            Ok((id, method)) => calls
+                .send(Call {
+                    id,
+                    method,
+                    connection: connection.clone(),
+                })
+                .map_err(|_| Error::Server("the editor has exited".into()))?,
+            Err(response) => {
+                let _ = connection.send(response);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_parsed() {
+        assert_eq!(
+            parse_call(r#"{"jsonrpc": "2.0", "id": 1, "method": "list-buffers"}"#),
+            Ok((Some(json!(1)), Method::ListBuffers))
+        );
+        assert_eq!(
+            parse_call(
+                r#"{"jsonrpc": "2.0", "id": "a", "method": "set-cursor", "params": {"line": 3}}"#
+            ),
+            Ok((
+                Some(json!("a")),
+                Method::SetCursor {
+                    buffer: None,
+                    position: Position { line: 3, column: 1 }
+                }
+            ))
+        );
+        assert_eq!(
+            parse_call(
+                r#"{"jsonrpc": "2.0", "method": "insert-text", "params": {"buffer": 7, "text": "hi"}}"#
+            ),
+            Ok((
+                None,
+                Method::InsertText {
+                    buffer: Some(7),
+                    text: "hi".into()
+                }
+            ))
+        );
+
+        let error_code = |line| {
+            let response: Value = serde_json::from_str(&parse_call(line).unwrap_err()).unwrap();
+            response["error"]["code"].as_i64().unwrap()
+        };
+        assert_eq!(error_code("{"), PARSE_ERROR);
+        assert_eq!(error_code(r#"{"id": 1}"#), INVALID_REQUEST);
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 1, "method": "explode"}"#),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 1, "method": "set-cursor", "params": {}}"#),
+            INVALID_PARAMS
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use ropey::Rope;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::{Error, Result},
+    files::Position,
+    settings,
+};
+
+/// A change asked for by a script. Buffer changes are made once the script
+/// returns, commands are run after them.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptAction {
+    Insert(String),
+    SetCursor(Position),
+    RunCommand(String),
+    Message(String),
+    OpenFile(PathBuf),
+}
+
+/// Functions of the init script which the editor calls when a buffer is
+/// opened or saved, given its path, or when its mode changes, given the mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    Open,
+    Save,
+    ModeChange,
+}
+
+/// What a script can read of the buffer it runs in
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub struct BufferSnapshot {
+    pub text: Rope,
+    pub path: Option<PathBuf>,
+    pub mode: String,
+    pub cursor: Position,
+}
+
+/// A command defined by the init script, listed in the command palette
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptCommand {
+    pub name: String,
+    pub description: String,
+}
+
+pub fn init_script_path() -> Result<PathBuf> {
+    Ok(settings::config_dir()?.join("init.rhai"))
+}
+
+#[cfg(feature = "scripting")]
+pub use self::engine::Scripts;
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FnPtr, ImmutableString, Map, AST};
+    use std::{cell::RefCell, mem, rc::Rc};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct State {
+        snapshot: Option<BufferSnapshot>,
+        actions: Vec<ScriptAction>,
+        commands: Vec<(ScriptCommand, FnPtr)>,
+    }
+
+    /// The init script and the engine which runs it
+    #[derive(Default)]
+    pub struct Scripts {
+        loaded: Option<Loaded>,
+    }
+
+    struct Loaded {
+        engine: Engine,
+        ast: AST,
+        state: Rc<RefCell<State>>,
+    }
+
+    impl Scripts {
+        /// Runs the init script, which defines commands with `command()` and
+        /// hooks as functions named after them
+        pub fn load(path: &Path) -> Result<Self> {
+            let state = Rc::new(RefCell::new(State::default()));
+            let engine = new_engine(&state);
+            let ast = engine
+                .compile_file(path.to_path_buf())
+                .map_err(script_error)?;
+            engine.run_ast(&ast).map_err(script_error)?;
+            Ok(Self {
+                loaded: Some(Loaded { engine, ast, state }),
+            })
+        }
+
+        pub fn commands(&self) -> Vec<ScriptCommand> {
+            self.loaded.as_ref().map_or_else(Vec::new, |loaded| {
+                let state = loaded.state.borrow();
+                state
+                    .commands
+                    .iter()
+                    .map(|(command, _)| command.clone())
+                    .collect()
+            })
+        }
+
+        pub fn has_hook(&self, hook: Hook) -> bool {
+            match self.loaded {
+                Some(ref loaded) => loaded
+                    .ast
+                    .iter_functions()
+                    .any(|function| function.name == function_name(hook)),
+                None => false,
+            }
+        }
+
+        pub fn run_command(
+            &self,
+            name: &str,
+            snapshot: Option<BufferSnapshot>,
+        ) -> Result<Vec<ScriptAction>> {
+            let loaded = match self.loaded {
+                Some(ref loaded) => loaded,
+                None => return Ok(Vec::new()),
+            };
+            let function = loaded
+                .state
+                .borrow()
+                .commands
+                .iter()
+                .find(|(command, _)| command.name == name)
+                .map(|(_, function)| function.clone())
+                .ok_or_else(|| Error::Script(format!("no command is named `{}`", name)))?;
+            loaded.run(snapshot, |engine, ast| function.call(engine, ast, ()))
+        }
+
+        pub fn run_hook(&self, hook: Hook, snapshot: BufferSnapshot) -> Result<Vec<ScriptAction>> {
+            let loaded = match self.loaded {
+                Some(ref loaded) if self.has_hook(hook) => loaded,
+                _ => return Ok(Vec::new()),
+            };
+            let argument = match hook {
+                Hook::Open | Hook::Save => snapshot
+                    .path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+                Hook::ModeChange => snapshot.mode.clone(),
+            };
+            loaded.run(Some(snapshot), |engine, ast| {
+                engine.call_fn_with_options(
+                    CallFnOptions::new().eval_ast(false),
+                    &mut Default::default(),
+                    ast,
+                    function_name(hook),
+                    (argument,),
+                )
+            })
+        }
+    }
+
+    impl Loaded {
+        fn run(
+            &self,
+            snapshot: Option<BufferSnapshot>,
+            call: impl FnOnce(&Engine, &AST) -> std::result::Result<Dynamic, Box<EvalAltResult>>,
+        ) -> Result<Vec<ScriptAction>> {
+            self.state.borrow_mut().snapshot = snapshot;
+            let result = call(&self.engine, &self.ast);
+            let mut state = self.state.borrow_mut();
+            state.snapshot = None;
+            let actions = mem::take(&mut state.actions);
+            result.map(|_| actions).map_err(script_error)
+        }
+    }
+
+    fn function_name(hook: Hook) -> &'static str {
+        match hook {
+            Hook::Open => "on_open",
+            Hook::Save => "on_save",
+            Hook::ModeChange => "on_mode_change",
+        }
+    }
+
+    type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+    const MAX_OPERATIONS: u64 = 1_000_000;
+
+    fn new_engine(state: &Rc<RefCell<State>>) -> Engine {
+        let mut engine = Engine::new();
+        // Scripts run on the UI thread, one stuck in a loop is stopped
+        engine.set_max_operations(MAX_OPERATIONS);
+        let snapshot = |state: &Rc<RefCell<State>>| -> ScriptResult<BufferSnapshot> {
+            state
+                .borrow()
+                .snapshot
+                .clone()
+                .ok_or_else(|| "no buffer is focused".into())
+        };
+
+        let state_ref = state.clone();
+        engine.register_fn("text", move || -> ScriptResult<String> {
+            Ok(snapshot(&state_ref)?.text.to_string())
+        });
+        let state_ref = state.clone();
+        engine.register_fn("path", move || -> ScriptResult<Dynamic> {
+            Ok(match snapshot(&state_ref)?.path {
+                Some(path) => path.display().to_string().into(),
+                None => Dynamic::UNIT,
+            })
+        });
+        let state_ref = state.clone();
+        engine.register_fn("mode", move || -> ScriptResult<String> {
+            Ok(snapshot(&state_ref)?.mode)
+        });
+        let state_ref = state.clone();
+        engine.register_fn("cursor", move || -> ScriptResult<Map> {
+            let cursor = snapshot(&state_ref)?.cursor;
+            let mut map = Map::new();
+            map.insert("line".into(), (cursor.line as i64).into());
+            map.insert("column".into(), (cursor.column as i64).into());
+            Ok(map)
+        });
+
+        let push = |state: &Rc<RefCell<State>>, action| state.borrow_mut().actions.push(action);
+        let state_ref = state.clone();
+        engine.register_fn("insert", move |text: ImmutableString| {
+            push(&state_ref, ScriptAction::Insert(text.into()))
+        });
+        let state_ref = state.clone();
+        engine.register_fn("set_cursor", move |line: i64, column: i64| {
+            let position = Position {
+                line: line.max(1) as usize,
+                column: column.max(1) as usize,
+            };
+            push(&state_ref, ScriptAction::SetCursor(position))
+        });
+        let state_ref = state.clone();
+        engine.register_fn("run", move |name: ImmutableString| {
+            push(&state_ref, ScriptAction::RunCommand(name.into()))
+        });
+        let state_ref = state.clone();
+        engine.register_fn("message", move |text: ImmutableString| {
+            push(&state_ref, ScriptAction::Message(text.into()))
+        });
+        let state_ref = state.clone();
+        engine.register_fn("open", move |path: ImmutableString| {
+            push(&state_ref, ScriptAction::OpenFile(path.as_str().into()))
+        });
+
+        let state_ref = state.clone();
+        engine.register_fn(
+            "command",
+            move |name: ImmutableString, description: ImmutableString, function: FnPtr| {
+                let command = ScriptCommand {
+                    name: name.into(),
+                    description: description.into(),
+                };
+                let mut state = state_ref.borrow_mut();
+                state
+                    .commands
+                    .retain(|(other, _)| other.name != command.name);
+                state.commands.push((command, function));
+            },
+        );
+        engine
+    }
+
+    fn script_error(error: Box<EvalAltResult>) -> Error {
+        Error::Script(error.to_string())
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+pub use self::disabled::Scripts;
+
+#[cfg(not(feature = "scripting"))]
+mod disabled {
+    use super::*;
+
+    /// Stands in for the init script when zee is built without the
+    /// `scripting` feature
+    #[derive(Default)]
+    pub struct Scripts;
+
+    impl Scripts {
+        pub fn load(path: &Path) -> Result<Self> {
+            Err(Error::Script(format!(
+                "{} isn't run, zee was built without the `scripting` feature",
+                path.display()
+            )))
+        }
+
+        pub fn commands(&self) -> Vec<ScriptCommand> {
+            Vec::new()
+        }
+
+        pub fn has_hook(&self, _hook: Hook) -> bool {
+            false
+        }
+
+        pub fn run_command(
+            &self,
+            name: &str,
+            _snapshot: Option<BufferSnapshot>,
+        ) -> Result<Vec<ScriptAction>> {
+            Err(Error::Script(format!("no command is named `{}`", name)))
+        }
+
+        pub fn run_hook(
+            &self,
+            _hook: Hook,
+            _snapshot: BufferSnapshot,
+        ) -> Result<Vec<ScriptAction>> {
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn scripts_define_commands_and_hooks() {
+        let path = env::temp_dir().join(format!("zee-init-{}.rhai", process::id()));
+        fs::write(
+            &path,
+            r#"
+            command("shout", "Upper case the text", || {
+                let text = text();
+                text.make_upper();
+                insert(text);
+                run("save-buffer");
+            });
+            command("spin", "Never returns", || loop {});
+
+            fn on_save(path) {
+                if mode() == "Rust" {
+                    set_cursor(cursor().line + 1, 1);
+                    message("saving " + path);
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let scripts = Scripts::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let snapshot = BufferSnapshot {
+            text: Rope::from_str("hello\n"),
+            path: Some("/tmp/a.rs".into()),
+            mode: "Rust".into(),
+            cursor: Position { line: 2, column: 3 },
+        };
+        assert_eq!(
+            scripts.commands(),
+            vec![
+                ScriptCommand {
+                    name: "shout".into(),
+                    description: "Upper case the text".into()
+                },
+                ScriptCommand {
+                    name: "spin".into(),
+                    description: "Never returns".into()
+                }
+            ]
+        );
+        assert!(scripts.run_command("spin", None).is_err());
+        assert_eq!(
+            scripts
+                .run_command("shout", Some(snapshot.clone()))
+                .unwrap(),
+            vec![
+                ScriptAction::Insert("HELLO\n".into()),
+                ScriptAction::RunCommand("save-buffer".into())
+            ]
+        );
+        assert!(scripts.run_command("shout", None).is_err());
+
+        assert!(scripts.has_hook(Hook::Save) && !scripts.has_hook(Hook::Open));
+        assert_eq!(
+            scripts.run_hook(Hook::Save, snapshot).unwrap(),
+            vec![
+                ScriptAction::SetCursor(Position { line: 3, column: 1 }),
+                ScriptAction::Message("saving /tmp/a.rs".into())
+            ]
+        );
+    }
+}
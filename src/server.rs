@@ -0,0 +1,335 @@
+use crossbeam_channel::{self, Receiver, Sender};
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+
+#[cfg(unix)]
+use std::{
+    io,
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    process,
+};
+
+use crate::{
+    error::{Error, Result},
+    files::Position,
+    rpc,
+};
+
+/// Files which `zee --client` asked the server to open
+#[derive(Debug)]
+pub struct OpenRequest {
+    pub files: Vec<(PathBuf, Option<Position>)>,
+    pub wait: bool,
+    pub read_only: bool,
+    /// Takes a message for each file which couldn't be opened. The client is
+    /// told the request is done once this is dropped, so a waiting request
+    /// keeps it until its buffers are closed.
+    pub errors: Sender<String>,
+}
+
+/// Accepts clients on a Unix socket, which is removed when this is dropped
+pub struct Server {
+    path: PathBuf,
+    pub requests: Receiver<OpenRequest>,
+    pub calls: Receiver<rpc::Call>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Where the server listens unless given `--socket`
+pub fn socket_path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("zee.sock"),
+        None => env::temp_dir().join(format!("zee-{}.sock", user_id())),
+    }
+}
+
+// A client sends one line for each file, `open <line>:<column> <path>` or
+// `open - <path>`, followed by `wait` and `read-only` if needed. It then shuts
+// down its side of the socket. The server answers with `error <message>` for
+// each file which couldn't be opened and `done` when the request is finished.
+//
+// A connection which starts with a JSON object speaks JSON-RPC instead, see
+// `rpc::serve_connection`.
+
+#[cfg(unix)]
+pub fn listen(path: &Path) -> Result<Server> {
+    check_owner(path)?;
+    if UnixStream::connect(path).is_ok() {
+        return Err(Error::Server(format!(
+            "a zee server is already listening on {}",
+            path.display()
+        )));
+    }
+    // Left behind by a server which didn't exit cleanly
+    let _ = fs::remove_file(path);
+    let listener = bind_privately(path)?;
+
+    let (request_sender, requests) = crossbeam_channel::unbounded();
+    let (call_sender, calls) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+            let request_sender = request_sender.clone();
+            let call_sender = call_sender.clone();
+            thread::spawn(move || {
+                if let Err(error) = serve_client(stream, &request_sender, &call_sender) {
+                    log::warn!("Client request failed: {}", error);
+                }
+            });
+        }
+    });
+    Ok(Server {
+        path: path.to_path_buf(),
+        requests,
+        calls,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path) -> Result<Server> {
+    Err(unsupported())
+}
+
+/// Asks the server listening on `socket` to open `files` and returns the
+/// errors for the ones it couldn't. With `wait`, returns once they're closed.
+#[cfg(unix)]
+pub fn open_in_server(
+    socket: &Path,
+    files: &[(PathBuf, Option<Position>)],
+    wait: bool,
+    read_only: bool,
+) -> Result<Vec<String>> {
+    check_owner(socket)?;
+    let mut stream = UnixStream::connect(socket).map_err(|error| {
+        Error::Server(format!(
+            "no zee server is listening on {}: {}",
+            socket.display(),
+            error
+        ))
+    })?;
+    for (path, position) in files {
+        let position = match position {
+            Some(Position { line, column }) => format!("{}:{}", line, column),
+            None => "-".into(),
+        };
+        writeln!(stream, "open {} {}", position, path.display())?;
+    }
+    if wait {
+        writeln!(stream, "wait")?;
+    }
+    if read_only {
+        writeln!(stream, "read-only")?;
+    }
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut errors = Vec::new();
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line == "done" {
+            break;
+        } else if let Some(error) = line.strip_prefix("error ") {
+            errors.push(error.to_owned());
+        }
+    }
+    Ok(errors)
+}
+
+#[cfg(not(unix))]
+pub fn open_in_server(
+    _socket: &Path,
+    _files: &[(PathBuf, Option<Position>)],
+    _wait: bool,
+    _read_only: bool,
+) -> Result<Vec<String>> {
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+fn serve_client(
+    stream: UnixStream,
+    requests: &Sender<OpenRequest>,
+    calls: &Sender<rpc::Call>,
+) -> Result<()> {
+    let mut lines = BufReader::new(stream.try_clone()?).lines().peekable();
+    if let Some(Ok(line)) = lines.peek() {
+        if line.starts_with('{') {
+            return rpc::serve_connection(lines, stream, calls);
+        }
+    }
+
+    let (errors, error_messages) = crossbeam_channel::unbounded();
+    let mut request = OpenRequest {
+        files: Vec::new(),
+        wait: false,
+        read_only: false,
+        errors,
+    };
+    for line in lines {
+        match parse_line(&line?, &mut request) {
+            Ok(()) => {}
+            Err(message) => request.errors.send(message).unwrap(),
+        }
+    }
+    // Nothing to open, e.g. a new server checking whether this one is running
+    if request.files.is_empty() {
+        drop(request);
+    } else {
+        requests
+            .send(request)
+            .map_err(|_| Error::Server("the editor has exited".into()))?;
+    }
+
+    // Ends once the editor is done with the request and drops its sender
+    let mut writer = &stream;
+    for message in error_messages.iter() {
+        writeln!(writer, "error {}", message)?;
+    }
+    writeln!(writer, "done")?;
+    Ok(())
+}
+
+fn parse_line(line: &str, request: &mut OpenRequest) -> std::result::Result<(), String> {
+    match line {
+        "wait" => request.wait = true,
+        "read-only" => request.read_only = true,
+        _ => {
+            let (position, path) = line
+                .strip_prefix("open ")
+                .and_then(|open| open.split_once(' '))
+                .ok_or_else(|| format!("unknown request `{}`", line))?;
+            let position = match position {
+                "-" => None,
+                position => Some(
+                    Position::parse(position)
+                        .ok_or_else(|| format!("invalid position `{}`", position))?,
+                ),
+            };
+            request.files.push((PathBuf::from(path), position));
+        }
+    }
+    Ok(())
+}
+
+/// Clients can edit files and run commands, so only this user may connect.
+/// The socket is bound in a directory only we can enter and made private
+/// before it's moved into place, it's never reachable with the umask's mode.
+#[cfg(unix)]
+fn bind_privately(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.with_file_name(format!(".zee-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join("s");
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+/// Refuses a socket created by another user, e.g. in the shared temporary
+/// directory, which could be listening in place of a zee server
+#[cfg(unix)]
+fn check_owner(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.uid() != user_id() => Err(Error::Server(format!(
+            "{} is owned by another user",
+            path.display()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn user_id() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn user_id() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> Error {
+    Error::Server("the client and server need Unix sockets".into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_wait_for_the_server() {
+        let socket = env::temp_dir().join(format!("zee-server-test-{}.sock", process::id()));
+        let server = listen(&socket).unwrap();
+        assert!(listen(&socket).is_err());
+        assert_eq!(
+            fs::metadata(&socket).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let client_socket = socket.clone();
+        let client = thread::spawn(move || {
+            let files = [
+                ("/tmp/a.rs".into(), Some(Position { line: 3, column: 4 })),
+                ("/tmp/with space.txt".into(), None),
+            ];
+            open_in_server(&client_socket, &files, true, false).unwrap()
+        });
+
+        let request = server.requests.recv().unwrap();
+        assert!(request.wait && !request.read_only);
+        assert_eq!(
+            request.files,
+            vec![
+                (
+                    PathBuf::from("/tmp/a.rs"),
+                    Some(Position { line: 3, column: 4 })
+                ),
+                (PathBuf::from("/tmp/with space.txt"), None),
+            ]
+        );
+        request.errors.send("couldn't open a.rs".into()).unwrap();
+        drop(request);
+        assert_eq!(client.join().unwrap(), vec!["couldn't open a.rs"]);
+
+        drop(server);
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn json_connections_are_answered_over_rpc() {
+        let socket = env::temp_dir().join(format!("zee-rpc-test-{}.sock", process::id()));
+        let server = listen(&socket).unwrap();
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(
+            stream,
+            r#"{{"jsonrpc": "2.0", "id": 1, "method": "list-buffers"}}"#
+        )
+        .unwrap();
+        let call = server.calls.recv().unwrap();
+        assert_eq!(call.method, rpc::Method::ListBuffers);
+        call.connection
+            .send(rpc::response(&call.id.unwrap(), serde_json::json!([])))
+            .unwrap();
+
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":[]}\n");
+    }
+}
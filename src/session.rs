@@ -0,0 +1,318 @@
+use serde_derive::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    components::{ComponentId, Flex, Layout, LayoutDirection, LayoutNode, LayoutNodeFlex},
+    error::{Error, Result},
+    files, settings,
+};
+
+/// The open buffers, how they are laid out and the selected theme. Sessions
+/// are saved per working directory when zee exits.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Session {
+    pub theme_index: usize,
+
+    /// Index in `buffers` of the focused pane
+    pub focus: Option<usize>,
+
+    pub buffers: Vec<BufferSession>,
+    pub layout: Option<SessionLayout>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BufferSession {
+    pub path: PathBuf,
+    pub cursor: usize,
+    pub first_line: usize,
+}
+
+/// Mirrors `Layout`, but refers to buffers by their index in
+/// `Session::buffers` rather than by component id.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SessionLayout {
+    Buffer {
+        index: usize,
+    },
+    Node {
+        direction: LayoutDirection,
+        children: Vec<SessionLayoutChild>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SessionLayoutChild {
+    /// `None` if the child is stretched, otherwise its fixed size
+    pub fixed_size: Option<usize>,
+    pub node: SessionLayout,
+}
+
+impl SessionLayout {
+    /// Converts a layout, dropping any component for which `buffer_index`
+    /// returns `None` (e.g. the splash screen).
+    pub fn from_layout(
+        layout: &Layout,
+        buffer_index: &impl Fn(ComponentId) -> Option<usize>,
+    ) -> Option<Self> {
+        match *layout {
+            Layout::Component(id) => buffer_index(id).map(|index| SessionLayout::Buffer { index }),
+            Layout::Node(ref node) => {
+                let mut children: Vec<_> = node
+                    .children
+                    .iter()
+                    .filter_map(|child| {
+                        Self::from_layout(&child.node, buffer_index).map(|node| {
+                            SessionLayoutChild {
+                                fixed_size: match child.flex {
+                                    Flex::Fixed(size) => Some(size),
+                                    Flex::Stretched => None,
+                                },
+                                node,
+                            }
+                        })
+                    })
+                    .collect();
+                match children.len() {
+                    0 => None,
+                    1 => Some(children.remove(0).node),
+                    _ => Some(SessionLayout::Node {
+                        direction: node.direction,
+                        children,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Converts back to a layout, dropping any buffer for which
+    /// `component_id` returns `None` (e.g. files that couldn't be opened).
+    pub fn to_layout(
+        &self,
+        component_id: &impl Fn(usize) -> Option<ComponentId>,
+    ) -> Option<Layout> {
+        match *self {
+            SessionLayout::Buffer { index } => component_id(index).map(Layout::Component),
+            SessionLayout::Node {
+                direction,
+                ref children,
+            } => {
+                let mut children: SmallVec<[LayoutNodeFlex; 4]> = children
+                    .iter()
+                    .filter_map(|child| {
+                        child
+                            .node
+                            .to_layout(component_id)
+                            .map(|node| LayoutNodeFlex {
+                                node,
+                                flex: child.fixed_size.map_or(Flex::Stretched, Flex::Fixed),
+                            })
+                    })
+                    .collect();
+                match children.len() {
+                    0 => None,
+                    1 => Some(children.remove(0).node),
+                    _ => Some(Layout::Node(Box::new(LayoutNode {
+                        direction,
+                        children,
+                    }))),
+                }
+            }
+        }
+    }
+}
+
+pub fn session_path(working_dir: impl AsRef<Path>) -> Result<PathBuf> {
+    let mut path = settings::config_dir()?;
+    path.push("sessions");
+    path.push(session_file_name(working_dir.as_ref()));
+    Ok(path)
+}
+
+pub fn read_session(working_dir: impl AsRef<Path>) -> Result<Option<Session>> {
+    let path = session_path(working_dir)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| {
+            Error::Config(format!(
+                "Could not read session file `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    log::info!("Reading session file `{}`", path.display());
+    toml::de::from_str(&contents).map(Some).map_err(|err| {
+        Error::Config(format!(
+            "Could not parse session file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+pub fn write_session(working_dir: impl AsRef<Path>, session: &Session) -> Result<()> {
+    let path = session_path(working_dir)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let session_str = toml::to_string_pretty(session).map_err(|err| {
+        Error::Config(format!(
+            "Could not serialize session to file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    files::write_atomically(&path, false, |writer| {
+        writer.write_all(session_str.as_bytes())
+    })?;
+    log::info!("Saved session to `{}`", path.display());
+    Ok(())
+}
+
+/// Names the session after the directory, e.g. `/home/zee/src` ->
+/// `src-<hash>.toml`. The hash of the whole canonical path tells apart
+/// directories with the same name, while the name is short enough for any
+/// file system.
+fn session_file_name(working_dir: &Path) -> String {
+    let working_dir = working_dir
+        .canonicalize()
+        .unwrap_or_else(|_| working_dir.to_path_buf());
+    let name: String = working_dir
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(|character| character.is_alphanumeric() || "-_.".contains(*character))
+        .take(MAX_NAME_CHARS)
+        .collect();
+    format!(
+        "{}-{:016x}.toml",
+        name,
+        fnv1a_hash(working_dir.to_string_lossy().as_bytes())
+    )
+}
+
+/// A hash which, unlike `DefaultHasher`'s, stays the same across versions
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+const MAX_NAME_CHARS: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_layout() -> SessionLayout {
+        SessionLayout::Node {
+            direction: LayoutDirection::Horizontal,
+            children: vec![
+                SessionLayoutChild {
+                    fixed_size: None,
+                    node: SessionLayout::Buffer { index: 1 },
+                },
+                SessionLayoutChild {
+                    fixed_size: Some(40),
+                    node: SessionLayout::Node {
+                        direction: LayoutDirection::Vertical,
+                        children: vec![
+                            SessionLayoutChild {
+                                fixed_size: None,
+                                node: SessionLayout::Buffer { index: 0 },
+                            },
+                            SessionLayoutChild {
+                                fixed_size: None,
+                                node: SessionLayout::Buffer { index: 2 },
+                            },
+                        ],
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn session_file_names_are_distinct_and_short() {
+        let name = |path: &str| session_file_name(Path::new(path));
+        assert_ne!(name("/zee-missing/a!/b"), name("/zee-missing/a/!b"));
+        assert_ne!(name("/zee-missing/a/src"), name("/zee-missing/b/src"));
+        assert_eq!(name("/zee-missing/src"), name("/zee-missing/src"));
+        assert!(name("/zee-missing/a/src").starts_with("src-"));
+
+        let long = format!("/zee-missing/{}", "é".repeat(300));
+        assert!(name(&long).len() < 255);
+    }
+
+    #[test]
+    fn session_roundtrips_through_toml() {
+        let session = Session {
+            theme_index: 3,
+            focus: Some(2),
+            buffers: vec![
+                BufferSession {
+                    path: "src/main.rs".into(),
+                    cursor: 120,
+                    first_line: 4,
+                },
+                BufferSession {
+                    path: "README.md".into(),
+                    cursor: 0,
+                    first_line: 0,
+                },
+                BufferSession {
+                    path: "Cargo.toml".into(),
+                    cursor: 7,
+                    first_line: 0,
+                },
+            ],
+            layout: Some(split_layout()),
+        };
+        let serialized = toml::to_string_pretty(&session).unwrap();
+        assert_eq!(session, toml::de::from_str(&serialized).unwrap());
+    }
+
+    #[test]
+    fn layout_roundtrips_through_component_ids() {
+        let layout = split_layout();
+        let component_layout = layout.to_layout(&|index| Some(index + 10)).unwrap();
+        assert_eq!(
+            Some(layout),
+            SessionLayout::from_layout(&component_layout, &|id| Some(id - 10))
+        );
+    }
+
+    #[test]
+    fn missing_buffers_collapse_layout_nodes() {
+        let layout = split_layout()
+            .to_layout(&|index| if index == 1 { None } else { Some(index) })
+            .unwrap();
+        assert_eq!(
+            SessionLayout::from_layout(&layout, &Some),
+            Some(SessionLayout::Node {
+                direction: LayoutDirection::Vertical,
+                children: vec![
+                    SessionLayoutChild {
+                        fixed_size: None,
+                        node: SessionLayout::Buffer { index: 0 },
+                    },
+                    SessionLayoutChild {
+                        fixed_size: None,
+                        node: SessionLayout::Buffer { index: 2 },
+                    },
+                ],
+            })
+        );
+    }
+}
@@ -1,65 +1,368 @@
 use dirs;
 use serde_derive::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs::{self, File},
-    io::{Read, Write},
+    io::Write,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use crate::error::{Error, Result};
+use crate::{
+    components::theme::THEMES,
+    error::{Error, Result},
+    indentation::{IndentStyle, DEFAULT_TAB_WIDTH},
+    keymap::{self, Keymap},
+    mode,
+};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
     pub theme_index: usize,
+
+    /// Restore the last session saved in the working directory when zee is
+    /// started without any files
+    #[serde(default)]
+    pub restore_session: bool,
+
+    /// Keep a copy of the previous version of a file as `file~` when saving
+    #[serde(default)]
+    pub backup_on_save: bool,
+
+    /// Which lines to strip trailing whitespace from when saving
+    #[serde(default)]
+    pub trim_trailing_whitespace: TrimTrailingWhitespace,
+
+    /// Make sure files end with a newline when saving, or that they don't if
+    /// set to false
+    #[serde(default = "default_insert_final_newline")]
+    pub insert_final_newline: bool,
+
+    /// Whether to indent with `tabs` or `spaces` in files where it can't be
+    /// detected from their content
+    #[serde(default)]
+    pub indent_style: IndentStyle,
+
+    /// The number of columns of one level of indentation with spaces,
+    /// defaults to `tab_width`
+    pub indent_size: Option<usize>,
+
+    /// The number of columns a tab is drawn as
+    #[serde(default = "default_tab_width")]
+    pub tab_width: usize,
+
+    /// Show line numbers to the left of buffers
+    #[serde(default)]
+    pub line_numbers: bool,
+
+    /// Wrap lines which are wider than the window instead of cutting them off
+    #[serde(default)]
+    pub wrap_lines: bool,
+
+    /// The style of the key bindings in buffers, `emacs` or the modal `vi`
+    #[serde(default)]
+    pub keymap: KeymapStyle,
+
+    /// Overrides for files in a given mode, e.g. `[mode.markdown]`. Mode names
+    /// are matched case insensitively.
+    #[serde(default, rename = "mode")]
+    pub modes: HashMap<String, ModeSettings>,
+
+    /// Changes to the default key bindings
+    #[serde(default)]
+    pub keys: KeySettings,
+
+    /// Keyboard macros saved by name, as the keys they press, e.g.
+    /// `fix-header = "C-a C-k C-n"`
+    #[serde(default)]
+    pub macros: HashMap<String, String>,
+}
+
+impl Settings {
+    pub fn mode(&self, name: &str) -> Option<&ModeSettings> {
+        self.modes
+            .iter()
+            .find(|(mode_name, _)| mode_name.eq_ignore_ascii_case(name))
+            .map(|(_, settings)| settings)
+    }
+
+    /// Checks the values which can't be expressed by their types, e.g. that
+    /// every `[mode.<name>]` table refers to a mode which exists.
+    pub fn validate(&self) -> Result<()> {
+        if self.theme_index >= THEMES.len() {
+            return Err(Error::Config(format!(
+                "`theme_index` is {}, but there are only {} themes",
+                self.theme_index,
+                THEMES.len()
+            )));
+        }
+        validate_width("tab_width", Some(self.tab_width)).map_err(Error::Config)?;
+        validate_width("indent_size", self.indent_size).map_err(Error::Config)?;
+
+        for (name, mode_settings) in self.modes.iter() {
+            if mode::find_by_name(name).is_none() {
+                return Err(Error::Config(format!(
+                    "unknown mode `[mode.{}]`, expected one of {}",
+                    name,
+                    mode::names().collect::<Vec<_>>().join(", ")
+                )));
+            }
+            mode_settings
+                .validate()
+                .map_err(|error| Error::Config(format!("`[mode.{}]` {}", name, error)))?;
+        }
+        Keymap::new(&self.keys)?;
+
+        for (name, keys) in self.macros.iter() {
+            if !is_valid_macro_name(name) {
+                return Err(Error::Config(format!(
+                    "`[macros]` `{}` isn't a valid name, use letters, digits, `-` and `_`",
+                    name
+                )));
+            }
+            keymap::parse_keys(keys).map_err(|error| {
+                Error::Config(format!("`[macros]` `{}` has invalid keys: {}", name, error))
+            })?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        Settings { theme_index: 0 }
+        Settings {
+            theme_index: 0,
+            restore_session: false,
+            backup_on_save: false,
+            trim_trailing_whitespace: TrimTrailingWhitespace::default(),
+            insert_final_newline: default_insert_final_newline(),
+            indent_style: IndentStyle::default(),
+            indent_size: None,
+            tab_width: default_tab_width(),
+            line_numbers: false,
+            wrap_lines: false,
+            keymap: KeymapStyle::default(),
+            modes: HashMap::new(),
+            keys: KeySettings::default(),
+            macros: HashMap::new(),
+        }
     }
 }
 
-pub fn settings_path() -> Result<PathBuf> {
-    let mut path = dirs::config_dir()
+/// Settings which can be overridden per mode. Unset fields fall back to the
+/// global settings.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModeSettings {
+    pub trim_trailing_whitespace: Option<TrimTrailingWhitespace>,
+    pub insert_final_newline: Option<bool>,
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub line_numbers: Option<bool>,
+    pub wrap_lines: Option<bool>,
+
+    /// A shell command run before saving, which reads the text on stdin and
+    /// writes it formatted to stdout, e.g. `rustfmt --emit stdout`
+    pub format_on_save: Option<String>,
+
+    /// Globs of files to open in this mode, in addition to the mode's own,
+    /// e.g. `["*.bzl", "BUILD"]`. They use the same syntax as section names
+    /// in `.editorconfig` files.
+    #[serde(default)]
+    pub file_patterns: Vec<String>,
+}
+
+impl ModeSettings {
+    fn validate(&self) -> std::result::Result<(), String> {
+        validate_width("tab_width", self.tab_width)?;
+        validate_width("indent_size", self.indent_size)?;
+        match self.format_on_save {
+            Some(ref command) if command.trim().is_empty() => {
+                return Err("`format_on_save` is empty".into())
+            }
+            _ => {}
+        }
+        if self.file_patterns.iter().any(|pattern| pattern.is_empty()) {
+            return Err("`file_patterns` contains an empty pattern".into());
+        }
+        Ok(())
+    }
+}
+
+pub fn is_valid_macro_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-_".contains(character))
+}
+
+/// Adds a named macro to the `[macros]` table of the settings file, creating
+/// the table or the file if needed. The file is edited as text to keep its
+/// comments and layout.
+pub fn save_macro(path: impl AsRef<Path>, name: &str, keys: &str) -> Result<()> {
+    let path = path.as_ref();
+    if !is_valid_macro_name(name) {
+        return Err(Error::Config(format!(
+            "`{}` isn't a valid macro name, use letters, digits, `-` and `_`",
+            name
+        )));
+    }
+    if read_settings(path)?.macros.contains_key(name) {
+        return Err(Error::Config(format!(
+            "A macro named `{}` already exists",
+            name
+        )));
+    }
+
+    let contents = if path.exists() {
+        fs::read_to_string(path).map_err(|err| {
+            Error::Config(format!(
+                "Could not read settings file `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?
+    } else {
+        String::new()
+    };
+    let line = format!("{} = {}\n", name, toml::Value::String(keys.into()));
+    let mut lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    match lines.iter().position(|line| line.trim() == "[macros]") {
+        Some(index) => lines.insert(index + 1, &line),
+        None => {
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                lines.push("\n");
+            }
+            lines.push("\n[macros]\n");
+            lines.push(&line);
+        }
+    }
+    fs::write(path, lines.concat()).map_err(|err| {
+        Error::Config(format!(
+            "Could not write settings file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+/// Key bindings for the editor, which are active everywhere, for buffers and
+/// for the prompt. Keys are written like `"C-x C-s"` and map to the name of
+/// an action, or to `"unbind"` to remove a default binding.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySettings {
+    #[serde(default)]
+    pub editor: HashMap<String, String>,
+    #[serde(default)]
+    pub buffer: HashMap<String, String>,
+    #[serde(default)]
+    pub prompt: HashMap<String, String>,
+}
+
+fn validate_width(name: &str, width: Option<usize>) -> std::result::Result<(), String> {
+    match width {
+        Some(width) if width == 0 || width > MAX_WIDTH => Err(format!(
+            "`{}` is {}, but must be between 1 and {}",
+            name, width, MAX_WIDTH
+        )),
+        _ => Ok(()),
+    }
+}
+
+const MAX_WIDTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrimTrailingWhitespace {
+    Always,
+    /// Only lines which were added or changed since the file was last read or
+    /// saved, to avoid noisy diffs in files we don't own
+    EditedLines,
+    Never,
+}
+
+impl Default for TrimTrailingWhitespace {
+    fn default() -> Self {
+        Self::Always
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeymapStyle {
+    #[default]
+    Emacs,
+    /// Normal, insert and visual modes like vi, the Emacs bindings are used
+    /// for the keys vi doesn't bind
+    Vi,
+}
+
+fn default_insert_final_newline() -> bool {
+    true
+}
+
+fn default_tab_width() -> usize {
+    DEFAULT_TAB_WIDTH
+}
+
+pub fn config_dir() -> Result<PathBuf> {
+    dirs::config_dir()
         .ok_or_else(|| Error::Config("Could not get path to the user's config directory".into()))
         .map(|mut config_dir| {
             config_dir.push("zee");
             config_dir
-        })?;
+        })
+}
+
+pub fn settings_path() -> Result<PathBuf> {
+    let mut path = config_dir()?;
     path.push("settings.toml");
     Ok(path)
 }
 
-pub fn read_settings(path: impl AsRef<Path>) -> Settings {
-    if path.as_ref().exists() {
-        File::open(path.as_ref())
-            .and_then(|mut file| {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                Ok(contents)
-            })
-            .map_err(|err| {
-                Error::Config(format!(
-                    "Could not read settings file `{}`: {}",
-                    path.as_ref().display(),
-                    err
-                ))
-            })
-            .and_then(|contents| {
-                log::info!("Reading settings file `{}`", path.as_ref().display());
-                toml::de::from_str(&contents).map_err(|err| {
-                    Error::Config(format!(
-                        "Could not parse settings file `{}`: {}",
-                        path.as_ref().display(),
-                        err
-                    ))
-                })
-            })
-            .map_err(|err| log::error!("{}", err))
-            .unwrap_or_else(|_| Default::default())
-    } else {
-        Default::default()
+/// Reads and validates the settings file, the default settings are used if
+/// it doesn't exist
+pub fn read_settings(path: impl AsRef<Path>) -> Result<Settings> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(Default::default());
     }
+
+    log::info!("Reading settings file `{}`", path.display());
+    let contents = fs::read_to_string(path).map_err(|err| {
+        Error::Config(format!(
+            "Could not read settings file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    let settings: Settings = toml::de::from_str(&contents).map_err(|err| {
+        Error::Config(format!(
+            "Could not parse settings file `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    settings.validate().map_err(|err| match err {
+        Error::Config(message) => Error::Config(format!(
+            "Invalid settings file `{}`: {}",
+            path.display(),
+            message
+        )),
+        err => err,
+    })?;
+    Ok(settings)
+}
+
+/// The last time the settings file was modified, to check if it needs to be
+/// read again. `None` if it doesn't exist.
+pub fn modified_time(path: impl AsRef<Path>) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
 }
 
 pub fn create_default_file(path: impl AsRef<Path>) -> Result<()> {
@@ -91,3 +394,118 @@ pub fn create_default_file(path: impl AsRef<Path>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn parse(contents: &str) -> Result<Settings> {
+        let settings: Settings =
+            toml::de::from_str(contents).map_err(|err| Error::Config(err.to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn error_message(contents: &str) -> String {
+        match parse(contents) {
+            Err(Error::Config(message)) => message,
+            result => panic!("expected a config error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn mode_settings_are_validated() {
+        let settings = parse(
+            "theme_index = 0\n\
+             [mode.go]\n\
+             indent_style = \"tabs\"\n\
+             tab_width = 8\n\
+             [mode.\"shell script\"]\n\
+             file_patterns = [\"*.bash\", \"PKGBUILD\"]\n",
+        )
+        .unwrap();
+        assert_eq!(settings.mode("Go").unwrap().tab_width, Some(8));
+
+        assert!(error_message("theme_index = 0\n[mode.cobol]\n").contains("unknown mode"));
+        assert!(
+            error_message("theme_index = 0\n[mode.rust]\ntab_width = 0\n")
+                .contains("`[mode.rust]` `tab_width` is 0")
+        );
+        assert!(error_message("theme_index = 0\ntab_wdith = 4\n").contains("tab_wdith"));
+        assert!(error_message("theme_index = 1000\n").contains("theme_index"));
+        assert!(
+            error_message("theme_index = 0\n[keys.buffer]\n\"C-x\" = \"undo\"\n")
+                .contains("is a prefix of")
+        );
+    }
+
+    #[test]
+    fn file_patterns_take_precedence() {
+        let settings = parse(
+            "theme_index = 0\n\
+             [mode.python]\n\
+             file_patterns = [\"*.bzl\", \"BUILD\", \"/opt/*.rs\"]\n\
+             [mode.json]\n\
+             file_patterns = [\"/opt/config/*.bzl\", \"zee-scripts/*.rs\"]\n",
+        )
+        .unwrap();
+        let mode_name = |path: &str| mode::find(Path::new(path), &settings).name.to_string();
+        assert_eq!(mode_name("/src/defs.bzl"), "Python");
+        assert_eq!(mode_name("/src/BUILD"), "Python");
+        assert_eq!(mode_name("/opt/main.rs"), "Python");
+        assert_eq!(mode_name("/src/main.rs"), "Rust");
+        assert_eq!(mode_name("/src/notes.txt"), "Plain");
+
+        // The longest matching pattern wins, relative ones start from the
+        // working directory
+        assert_eq!(mode_name("/opt/config/defs.bzl"), "JSON");
+        assert_eq!(mode_name("zee-scripts/main.rs"), "JSON");
+        assert_eq!(mode_name("/zee-scripts/main.rs"), "Rust");
+    }
+
+    #[test]
+    fn read_settings_reports_errors() {
+        let path = std::env::temp_dir().join(format!("zee-settings-{}.toml", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(read_settings(&path).is_ok());
+        assert_eq!(modified_time(&path), None);
+
+        fs::write(&path, "theme_index = 0\nline_numbers = \"yes\"\n").unwrap();
+        match read_settings(&path) {
+            Err(Error::Config(message)) => assert!(message.contains("Could not parse")),
+            result => panic!("expected a config error, got {:?}", result),
+        }
+        assert!(modified_time(&path).is_some());
+
+        fs::write(&path, "theme_index = 0\nline_numbers = true\n").unwrap();
+        assert!(read_settings(&path).unwrap().line_numbers);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn macros_are_added_to_the_settings_file() {
+        let path = std::env::temp_dir().join(format!("zee-macros-{}.toml", std::process::id()));
+        fs::write(&path, "# My settings\ntheme_index = 0").unwrap();
+        save_macro(&path, "quote", "\" C-e \" RET").unwrap();
+        save_macro(&path, "kill-two", "C-k C-k").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# My settings\ntheme_index = 0\n\n[macros]\n"));
+
+        let settings = read_settings(&path).unwrap();
+        assert_eq!(settings.macros["quote"], "\" C-e \" RET");
+        assert_eq!(settings.macros["kill-two"], "C-k C-k");
+        match save_macro(&path, "quote", "C-a") {
+            Err(Error::Config(message)) => assert!(message.contains("already exists")),
+            result => panic!("expected a config error, got {:?}", result),
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(
+            error_message("theme_index = 0\n[macros]\n\"a b\" = \"C-a\"\n")
+                .contains("isn't a valid name")
+        );
+        assert!(error_message("theme_index = 0\n[macros]\nab = \"C-M-a\"\n")
+            .contains("has invalid keys"));
+    }
+}
@@ -20,6 +20,9 @@ pub struct UndoTree {
     revisions: Vec<Revision>,
     parent_revision_index: usize,
     head: Rope,
+    // Set while edits are merged into a single revision, with the index of
+    // the revision they're merged into once there is one
+    group: Option<Option<usize>>,
 }
 
 impl UndoTree {
@@ -36,10 +39,45 @@ impl UndoTree {
             revisions: vec![root],
             parent_revision_index: 0,
             head: text,
+            group: None,
         }
     }
 
+    /// Merges the revisions until `end_group` into one, so they're undone
+    /// together
+    pub fn begin_group(&mut self) {
+        self.group = Some(None);
+    }
+
+    pub fn end_group(&mut self) {
+        self.group = None;
+    }
+
     pub fn new_revision(&mut self, diff: OpaqueDiff, cursor: Cursor) {
+        if let Some(Some(index)) = self.group {
+            // Unless the grouped revision was undone in the meantime
+            if index == self.parent_revision_index {
+                let parent_index = self.revisions[index]
+                    .parent
+                    .as_ref()
+                    .map(|parent| parent.index)
+                    .unwrap_or(0);
+                let diff = OpaqueDiff::new(
+                    0,
+                    self.revisions[parent_index].text.len_bytes(),
+                    self.head.len_bytes(),
+                );
+                let revision = &mut self.revisions[index];
+                revision.text = self.head.clone();
+                revision.cursor = cursor;
+                revision.parent = Some(Reference {
+                    index: parent_index,
+                    diff,
+                });
+                return;
+            }
+        }
+
         self.revisions.push(Revision {
             text: self.head.clone(),
             cursor,
@@ -49,6 +87,9 @@ impl UndoTree {
             }),
         });
         self.parent_revision_index = self.revisions.len() - 1;
+        if let Some(ref mut group) = self.group {
+            *group = Some(self.parent_revision_index);
+        }
     }
 
     pub fn undo(&mut self) -> Option<(OpaqueDiff, Cursor)> {
@@ -125,4 +166,29 @@ mod tests {
         tree.insert(position, " violet.");
         assert_eq!("The flowers are... violet.\n", &tree.to_string());
     }
+
+    #[test]
+    fn grouped_revisions_are_merged() {
+        let mut tree = UndoTree::new(Rope::from("a\n"));
+        tree.begin_group();
+        for text in &["b", "c", "d"] {
+            tree.insert(0, text);
+            tree.new_revision(
+                OpaqueDiff::new(0, 0, text.len()),
+                Cursor::end_of_buffer(&tree),
+            );
+        }
+        tree.end_group();
+        assert_eq!(tree.revisions.len(), 2);
+        assert_eq!(tree.revisions[1].text.to_string(), "dcba\n");
+
+        tree.insert(0, "e");
+        tree.new_revision(OpaqueDiff::new(0, 0, 1), Cursor::end_of_buffer(&tree));
+        assert_eq!(tree.revisions.len(), 3);
+
+        // The merged revision is undone with a single diff
+        tree.undo();
+        let (diff, _) = tree.undo().unwrap();
+        assert_eq!(diff, OpaqueDiff::new(0, 5, 2));
+    }
 }
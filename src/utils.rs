@@ -1,5 +1,10 @@
 use ropey::{iter::Chunks, Rope, RopeSlice};
-use std::{mem, path::PathBuf};
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::Hasher,
+    mem,
+    path::PathBuf,
+};
 use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
 use unicode_width::UnicodeWidthStr;
 
@@ -15,23 +20,41 @@ pub fn clear_path_buf(path: &mut PathBuf) {
     *path = old_path_str.into();
 }
 
-pub fn grapheme_width(slice: &RopeSlice) -> usize {
+pub fn grapheme_width(slice: &RopeSlice, tab_width: usize) -> usize {
     if let Some(text) = slice.as_str() {
         if text == "\t" {
-            return TAB_WIDTH;
+            return tab_width;
         }
-        text.chars().filter(|character| *character == '\t').count() * TAB_WIDTH
+        text.chars().filter(|character| *character == '\t').count() * tab_width
             + UnicodeWidthStr::width(text)
     } else {
         let text = SmallString::from_rope_slice(slice);
         if &text[..] == "\t" {
-            return TAB_WIDTH;
+            return tab_width;
         }
-        text.chars().filter(|character| *character == '\t').count() * TAB_WIDTH
+        text.chars().filter(|character| *character == '\t').count() * tab_width
             + UnicodeWidthStr::width(&text[..])
     }
 }
 
+/// The width of a grapheme drawn at `column`, tabs extend to the next tab
+/// stop
+pub fn grapheme_width_at(slice: &RopeSlice, column: usize, tab_width: usize) -> usize {
+    if *slice == "\t" {
+        tab_width - column % tab_width
+    } else {
+        grapheme_width(slice, tab_width)
+    }
+}
+
+/// The number of columns a slice of a line takes up when drawn, with tab stops
+/// every `tab_width` columns
+pub fn visual_width(slice: &RopeSlice, tab_width: usize) -> usize {
+    RopeGraphemes::new(slice).fold(0, |column, grapheme| {
+        column + grapheme_width_at(&grapheme, column, tab_width)
+    })
+}
+
 /// An iterator over the graphemes of a RopeSlice.
 pub struct RopeGraphemes<'a> {
     text: RopeSlice<'a>,
@@ -137,7 +160,64 @@ pub fn strip_trailing_whitespace(mut text: Rope) -> Rope {
     text
 }
 
-pub const TAB_WIDTH: usize = 4;
+/// Strips trailing whitespace from the lines (passed without their line
+/// ending) for which `should_strip` returns true. Unlike
+/// `strip_trailing_whitespace`, trailing empty lines are left alone.
+pub fn strip_trailing_whitespace_from_lines(
+    mut text: Rope,
+    should_strip: impl Fn(&RopeSlice) -> bool,
+) -> Rope {
+    for line_index in (0..text.len_lines()).rev() {
+        let line = text.line(line_index);
+        let content = line_content(&line);
+        let content_len = content.len_chars();
+        let mut trimmed_len = content_len;
+        while trimmed_len > 0 && content.char(trimmed_len - 1).is_whitespace() {
+            trimmed_len -= 1;
+        }
+        if trimmed_len < content_len && should_strip(&content) {
+            let start = text.line_to_char(line_index);
+            text.remove(start + trimmed_len..start + content_len);
+        }
+    }
+    text
+}
+
+/// Hashes of the contents of every line, used to tell which lines were
+/// edited since
+pub fn line_hashes(text: &Rope) -> HashSet<u64> {
+    text.lines()
+        .map(|line| line_hash(&line_content(&line)))
+        .collect()
+}
+
+pub fn line_hash(line: &RopeSlice) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for chunk in line.chunks() {
+        hasher.write(chunk.as_bytes());
+    }
+    hasher.finish()
+}
+
+fn line_content<'a>(line: &RopeSlice<'a>) -> RopeSlice<'a> {
+    let len_chars = line.len_chars();
+    if len_chars > 0 && line.char(len_chars - 1) == '\n' {
+        line.slice(..len_chars - 1)
+    } else {
+        line.slice(..)
+    }
+}
+
+pub fn has_final_newline(text: &Rope) -> bool {
+    let len_chars = text.len_chars();
+    len_chars > 0 && text.char(len_chars - 1) == '\n'
+}
+
+pub fn remove_final_newline(text: &mut Rope) {
+    if has_final_newline(text) {
+        text.remove(text.len_chars() - 1..);
+    }
+}
 
 pub fn ensure_trailing_newline_with_content(text: &mut Rope) {
     if text.len_chars() == 0 || text.char(text.len_chars() - 1) != '\n' {
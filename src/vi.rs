@@ -0,0 +1,522 @@
+//! A modal layer over the buffer key bindings, like vi. In normal and visual
+//! mode keys are parsed into counts, operators and motions which are run as
+//! buffer actions, in insert mode they go to the usual bindings.
+
+use std::mem;
+
+use crate::{
+    components::{buffer::SyncAction, PrefixArg},
+    terminal::Key,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+impl Mode {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Normal => "NORMAL",
+            Self::Insert => "INSERT",
+            Self::Visual => "VISUAL",
+        }
+    }
+}
+
+/// Where the cursor moves to, or the text an operator applies to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    NextWordStart,
+    PreviousWordStart,
+    WordEnd,
+    StartOfLine,
+    EndOfLine,
+    /// `gg`, or the line given by the count
+    FirstLine,
+    /// `G`, or the line given by the count
+    LastLine,
+    /// The current line and the ones below it, as in `dd`, `cc` and `yy`
+    Lines,
+}
+
+impl Motion {
+    /// Whether an operator applies to whole lines
+    pub fn is_linewise(self) -> bool {
+        matches!(
+            self,
+            Self::Up | Self::Down | Self::FirstLine | Self::LastLine | Self::Lines
+        )
+    }
+
+    /// Whether an operator includes the character the motion ends on
+    pub fn is_inclusive(self) -> bool {
+        self == Self::WordEnd
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+/// What the editor should do with a key
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The key is part of a command which isn't complete yet
+    Pending,
+    /// The key isn't bound by the vi layer in the current mode
+    Unhandled,
+    /// Run the actions on the focused buffer, with the count of the command
+    Run(Vec<SyncAction>, PrefixArg),
+    /// Repeat the last change by handling its keys again `count` times
+    Repeat(Vec<Key>, usize),
+}
+
+pub struct Vi {
+    mode: Mode,
+    // The command being typed, and its keys other than the count which are
+    // kept to repeat it with `.`
+    keys: Vec<Key>,
+    command_keys: Vec<Key>,
+    count: Option<usize>,
+    operator: Option<(Operator, Option<usize>)>,
+    pending_g: bool,
+    // The keys of the last change without its count, the count, and whether
+    // the keys typed in insert mode are still being added to it
+    last_change: Vec<Key>,
+    last_change_count: Option<usize>,
+    recording_change: bool,
+}
+
+impl Vi {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Normal,
+            keys: Vec::new(),
+            command_keys: Vec::new(),
+            count: None,
+            operator: None,
+            pending_g: false,
+            last_change: Vec::new(),
+            last_change_count: None,
+            recording_change: false,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The keys of the command typed so far
+    pub fn pending_keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Forgets the command typed so far, e.g. when the keys that follow run a
+    /// binding of the editor instead
+    pub fn cancel_command(&mut self) {
+        self.keys.clear();
+        self.command_keys.clear();
+        self.count = None;
+        self.operator = None;
+        self.pending_g = false;
+    }
+
+    pub fn handle_key(&mut self, key: Key) -> Outcome {
+        if self.mode == Mode::Insert {
+            if self.recording_change {
+                self.last_change.push(key);
+            }
+            if key == Key::Esc {
+                self.mode = Mode::Normal;
+                self.recording_change = false;
+                return Outcome::Run(Vec::new(), PrefixArg::None);
+            }
+            return Outcome::Unhandled;
+        }
+
+        self.keys.push(key);
+        match key {
+            Key::Char(digit @ '1'..='9') => return self.push_digit(digit),
+            Key::Char('0') if self.count.is_some() => return self.push_digit('0'),
+            Key::Esc => {
+                let actions = if self.mode == Mode::Visual {
+                    vec![SyncAction::ClearSelection]
+                } else {
+                    Vec::new()
+                };
+                return self.finish(actions, Mode::Normal, false);
+            }
+            _ => {}
+        }
+        self.command_keys.push(key);
+
+        if self.pending_g {
+            self.pending_g = false;
+            return match key {
+                Key::Char('g') => self.motion(Motion::FirstLine),
+                _ => self.finish(Vec::new(), self.mode, false),
+            };
+        }
+        if key == Key::Char('g') {
+            self.pending_g = true;
+            return Outcome::Pending;
+        }
+        if let Some(motion) = motion_for(key) {
+            return self.motion(motion);
+        }
+        if let Some((operator, _)) = self.operator {
+            return if operator_for(key) == Some(operator) {
+                self.motion(Motion::Lines)
+            } else {
+                self.finish(Vec::new(), self.mode, false)
+            };
+        }
+
+        match (self.mode, key) {
+            (Mode::Normal, Key::Char('d'))
+            | (Mode::Normal, Key::Char('c'))
+            | (Mode::Normal, Key::Char('y')) => {
+                self.operator = operator_for(key).map(|operator| (operator, self.count.take()));
+                Outcome::Pending
+            }
+            (Mode::Normal, Key::Char('v')) => {
+                self.finish(vec![SyncAction::BeginSelection], Mode::Visual, false)
+            }
+            (Mode::Normal, Key::Char('x')) | (Mode::Normal, Key::Delete) => {
+                self.finish(vec![SyncAction::DeleteForward], Mode::Normal, true)
+            }
+            (Mode::Normal, Key::Char('p')) => {
+                self.finish(vec![SyncAction::PasteAfter], Mode::Normal, true)
+            }
+            (Mode::Normal, Key::Char('P')) => {
+                self.finish(vec![SyncAction::Yank], Mode::Normal, true)
+            }
+            (Mode::Normal, Key::Char('u')) => {
+                self.finish(vec![SyncAction::Undo], Mode::Normal, false)
+            }
+            (Mode::Normal, Key::Char('i')) => self.finish(Vec::new(), Mode::Insert, true),
+            (Mode::Normal, Key::Char('a')) => {
+                self.finish(vec![SyncAction::Right], Mode::Insert, true)
+            }
+            (Mode::Normal, Key::Char('I')) => {
+                self.finish(vec![SyncAction::StartOfLine], Mode::Insert, true)
+            }
+            (Mode::Normal, Key::Char('A')) => {
+                self.finish(vec![SyncAction::EndOfLine], Mode::Insert, true)
+            }
+            (Mode::Normal, Key::Char('o')) => {
+                self.finish(vec![SyncAction::OpenLineBelow], Mode::Insert, true)
+            }
+            (Mode::Normal, Key::Char('O')) => {
+                self.finish(vec![SyncAction::OpenLineAbove], Mode::Insert, true)
+            }
+            (Mode::Normal, Key::Char('.')) => {
+                let count = self.count;
+                self.cancel_command();
+                if self.last_change.is_empty() {
+                    return Outcome::Run(Vec::new(), PrefixArg::None);
+                }
+                // A count replaces the change's own, as `3.` after `2dw`
+                // deletes 3 words. A change without one, like an insert, is
+                // repeated that many times.
+                match (count, self.last_change_count) {
+                    (Some(count), Some(_)) => {
+                        Outcome::Repeat(with_count(Some(count), &self.last_change), 1)
+                    }
+                    (count, change_count) => Outcome::Repeat(
+                        with_count(change_count, &self.last_change),
+                        count.unwrap_or(1),
+                    ),
+                }
+            }
+            (Mode::Visual, Key::Char('v')) => {
+                self.finish(vec![SyncAction::ClearSelection], Mode::Normal, false)
+            }
+            (Mode::Visual, Key::Char('d'))
+            | (Mode::Visual, Key::Char('x'))
+            | (Mode::Visual, Key::Delete) => self.finish(
+                vec![SyncAction::OperateOnSelection(Operator::Delete)],
+                Mode::Normal,
+                false,
+            ),
+            (Mode::Visual, Key::Char('c')) => self.finish(
+                vec![SyncAction::OperateOnSelection(Operator::Change)],
+                Mode::Insert,
+                false,
+            ),
+            (Mode::Visual, Key::Char('y')) => self.finish(
+                vec![SyncAction::OperateOnSelection(Operator::Yank)],
+                Mode::Normal,
+                false,
+            ),
+            _ => {
+                self.cancel_command();
+                Outcome::Unhandled
+            }
+        }
+    }
+
+    fn push_digit(&mut self, digit: char) -> Outcome {
+        let digit = digit.to_digit(10).unwrap_or(0) as usize;
+        self.count = Some(
+            self.count
+                .unwrap_or(0)
+                .saturating_mul(10)
+                .saturating_add(digit),
+        );
+        Outcome::Pending
+    }
+
+    fn motion(&mut self, motion: Motion) -> Outcome {
+        match self.operator.take() {
+            Some((operator, operator_count)) => {
+                // `2d3w` deletes 6 words
+                self.count = match (operator_count, self.count) {
+                    (Some(first), Some(second)) => Some(first.saturating_mul(second)),
+                    (first, second) => first.or(second),
+                };
+                let mode = if operator == Operator::Change {
+                    Mode::Insert
+                } else {
+                    Mode::Normal
+                };
+                self.finish(
+                    vec![SyncAction::Operate(operator, motion)],
+                    mode,
+                    operator != Operator::Yank,
+                )
+            }
+            None => self.finish(vec![SyncAction::Move(motion)], self.mode, false),
+        }
+    }
+
+    /// Completes the command, switching to `mode`. A change is kept to be
+    /// repeated with `.`, along with what's typed next if in insert mode.
+    fn finish(&mut self, actions: Vec<SyncAction>, mode: Mode, is_change: bool) -> Outcome {
+        if is_change {
+            self.last_change = mem::take(&mut self.command_keys);
+            self.last_change_count = self.count;
+            self.recording_change = mode == Mode::Insert;
+        }
+        let prefix_arg = self.count.map_or(PrefixArg::None, PrefixArg::Number);
+        self.cancel_command();
+        self.mode = mode;
+        Outcome::Run(actions, prefix_arg)
+    }
+}
+
+fn motion_for(key: Key) -> Option<Motion> {
+    Some(match key {
+        Key::Char('h') | Key::Left | Key::Backspace => Motion::Left,
+        Key::Char('l') | Key::Right => Motion::Right,
+        Key::Char('k') | Key::Up => Motion::Up,
+        Key::Char('j') | Key::Down => Motion::Down,
+        Key::Char('w') => Motion::NextWordStart,
+        Key::Char('b') => Motion::PreviousWordStart,
+        Key::Char('e') => Motion::WordEnd,
+        Key::Char('0') | Key::Home => Motion::StartOfLine,
+        Key::Char('$') | Key::End => Motion::EndOfLine,
+        Key::Char('G') => Motion::LastLine,
+        _ => return None,
+    })
+}
+
+/// The keys typing `count` followed by `keys`
+fn with_count(count: Option<usize>, keys: &[Key]) -> Vec<Key> {
+    count
+        .map(|count| count.to_string().chars().map(Key::Char).collect())
+        .unwrap_or_else(Vec::new)
+        .into_iter()
+        .chain(keys.iter().copied())
+        .collect()
+}
+
+fn operator_for(key: Key) -> Option<Operator> {
+    match key {
+        Key::Char('d') => Some(Operator::Delete),
+        Key::Char('c') => Some(Operator::Change),
+        Key::Char('y') => Some(Operator::Yank),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Cursor;
+    use ropey::Rope;
+
+    fn type_keys(vi: &mut Vi, keys: &str) -> Outcome {
+        let mut outcome = Outcome::Pending;
+        for character in keys.chars() {
+            let key = if character == '~' {
+                Key::Esc
+            } else {
+                Key::Char(character)
+            };
+            outcome = vi.handle_key(key);
+        }
+        outcome
+    }
+
+    #[test]
+    fn parses_counts_operators_and_motions() {
+        let mut vi = Vi::new();
+        assert_eq!(type_keys(&mut vi, "3"), Outcome::Pending);
+        assert_eq!(
+            type_keys(&mut vi, "j"),
+            Outcome::Run(vec![SyncAction::Move(Motion::Down)], PrefixArg::Number(3))
+        );
+        assert_eq!(
+            type_keys(&mut vi, "2d3w"),
+            Outcome::Run(
+                vec![SyncAction::Operate(Operator::Delete, Motion::NextWordStart)],
+                PrefixArg::Number(6)
+            )
+        );
+        assert_eq!(
+            type_keys(&mut vi, "yy"),
+            Outcome::Run(
+                vec![SyncAction::Operate(Operator::Yank, Motion::Lines)],
+                PrefixArg::None
+            )
+        );
+        assert_eq!(
+            type_keys(&mut vi, "dgg"),
+            Outcome::Run(
+                vec![SyncAction::Operate(Operator::Delete, Motion::FirstLine)],
+                PrefixArg::None
+            )
+        );
+        assert_eq!(
+            type_keys(&mut vi, "10G"),
+            Outcome::Run(
+                vec![SyncAction::Move(Motion::LastLine)],
+                PrefixArg::Number(10)
+            )
+        );
+        assert_eq!(vi.mode(), Mode::Normal);
+
+        assert_eq!(
+            type_keys(&mut vi, "c$"),
+            Outcome::Run(
+                vec![SyncAction::Operate(Operator::Change, Motion::EndOfLine)],
+                PrefixArg::None
+            )
+        );
+        assert_eq!(vi.mode(), Mode::Insert);
+        assert_eq!(type_keys(&mut vi, "x"), Outcome::Unhandled);
+        assert_eq!(
+            type_keys(&mut vi, "~"),
+            Outcome::Run(Vec::new(), PrefixArg::None)
+        );
+        assert_eq!(vi.mode(), Mode::Normal);
+        assert_eq!(type_keys(&mut vi, "Z"), Outcome::Unhandled);
+
+        // Editing keys don't edit outside of insert mode
+        assert_eq!(
+            vi.handle_key(Key::Backspace),
+            Outcome::Run(vec![SyncAction::Move(Motion::Left)], PrefixArg::None)
+        );
+        assert_eq!(
+            vi.handle_key(Key::Delete),
+            Outcome::Run(vec![SyncAction::DeleteForward], PrefixArg::None)
+        );
+    }
+
+    #[test]
+    fn visual_mode_operates_on_the_selection() {
+        let mut vi = Vi::new();
+        type_keys(&mut vi, "v");
+        assert_eq!(vi.mode(), Mode::Visual);
+        assert_eq!(
+            type_keys(&mut vi, "e"),
+            Outcome::Run(vec![SyncAction::Move(Motion::WordEnd)], PrefixArg::None)
+        );
+        assert_eq!(
+            type_keys(&mut vi, "y"),
+            Outcome::Run(
+                vec![SyncAction::OperateOnSelection(Operator::Yank)],
+                PrefixArg::None
+            )
+        );
+        assert_eq!(vi.mode(), Mode::Normal);
+    }
+
+    #[test]
+    fn dot_repeats_the_last_change() {
+        let mut vi = Vi::new();
+        assert_eq!(
+            type_keys(&mut vi, "."),
+            Outcome::Run(Vec::new(), PrefixArg::None)
+        );
+
+        type_keys(&mut vi, "2dw");
+        type_keys(&mut vi, "j");
+        assert_eq!(
+            type_keys(&mut vi, "."),
+            Outcome::Repeat(vec![Key::Char('2'), Key::Char('d'), Key::Char('w')], 1)
+        );
+        assert_eq!(
+            type_keys(&mut vi, "3."),
+            Outcome::Repeat(vec![Key::Char('3'), Key::Char('d'), Key::Char('w')], 1)
+        );
+        type_keys(&mut vi, "d2w");
+        assert_eq!(
+            type_keys(&mut vi, "."),
+            Outcome::Repeat(vec![Key::Char('2'), Key::Char('d'), Key::Char('w')], 1)
+        );
+        type_keys(&mut vi, "x");
+        assert_eq!(
+            type_keys(&mut vi, "4."),
+            Outcome::Repeat(vec![Key::Char('x')], 4)
+        );
+
+        type_keys(&mut vi, "ohi~");
+        type_keys(&mut vi, "u");
+        assert_eq!(
+            type_keys(&mut vi, "3."),
+            Outcome::Repeat(
+                vec![Key::Char('o'), Key::Char('h'), Key::Char('i'), Key::Esc],
+                3
+            )
+        );
+    }
+
+    #[test]
+    fn word_motions() {
+        let text = Rope::from("let x = foo(bar);\n  baz\n");
+        let mut cursor = Cursor::new();
+        let mut starts = Vec::new();
+        for _ in 0..8 {
+            cursor.move_to_next_word_start(&text);
+            starts.push(cursor.range().start.0);
+        }
+        assert_eq!(starts, vec![4, 6, 8, 11, 12, 15, 20, 23]);
+
+        let mut ends = Vec::new();
+        cursor.move_to_start_of_buffer(&text);
+        for _ in 0..4 {
+            cursor.move_to_word_end(&text);
+            ends.push(cursor.range().start.0);
+        }
+        assert_eq!(ends, vec![2, 4, 6, 10]);
+
+        let mut previous = Vec::new();
+        for _ in 0..4 {
+            cursor.move_to_previous_word_start(&text);
+            previous.push(cursor.range().start.0);
+        }
+        assert_eq!(previous, vec![8, 6, 4, 0]);
+    }
+}